@@ -18,15 +18,31 @@ pub trait BTreeNode<K: Clone + PartialEq + PartialOrd, V: Clone> {
     // returns number of values (not keys)
     fn size(&self, meta: &Self::Meta) -> usize;
     fn split_out(&mut self, meta: &Self::Meta) -> (K, Self);
+    // 兄弟ノードとの併合や再分配が必要なほど小さいか
+    fn is_underflow(&self, meta: &Self::Meta) -> bool;
+    // 使っている容量の割合 (0.0 から 1.0)
+    fn fill_ratio(&self, meta: &Self::Meta) -> f64;
 
     // internal nodes only
 
     fn insert_node(&mut self, meta: &Self::Meta, key: &K, node_i: usize) -> bool;
     fn get_child(&self, meta: &Self::Meta, key: &K) -> usize;
+    // key と同じキーを持ちうる最初の子
+    // 同じキーが複数の葉にまたがるときに、最初の葉を探すのに使う
+    fn get_lower_child(&self, meta: &Self::Meta, key: &K) -> usize;
     fn get_first_child(&self, meta: &Self::Meta) -> usize;
     fn get_children(&self, meta: &Self::Meta) -> Vec<usize>;
     fn new_internal(meta: &Self::Meta) -> Self;
     fn init_as_root_internal(&mut self, meta: &Self::Meta, key: &K, i1: usize, i2: usize);
+    // (first child, [(key, child)])
+    fn get_internal_entries(&self, meta: &Self::Meta) -> (usize, Vec<(K, usize)>);
+    // 収まらなければ何もせず false を返す
+    fn set_internal_entries(
+        &mut self,
+        meta: &Self::Meta,
+        first_child: usize,
+        entries: &[(K, usize)],
+    ) -> bool;
 
     // leaf nodes only
 
@@ -37,16 +53,32 @@ pub trait BTreeNode<K: Clone + PartialEq + PartialOrd, V: Clone> {
     fn first_cursor(&self, meta: &Self::Meta) -> usize;
     fn cursor_get(&self, meta: &Self::Meta, cursor: usize) -> Option<(K, V)>;
     fn cursor_delete(&mut self, meta: &Self::Meta, cursor: usize) -> bool;
+    fn new_leaf(meta: &Self::Meta) -> Self;
+    // 収まらなければ何もせず false を返す
+    fn set_leaf_entries(&mut self, meta: &Self::Meta, entries: &[(K, V)]) -> bool;
+
+    fn get_leaf_entries(&self, meta: &Self::Meta) -> Vec<(K, V)> {
+        (0..self.size(meta))
+            .map(|i| self.cursor_get(meta, i).unwrap())
+            .collect()
+    }
 }
 
 pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
     type Node: BTreeNode<K, V>;
+    type NodeRef<'a>: std::ops::Deref<Target = Self::Node>
+    where
+        Self: 'a;
+    type NodeMut<'a>: std::ops::DerefMut<Target = Self::Node>
+    where
+        Self: 'a;
 
     fn add_root_node(&mut self) -> usize;
-    fn node_ref(&self, node_i: usize) -> &Self::Node;
-    fn node_mut(&mut self, node_i: usize) -> &mut Self::Node;
+    fn node_ref(&self, node_i: usize) -> Self::NodeRef<'_>;
+    fn node_mut(&mut self, node_i: usize) -> Self::NodeMut<'_>;
     fn push(&mut self, node: Self::Node) -> usize;
     fn swap(&mut self, node_i: usize, node: Self::Node) -> Self::Node;
+    fn free_node(&mut self, node_i: usize);
 
     fn first_cursor(
         &self,
@@ -64,6 +96,8 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
         }
     }
 
+    // ノードのラッチは一つずつ取るだけで、たどる間に木の形が変わらないことを前提にしている
+    // 書き込みと同時に読むときは、書き換えられない snapshot の木から探す
     fn find(
         &self,
         meta: &<Self::Node as BTreeNode<K, V>>::Meta,
@@ -71,37 +105,58 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
         key: &K,
     ) -> (BTreeCursor, bool) {
         debug_assert_ne!(node_i, 0);
+        let mut node_i = node_i;
         let mut node = self.node_ref(node_i);
-        if node.is_leaf(meta) {
-            let (mut i, mut found) = node.find_cursor(meta, key);
-            let mut node_i = node_i;
-            while node.size(meta) <= i {
-                if let Some(next_node_i) = node.get_next(meta) {
-                    node_i = next_node_i;
-                    node = self.node_ref(node_i);
-                    let r = node.find_cursor(meta, key);
-                    i = r.0;
-                    found = r.1;
-                } else {
-                    return (
-                        BTreeCursor {
-                            node_i: 0,
-                            value_i: 0,
-                        },
-                        false,
-                    );
-                }
-            }
-            (BTreeCursor { node_i, value_i: i }, found)
-        } else {
-            let child_node_i = node.get_child(meta, key);
-            if child_node_i == 0 {
+        while !node.is_leaf(meta) {
+            node_i = node.get_lower_child(meta, key);
+            if node_i == 0 {
                 dbg!(node.get_children(meta));
             }
-            self.find(meta, child_node_i, key)
+            node = self.node_ref(node_i);
+        }
+        let (mut i, mut found) = node.find_cursor(meta, key);
+        while node.size(meta) <= i {
+            if let Some(next_node_i) = node.get_next(meta) {
+                node_i = next_node_i;
+                node = self.node_ref(node_i);
+                let r = node.find_cursor(meta, key);
+                i = r.0;
+                found = r.1;
+            } else {
+                return (
+                    BTreeCursor {
+                        node_i: 0,
+                        value_i: 0,
+                    },
+                    false,
+                );
+            }
+        }
+        (BTreeCursor { node_i, value_i: i }, found)
+    }
+
+    // key が入る葉を探す
+    fn find_leaf(
+        &self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        node_i: usize,
+        key: &K,
+    ) -> usize {
+        let mut node_i = node_i;
+        let mut node = self.node_ref(node_i);
+        while !node.is_leaf(meta) {
+            node_i = node.get_child(meta, key);
+            node = self.node_ref(node_i);
         }
+        node_i
     }
 
+    // 葉までは共有ラッチでたどり、書き換えるノードだけ排他ラッチを取る
+    // 分割は葉から親へ一つずつ排他ラッチを取って伝える
+    // ラッチを結合しないので、書き込むスレッドは同時に一つだけでなければならない
+    // (たどった後に他の書き込みで木の形が変わらないことを前提にしている)
+    // 呼び出す側が保証する。Pager では lock_writer のガードを持って呼ぶ
+    // 分割の途中の木は今のページからは正しく読めないので、同時に読むスレッドは snapshot から読む
     fn insert(
         &mut self,
         meta: &<Self::Node as BTreeNode<K, V>>::Meta,
@@ -110,31 +165,24 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
         value: &V,
     ) -> Result<(), String> {
         debug_assert_ne!(node_i, 0);
-        if self.node_ref(node_i).is_leaf(meta) {
-            if self.node_mut(node_i).insert_value(meta, key, value) {
-                Ok(())
+        let node_i = self.find_leaf(meta, node_i, key);
+        if self.node_mut(node_i).insert_value(meta, key, value) {
+            Ok(())
+        } else {
+            let next_i = self.node_ref(node_i).get_next(meta);
+            let (pivot_key, mut new_node) = self.node_mut(node_i).split_out(meta);
+            if key < &pivot_key {
+                self.node_mut(node_i).insert_value(meta, key, value);
             } else {
-                let next_i = self.node_ref(node_i).get_next(meta);
-                let (pivot_key, mut new_node) = self.node_mut(node_i).split_out(meta);
-                if key < &pivot_key {
-                    self.node_mut(node_i).insert_value(meta, key, value);
-                } else {
-                    new_node.insert_value(meta, key, value);
-                }
-                let new_node_i = self.insert_node(meta, node_i, &pivot_key, new_node)?;
-                let node = self.node_mut(new_node_i);
-                debug_assert!(node.is_leaf(meta));
-                if next_i.is_some() {
-                    node.set_next(meta, next_i.unwrap());
-                }
-                Ok(())
+                new_node.insert_value(meta, key, value);
             }
-        } else {
-            let child_node_i = self.node_ref(node_i).get_child(meta, key);
-            if child_node_i == 0 {
-                dbg!(self.node_ref(node_i).get_children(meta));
+            let new_node_i = self.insert_node(meta, node_i, &pivot_key, new_node)?;
+            let mut node = self.node_mut(new_node_i);
+            debug_assert!(node.is_leaf(meta));
+            if next_i.is_some() {
+                node.set_next(meta, next_i.unwrap());
             }
-            self.insert(meta, child_node_i, key, value)
+            Ok(())
         }
     }
 
@@ -146,7 +194,8 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
         key: &K,
         node: Self::Node,
     ) -> Result<usize, String> {
-        if let Some(parent_i) = self.node_ref(node_i).get_parent(meta) {
+        let parent_i = self.node_ref(node_i).get_parent(meta);
+        if let Some(parent_i) = parent_i {
             let inserted_node_i = self.push(node);
             if self
                 .node_mut(parent_i)
@@ -169,7 +218,7 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
                     self.reparent(meta, parent2_i);
                 }
             }
-            let node = self.node_mut(node_i);
+            let mut node = self.node_mut(node_i);
             if node.is_leaf(meta) {
                 // is_leaf チェック必要?
                 node.set_next(meta, inserted_node_i);
@@ -181,7 +230,7 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
             let node_i1 = self.push(n);
             let node_i2 = self.push(node);
             {
-                let node1 = self.node_mut(node_i1);
+                let mut node1 = self.node_mut(node_i1);
                 node1.set_parent(meta, node_i);
                 if node1.is_leaf(meta) {
                     node1.set_next(meta, node_i2);
@@ -195,9 +244,140 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
         }
     }
 
+    // 空の木 root_i に entries を入れる
+    // ルートのノード番号は変わらない
+    fn bulk_load(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        root_i: usize,
+        entries: impl IntoIterator<Item = (K, V)>,
+        fill_factor: f64,
+    ) {
+        debug_assert!(self.node_ref(root_i).is_leaf(meta));
+        debug_assert_eq!(self.node_ref(root_i).size(meta), 0);
+
+        let top_i = if let Some(top_i) = self.build(meta, entries, fill_factor) {
+            top_i
+        } else {
+            return;
+        };
+        // 一番上のノードをルートに移す
+        let top = self.swap(top_i, Self::Node::new_leaf(meta));
+        self.swap(root_i, top);
+        self.node_mut(root_i).set_parent(meta, 0);
+        self.reparent(meta, root_i);
+        self.free_node(top_i);
+    }
+
+    // キーの順に並んだ entries から葉と内部ノードを下から作り、ルートを返す
+    // 各ノードは fill_factor の割合まで詰める
+    // entries が空なら何も作らない
+    fn build(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        entries: impl IntoIterator<Item = (K, V)>,
+        fill_factor: f64,
+    ) -> Option<usize> {
+        // (最初のキー, ノード)
+        let mut level: Vec<(K, usize)> = vec![];
+        let mut node = Self::Node::new_leaf(meta);
+        let mut node_entries: Vec<(K, V)> = vec![];
+        for (key, value) in entries {
+            debug_assert!(node_entries.last().is_none_or(|(k, _)| k <= &key));
+            // 同じキーが葉をまたがないように、キーが変わるところで区切る
+            let same_key = node_entries.last().is_some_and(|(k, _)| k == &key);
+            if !same_key && fill_factor <= node.fill_ratio(meta) {
+                self.bulk_load_leaf(meta, &mut level, node, &mut node_entries);
+                node = Self::Node::new_leaf(meta);
+            }
+            if !node.insert_value(meta, &key, &value) {
+                // 同じキーの続きは次の葉に移す
+                let run_start = node_entries
+                    .iter()
+                    .rposition(|(k, _)| k != &key)
+                    .map_or(0, |i| i + 1);
+                let mut run = if 0 < run_start {
+                    node_entries.split_off(run_start)
+                } else {
+                    vec![]
+                };
+                if !run.is_empty() {
+                    node = Self::Node::new_leaf(meta);
+                    node.set_leaf_entries(meta, &node_entries);
+                }
+                self.bulk_load_leaf(meta, &mut level, node, &mut node_entries);
+                node = Self::Node::new_leaf(meta);
+                if !node.set_leaf_entries(meta, &run) {
+                    unreachable!("entries of a leaf must fit in a leaf");
+                }
+                node_entries.append(&mut run);
+                if !node.insert_value(meta, &key, &value) {
+                    assert!(!node_entries.is_empty(), "entry is too large");
+                    // 同じキーだけで葉が埋まるときは分けるしかない
+                    self.bulk_load_leaf(meta, &mut level, node, &mut node_entries);
+                    node = Self::Node::new_leaf(meta);
+                    assert!(node.insert_value(meta, &key, &value), "entry is too large");
+                }
+            }
+            node_entries.push((key, value));
+        }
+        if node_entries.is_empty() {
+            return None;
+        }
+        self.bulk_load_leaf(meta, &mut level, node, &mut node_entries);
+
+        while 1 < level.len() {
+            let mut upper = vec![];
+            let mut children = level.into_iter();
+            let (mut first_key, first_child) = children.next().unwrap();
+            let mut node = Self::Node::new_internal(meta);
+            node.set_internal_entries(meta, first_child, &[]);
+            for (key, child_i) in children {
+                let inserted = fill_factor > node.fill_ratio(meta)
+                    && if node.size(meta) == 1 {
+                        let first_child = node.get_first_child(meta);
+                        node.set_internal_entries(meta, first_child, &[(key.clone(), child_i)])
+                    } else {
+                        node.insert_node(meta, &key, child_i)
+                    };
+                if !inserted {
+                    let node_i = self.push(node);
+                    self.reparent(meta, node_i);
+                    upper.push((first_key, node_i));
+                    first_key = key;
+                    node = Self::Node::new_internal(meta);
+                    node.set_internal_entries(meta, child_i, &[]);
+                }
+            }
+            let node_i = self.push(node);
+            self.reparent(meta, node_i);
+            upper.push((first_key, node_i));
+            level = upper;
+        }
+        Some(level[0].1)
+    }
+
+    // 作った葉を追加して、前の葉の next をつなぐ
+    fn bulk_load_leaf(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        level: &mut Vec<(K, usize)>,
+        node: Self::Node,
+        node_entries: &mut Vec<(K, V)>,
+    ) {
+        let first_key = node_entries[0].0.clone();
+        node_entries.clear();
+        let node_i = self.push(node);
+        if let Some((_, prev_i)) = level.last() {
+            self.node_mut(*prev_i).set_next(meta, node_i);
+        }
+        level.push((first_key, node_i));
+    }
+
     fn reparent(&mut self, meta: &<Self::Node as BTreeNode<K, V>>::Meta, node_i: usize) {
         if !self.node_ref(node_i).is_leaf(meta) {
-            for child_node_i in self.node_ref(node_i).get_children(meta) {
+            let children = self.node_ref(node_i).get_children(meta);
+            for child_node_i in children {
                 self.node_mut(child_node_i).set_parent(meta, node_i);
             }
         }
@@ -225,7 +405,234 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
         {
             panic!("something went wrong :(")
         }
-        cursor
+        self.rebalance(meta, cursor)
+    }
+
+    // 削除で小さくなったノードを兄弟ノードと併合または再分配する
+    // 値が移動するので、カーソルの指していた値の新しい位置を返す
+    fn rebalance(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        mut cursor: BTreeCursor,
+    ) -> BTreeCursor {
+        let mut node_i = cursor.node_i;
+        loop {
+            let parent_i = self.node_ref(node_i).get_parent(meta);
+            let parent_i = if let Some(parent_i) = parent_i {
+                parent_i
+            } else {
+                self.collapse_root(meta, node_i, &mut cursor);
+                return cursor;
+            };
+            if !self.node_ref(node_i).is_underflow(meta) {
+                return cursor;
+            }
+
+            let children = self.node_ref(parent_i).get_children(meta);
+            if children.len() == 1 {
+                node_i = parent_i;
+                continue;
+            }
+            // right_index は親における右側のノードの位置
+            let j = children.iter().position(|c| *c == node_i).unwrap();
+            let (right_index, left_i, right_i) = if j + 1 < children.len() {
+                (j + 1, node_i, children[j + 1])
+            } else {
+                (j, children[j - 1], node_i)
+            };
+
+            let merged = if self.node_ref(left_i).is_leaf(meta) {
+                self.rebalance_leaves(meta, parent_i, right_index, left_i, right_i, &mut cursor)
+            } else {
+                self.rebalance_internals(meta, parent_i, right_index, left_i, right_i)
+            };
+            if !merged {
+                return cursor;
+            }
+            node_i = parent_i;
+        }
+    }
+
+    // 併合したら true を返す
+    fn rebalance_leaves(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        parent_i: usize,
+        right_index: usize,
+        left_i: usize,
+        right_i: usize,
+        cursor: &mut BTreeCursor,
+    ) -> bool {
+        let mut entries = self.node_ref(left_i).get_leaf_entries(meta);
+        let left_size = entries.len();
+        entries.extend(self.node_ref(right_i).get_leaf_entries(meta));
+        let global_i = if cursor.node_i == left_i {
+            Some(cursor.value_i)
+        } else if cursor.node_i == right_i {
+            Some(left_size + cursor.value_i)
+        } else {
+            None
+        };
+
+        if self.node_mut(left_i).set_leaf_entries(meta, &entries) {
+            let next_i = self.node_ref(right_i).get_next(meta);
+            self.node_mut(left_i).set_next(meta, next_i.unwrap_or(0));
+            self.remove_child(meta, parent_i, right_index);
+            self.free_node(right_i);
+            if let Some(i) = global_i {
+                *cursor = BTreeCursor {
+                    node_i: left_i,
+                    value_i: i,
+                };
+            }
+            return true;
+        }
+
+        // 同じキーが左右に分かれないように、キーの境界で分ける
+        let mut pivots: Vec<_> = (1..entries.len())
+            .filter(|i| *i != left_size && entries[i - 1].0 != entries[*i].0)
+            .collect();
+        pivots.sort_by_key(|i| (*i as isize - entries.len() as isize / 2).abs());
+        let (first_child, mut parent_entries) = self.node_ref(parent_i).get_internal_entries(meta);
+        for pivot in pivots {
+            parent_entries[right_index - 1].0 = entries[pivot].0.clone();
+            if !Self::Node::new_leaf(meta).set_leaf_entries(meta, &entries[..pivot])
+                || !Self::Node::new_leaf(meta).set_leaf_entries(meta, &entries[pivot..])
+                || !Self::Node::new_internal(meta).set_internal_entries(
+                    meta,
+                    first_child,
+                    &parent_entries,
+                )
+            {
+                continue;
+            }
+            self.node_mut(left_i)
+                .set_leaf_entries(meta, &entries[..pivot]);
+            self.node_mut(right_i)
+                .set_leaf_entries(meta, &entries[pivot..]);
+            self.node_mut(parent_i)
+                .set_internal_entries(meta, first_child, &parent_entries);
+            if let Some(i) = global_i {
+                *cursor = if i < pivot {
+                    BTreeCursor {
+                        node_i: left_i,
+                        value_i: i,
+                    }
+                } else {
+                    BTreeCursor {
+                        node_i: right_i,
+                        value_i: i - pivot,
+                    }
+                };
+            }
+            break;
+        }
+        false
+    }
+
+    // 併合したら true を返す
+    fn rebalance_internals(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        parent_i: usize,
+        right_index: usize,
+        left_i: usize,
+        right_i: usize,
+    ) -> bool {
+        let (first_child, mut parent_entries) = self.node_ref(parent_i).get_internal_entries(meta);
+        let (left_first_child, mut entries) = self.node_ref(left_i).get_internal_entries(meta);
+        let (right_first_child, right_entries) = self.node_ref(right_i).get_internal_entries(meta);
+        let left_size = entries.len();
+        entries.push((parent_entries[right_index - 1].0.clone(), right_first_child));
+        entries.extend(right_entries);
+
+        if self
+            .node_mut(left_i)
+            .set_internal_entries(meta, left_first_child, &entries)
+        {
+            self.reparent(meta, left_i);
+            self.remove_child(meta, parent_i, right_index);
+            self.free_node(right_i);
+            return true;
+        }
+
+        // entries[pivot] のキーが親に上がり、子は右のノードの最初の子になる
+        let mut pivots: Vec<_> = (0..entries.len()).filter(|i| *i != left_size).collect();
+        pivots.sort_by_key(|i| (*i as isize - entries.len() as isize / 2).abs());
+        for pivot in pivots {
+            parent_entries[right_index - 1].0 = entries[pivot].0.clone();
+            let right_first_child = entries[pivot].1;
+            if !Self::Node::new_internal(meta).set_internal_entries(
+                meta,
+                left_first_child,
+                &entries[..pivot],
+            ) || !Self::Node::new_internal(meta).set_internal_entries(
+                meta,
+                right_first_child,
+                &entries[pivot + 1..],
+            ) || !Self::Node::new_internal(meta).set_internal_entries(
+                meta,
+                first_child,
+                &parent_entries,
+            ) {
+                continue;
+            }
+            self.node_mut(left_i)
+                .set_internal_entries(meta, left_first_child, &entries[..pivot]);
+            self.node_mut(right_i).set_internal_entries(
+                meta,
+                right_first_child,
+                &entries[pivot + 1..],
+            );
+            self.node_mut(parent_i)
+                .set_internal_entries(meta, first_child, &parent_entries);
+            self.reparent(meta, left_i);
+            self.reparent(meta, right_i);
+            break;
+        }
+        false
+    }
+
+    fn remove_child(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        node_i: usize,
+        child_index: usize,
+    ) {
+        debug_assert!(0 < child_index);
+        let (first_child, mut entries) = self.node_ref(node_i).get_internal_entries(meta);
+        entries.remove(child_index - 1);
+        let ok = self
+            .node_mut(node_i)
+            .set_internal_entries(meta, first_child, &entries);
+        debug_assert!(ok);
+    }
+
+    // 子が1つしかないルートを子で置き換える
+    // ルートのノード番号は変わらない
+    fn collapse_root(
+        &mut self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        root_i: usize,
+        cursor: &mut BTreeCursor,
+    ) {
+        loop {
+            let root = self.node_ref(root_i);
+            if root.is_leaf(meta) || root.size(meta) != 1 {
+                return;
+            }
+            let child_i = root.get_first_child(meta);
+            drop(root);
+
+            let child = self.swap(child_i, Self::Node::new_leaf(meta));
+            self.swap(root_i, child);
+            self.node_mut(root_i).set_parent(meta, 0);
+            self.reparent(meta, root_i);
+            self.free_node(child_i);
+            if cursor.node_i == child_i {
+                cursor.node_i = root_i;
+            }
+        }
     }
 
     fn cursor_next(
@@ -277,4 +684,105 @@ pub trait BTree<K: Clone + PartialEq + PartialOrd, V: Clone> {
         }
         false
     }
+
+    // 木の構造を検査し、見つかった問題を返す
+    // キーの順序、親ポインタ、葉の next のつながりを調べる
+    fn check(&self, meta: &<Self::Node as BTreeNode<K, V>>::Meta, root_i: usize) -> Vec<String> {
+        let mut problems = vec![];
+        let mut leaves = vec![];
+        let mut visited = std::collections::HashSet::new();
+        self.check_node(
+            meta,
+            root_i,
+            None,
+            (None, None),
+            &mut visited,
+            &mut leaves,
+            &mut problems,
+        );
+        for (i, leaf_i) in leaves.iter().enumerate() {
+            let next_i = self.node_ref(*leaf_i).get_next(meta);
+            let expected = leaves.get(i + 1).cloned();
+            if next_i != expected {
+                problems.push(format!(
+                    "node {}: next is {:?}, expected {:?}",
+                    leaf_i, next_i, expected
+                ));
+            }
+        }
+        problems
+    }
+
+    // bounds の範囲 (両端を含む) にキーが収まっているかも調べる
+    #[allow(clippy::too_many_arguments)]
+    fn check_node(
+        &self,
+        meta: &<Self::Node as BTreeNode<K, V>>::Meta,
+        node_i: usize,
+        parent_i: Option<usize>,
+        bounds: (Option<&K>, Option<&K>),
+        visited: &mut std::collections::HashSet<usize>,
+        leaves: &mut Vec<usize>,
+        problems: &mut Vec<String>,
+    ) {
+        if !visited.insert(node_i) {
+            problems.push(format!("node {}: referenced more than once", node_i));
+            return;
+        }
+        let node = self.node_ref(node_i);
+        if node.get_parent(meta) != parent_i {
+            problems.push(format!(
+                "node {}: parent is {:?}, expected {:?}",
+                node_i,
+                node.get_parent(meta),
+                parent_i
+            ));
+        }
+        let in_bounds = |key: &K| {
+            bounds.0.is_none_or(|lower| lower <= key) && bounds.1.is_none_or(|upper| key <= upper)
+        };
+
+        if node.is_leaf(meta) {
+            let keys: Vec<_> = node
+                .get_leaf_entries(meta)
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            if keys.windows(2).any(|w| w[1] < w[0]) {
+                problems.push(format!("node {}: keys are not sorted", node_i));
+            }
+            if !keys.iter().all(in_bounds) {
+                problems.push(format!("node {}: keys are out of the parent range", node_i));
+            }
+            leaves.push(node_i);
+            return;
+        }
+
+        let (first_child, entries) = node.get_internal_entries(meta);
+        drop(node);
+        if entries.windows(2).any(|w| w[1].0 < w[0].0) {
+            problems.push(format!("node {}: keys are not sorted", node_i));
+        }
+        if !entries.iter().all(|(key, _)| in_bounds(key)) {
+            problems.push(format!("node {}: keys are out of the parent range", node_i));
+        }
+        let mut lower = bounds.0;
+        let mut child_i = first_child;
+        for i in 0..=entries.len() {
+            let upper = entries.get(i).map(|e| &e.0).or(bounds.1);
+            self.check_node(
+                meta,
+                child_i,
+                Some(node_i),
+                (lower, upper),
+                visited,
+                leaves,
+                problems,
+            );
+            if let Some((key, next_child_i)) = entries.get(i) {
+                lower = Some(key);
+                child_i = *next_child_i;
+            }
+        }
+    }
 }
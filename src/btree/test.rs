@@ -27,7 +27,7 @@ impl<V: Clone> BTreeNode<usize, V> for IBTreeNode<V> {
     }
 
     fn set_parent(&mut self, _: &(), i: usize) {
-        self.parent = Some(i);
+        self.parent = if i == 0 { None } else { Some(i) };
     }
 
     fn size(&self, _: &()) -> usize {
@@ -66,8 +66,22 @@ impl<V: Clone> BTreeNode<usize, V> for IBTreeNode<V> {
         }
     }
 
+    fn is_underflow(&self, meta: &()) -> bool {
+        self.size(meta) < 2
+    }
+
+    fn fill_ratio(&self, meta: &()) -> f64 {
+        match &self.values {
+            Ok(x) => x.len() as f64 / 4.0,
+            Err(x) => x.len() as f64 / 3.0,
+        }
+    }
+
     fn insert_node(&mut self, _: &(), key: &usize, node_i: usize) -> bool {
         if let Ok(children) = &mut self.values {
+            if children.len() == 4 {
+                return false;
+            }
             for i in 0..self.keys.len() {
                 if &self.keys[i] == key {
                     panic!("dup");
@@ -98,6 +112,19 @@ impl<V: Clone> BTreeNode<usize, V> for IBTreeNode<V> {
         }
     }
 
+    fn get_lower_child(&self, _: &(), key: &usize) -> usize {
+        if let Ok(chuldren) = &self.values {
+            for i in 0..self.keys.len() {
+                if key <= &self.keys[i] {
+                    return chuldren[i];
+                }
+            }
+            *chuldren.last().unwrap()
+        } else {
+            panic!("!")
+        }
+    }
+
     fn get_first_child(&self, meta: &Self::Meta) -> usize {
         if let Ok(chuldren) = &self.values {
             chuldren[0]
@@ -128,6 +155,34 @@ impl<V: Clone> BTreeNode<usize, V> for IBTreeNode<V> {
         self.values = Ok(vec![i1, i2]);
     }
 
+    fn get_internal_entries(&self, _: &()) -> (usize, Vec<(usize, usize)>) {
+        let children = self.values.as_ref().ok().unwrap();
+        (
+            children[0],
+            self.keys
+                .iter()
+                .cloned()
+                .zip(children[1..].iter().cloned())
+                .collect(),
+        )
+    }
+
+    fn set_internal_entries(
+        &mut self,
+        _: &(),
+        first_child: usize,
+        entries: &[(usize, usize)],
+    ) -> bool {
+        if 4 < entries.len() + 1 {
+            return false;
+        }
+        self.keys = entries.iter().map(|e| e.0).collect();
+        self.values = Ok(std::iter::once(first_child)
+            .chain(entries.iter().map(|e| e.1))
+            .collect());
+        true
+    }
+
     fn insert_value(&mut self, meta: &(), key: &usize, value: &V) -> bool {
         if match &self.values {
             Ok(x) => x.len() == 4,
@@ -158,7 +213,7 @@ impl<V: Clone> BTreeNode<usize, V> for IBTreeNode<V> {
     }
 
     fn set_next(&mut self, _: &(), i: usize) {
-        self.next = Some(i);
+        self.next = if i == 0 { None } else { Some(i) };
     }
 
     fn find_cursor(&self, meta: &Self::Meta, key: &usize) -> (usize, bool) {
@@ -194,10 +249,30 @@ impl<V: Clone> BTreeNode<usize, V> for IBTreeNode<V> {
             panic!("ook");
         }
     }
+
+    fn new_leaf(_: &()) -> Self {
+        IBTreeNode {
+            parent: None,
+            keys: vec![],
+            next: None,
+            values: Err(vec![]),
+        }
+    }
+
+    fn set_leaf_entries(&mut self, _: &(), entries: &[(usize, V)]) -> bool {
+        if 3 < entries.len() {
+            return false;
+        }
+        self.keys = entries.iter().map(|e| e.0).collect();
+        self.values = Err(entries.iter().map(|e| e.1.clone()).collect());
+        true
+    }
 }
 
 impl BTree<usize, String> for IBTree {
     type Node = IBTreeNode<String>;
+    type NodeRef<'a> = &'a IBTreeNode<String>;
+    type NodeMut<'a> = &'a mut IBTreeNode<String>;
 
     fn add_root_node(&mut self) -> usize {
         self.pages.push(IBTreeNode {
@@ -226,6 +301,10 @@ impl BTree<usize, String> for IBTree {
         std::mem::swap(&mut node, &mut self.pages[i]);
         node
     }
+
+    fn free_node(&mut self, i: usize) {
+        self.pages[i] = IBTreeNode::new_leaf(&());
+    }
 }
 
 impl<V: Clone> IBTreeNode<V> {
@@ -243,14 +322,10 @@ impl<V: Clone> IBTreeNode<V> {
 }
 
 impl IBTree {
+    // ノード 0 は null として使うので、ルートはノード 1
     pub fn new() -> Self {
         IBTree {
-            pages: vec![IBTreeNode {
-                parent: None,
-                keys: vec![],
-                next: None,
-                values: Err(vec![]),
-            }],
+            pages: vec![IBTreeNode::new_leaf(&()), IBTreeNode::new_leaf(&())],
         }
     }
 
@@ -290,34 +365,36 @@ impl IBTree {
     }
 }
 
+const ROOT: usize = 1;
+
 #[test]
 fn test() {
     #![allow(unused_must_use)]
 
     let mut set = IBTree::new();
     let meta = ();
-    dbg!(set.find_one(&meta, 0, &10));
-    dbg!(set.insert(&meta, 0, &10, &"10".to_string()));
-    dbg!(set.find_one(&meta, 0, &10));
-    dbg!(set.insert(&meta, 0, &20, &"20".to_string()));
-    dbg!(set.insert(&meta, 0, &30, &"30".to_string()));
+    dbg!(set.find_one(&meta, ROOT, &10));
+    dbg!(set.insert(&meta, ROOT, &10, &"10".to_string()));
+    dbg!(set.find_one(&meta, ROOT, &10));
+    dbg!(set.insert(&meta, ROOT, &20, &"20".to_string()));
+    dbg!(set.insert(&meta, ROOT, &30, &"30".to_string()));
     dbg!(&set);
-    dbg!(set.insert(&meta, 0, &40, &"40".to_string()));
+    dbg!(set.insert(&meta, ROOT, &40, &"40".to_string()));
     dbg!(&set);
-    dbg!(set.find_one(&meta, 0, &20));
-    dbg!(set.find_one(&meta, 0, &30));
-    dbg!(set.insert(&meta, 0, &15, &"15".to_string()));
+    dbg!(set.find_one(&meta, ROOT, &20));
+    dbg!(set.find_one(&meta, ROOT, &30));
+    dbg!(set.insert(&meta, ROOT, &15, &"15".to_string()));
     dbg!(&set);
-    dbg!(set.insert(&meta, 0, &16, &"16".to_string()));
+    dbg!(set.insert(&meta, ROOT, &16, &"16".to_string()));
     dbg!(&set);
-    dbg!(set.insert(&meta, 0, &25, &"25".to_string()));
-    dbg!(set.insert(&meta, 0, &45, &"45".to_string()));
+    dbg!(set.insert(&meta, ROOT, &25, &"25".to_string()));
+    dbg!(set.insert(&meta, ROOT, &45, &"45".to_string()));
     dbg!(&set);
-    dbg!(set.find_one(&meta, 0, &10));
-    dbg!(set.find_one(&meta, 0, &20));
-    dbg!(set.find_one(&meta, 0, &30));
-    dbg!(set.find_one(&meta, 0, &40));
-    dbg!(set.find_one(&meta, 0, &15));
+    dbg!(set.find_one(&meta, ROOT, &10));
+    dbg!(set.find_one(&meta, ROOT, &20));
+    dbg!(set.find_one(&meta, ROOT, &30));
+    dbg!(set.find_one(&meta, ROOT, &40));
+    dbg!(set.find_one(&meta, ROOT, &15));
 
     let vs = vec![
         11, 5, 9, 2, 6, 8, 16, 20, 18, 3, 4, 10, 12, 15, 1, 14, 13, 19, 7, 17,
@@ -325,17 +402,110 @@ fn test() {
     // let vs = (1..=20).rev().collect::<Vec<usize>>();
     let mut t = IBTree::new();
     for v in &vs {
-        t.insert(&meta, 0, v, &format!("{}", v)).unwrap();
-        t.show_nodes(0);
+        t.insert(&meta, ROOT, v, &format!("{}", v)).unwrap();
+        t.show_nodes(ROOT);
         // dbg!(&t);
         println!();
     }
     for v in &vs {
-        t.find_one(&meta, 0, v).unwrap();
+        t.find_one(&meta, ROOT, v).unwrap();
     }
     for v in &vs {
-        let c = t.find(&meta, 0, v).0;
+        let c = t.find(&meta, ROOT, v).0;
         t.cursor_delete(&meta, c);
     }
-    t.show_nodes(0);
+    t.show_nodes(ROOT);
+    assert!(t.node_ref(ROOT).is_leaf(&meta));
+    assert_eq!(t.node_ref(ROOT).size(&meta), 0);
+}
+
+#[test]
+fn test_rebalance() {
+    let meta = ();
+    let mut t = IBTree::new();
+    for v in 0..100 {
+        t.insert(&meta, ROOT, &v, &format!("{}", v)).unwrap();
+    }
+
+    // カーソルで進みながら奇数を消す
+    let mut cursor = t.first_cursor(&meta, ROOT);
+    while !t.cursor_is_end(&meta, &cursor) {
+        let (key, _) = t.cursor_get(&meta, &cursor).unwrap();
+        if key % 2 == 1 {
+            cursor = t.cursor_delete(&meta, cursor);
+        } else {
+            cursor = t.cursor_next(&meta, cursor);
+        }
+    }
+    t.show_nodes(ROOT);
+
+    let mut keys = vec![];
+    let mut cursor = t.first_cursor(&meta, ROOT);
+    while !t.cursor_is_end(&meta, &cursor) {
+        keys.push(t.cursor_get(&meta, &cursor).unwrap().0);
+        cursor = t.cursor_next(&meta, cursor);
+    }
+    assert_eq!(keys, (0..100).step_by(2).collect::<Vec<_>>());
+    assert_eq!(t.check(&meta, ROOT), Vec::<String>::new());
+    for v in (0..100).step_by(2) {
+        assert_eq!(t.find_one(&meta, ROOT, &v), Some(format!("{}", v)));
+    }
+
+    // 葉の連結リストに空の葉が残っていない
+    let mut node_i = t.first_cursor(&meta, ROOT).node_i;
+    loop {
+        assert!(0 < t.node_ref(node_i).size(&meta));
+        if let Some(next_i) = t.node_ref(node_i).get_next(&meta) {
+            node_i = next_i;
+        } else {
+            break;
+        }
+    }
+
+    for v in (0..100).step_by(2) {
+        let c = t.find(&meta, ROOT, &v).0;
+        t.cursor_delete(&meta, c);
+    }
+    assert!(t.node_ref(ROOT).is_leaf(&meta));
+    assert_eq!(t.node_ref(ROOT).size(&meta), 0);
+}
+
+#[test]
+fn test_bulk_load() {
+    let meta = ();
+    for fill_factor in [1.0, 0.5] {
+        let mut t = IBTree::new();
+        t.bulk_load(
+            &meta,
+            ROOT,
+            (0..100).map(|v| (v * 2, format!("{}", v * 2))),
+            fill_factor,
+        );
+        assert_eq!(t.check(&meta, ROOT), Vec::<String>::new());
+        for v in 0..100 {
+            assert_eq!(
+                t.find_one(&meta, ROOT, &(v * 2)),
+                Some(format!("{}", v * 2))
+            );
+        }
+
+        // 作った後も普通に挿入、削除できる
+        for v in 0..100 {
+            t.insert(&meta, ROOT, &(v * 2 + 1), &format!("{}", v * 2 + 1))
+                .unwrap();
+        }
+        assert_eq!(t.check(&meta, ROOT), Vec::<String>::new());
+        let mut keys = vec![];
+        let mut cursor = t.first_cursor(&meta, ROOT);
+        while !t.cursor_is_end(&meta, &cursor) {
+            keys.push(t.cursor_get(&meta, &cursor).unwrap().0);
+            cursor = t.cursor_next(&meta, cursor);
+        }
+        assert_eq!(keys, (0..200).collect::<Vec<_>>());
+        for v in 0..200 {
+            let c = t.find(&meta, ROOT, &v).0;
+            t.cursor_delete(&meta, c);
+        }
+        assert_eq!(t.node_ref(ROOT).size(&meta), 0);
+    }
 }
@@ -10,16 +10,19 @@ pub fn new_auto_increment_table() -> Table {
             Column {
                 name: "table".to_owned(),
                 dtype: Type::String,
+                nullable: false,
                 default: None,
             },
             Column {
                 name: "column".to_owned(),
                 dtype: Type::String,
+                nullable: false,
                 default: None,
             },
             Column {
                 name: "num".to_owned(),
                 dtype: Type::U64,
+                nullable: false,
                 default: Some(schema::Default::Data(Data::U64(1))),
             },
         ],
@@ -1,8 +1,16 @@
-use std::convert::TryInto;
+use std::{
+    cmp::Ordering,
+    convert::TryInto,
+    hash::{Hash, Hasher},
+};
 
 use serde::{Deserialize, Serialize};
 
-// I64, Date, Datetime, Time, Json, Bool, Bytes
+use crate::datetime::{
+    format_date, format_time, format_timestamp, parse_date, parse_time, parse_timestamp,
+};
+
+// Json
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Type {
@@ -10,14 +18,35 @@ pub enum Type {
     String,
     OptionU64,
     Lancer,
+    I64,
+    F64,
+    Bool,
+    Bytes,
+    Date,
+    Time,
+    Timestamp,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+// 数値 (U64, I64, F64) は型が違っても値で比べる
+// F64 は -0.0 と 0.0 を同じ値とし、NaN はすべて同じ値で、どの数よりも大きい
+// Null は nullable な列にだけ入り、どの値よりも小さい
+// それ以外の型の違う値は比べられない (partial_cmp は None)
+// Date, Time, Timestamp の値は datetime を参照
+// (OptionU64 は古いファイルのために残しているだけで、nullable な U64 の列を使う)
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Data {
     U64(u64),
     String(String),
     OptionU64(Option<u64>),
     Lancer(u16),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    Null,
+    Bytes(Vec<u8>),
+    Date(i32),
+    Time(u64),
+    Timestamp(i64),
 }
 
 impl Type {
@@ -27,6 +56,30 @@ impl Type {
             Type::String => None,
             Type::OptionU64 => Some(9),
             Type::Lancer => None,
+            Type::I64 => Some(8),
+            Type::F64 => Some(8),
+            Type::Bool => Some(1),
+            Type::Bytes => None,
+            Type::Date => Some(4),
+            Type::Time => Some(8),
+            Type::Timestamp => Some(8),
+        }
+    }
+
+    // キーとしてエンコードしたときのサイズ
+    pub fn key_size(&self) -> Option<usize> {
+        match self {
+            Type::U64 => Some(8),
+            Type::String => None,
+            Type::OptionU64 => None,
+            Type::Lancer => None,
+            Type::I64 => Some(8),
+            Type::F64 => Some(8),
+            Type::Bool => Some(1),
+            Type::Bytes => None,
+            Type::Date => Some(4),
+            Type::Time => Some(8),
+            Type::Timestamp => Some(8),
         }
     }
 }
@@ -38,24 +91,197 @@ impl Data {
             Data::String(s) => s.as_bytes().len(),
             Data::OptionU64(_) => 9,
             Data::Lancer(size) => *size as usize,
+            Data::I64(_) => 8,
+            Data::F64(_) => 8,
+            Data::Bool(_) => 1,
+            Data::Null => 0,
+            Data::Bytes(v) => v.len(),
+            Data::Date(_) => 4,
+            Data::Time(_) => 8,
+            Data::Timestamp(_) => 8,
+        }
+    }
+
+    // 文字列から読んだリテラルを列の型に合わせる
+    // 数値は値が変わらないときだけ変換し、変換できず型も合わなければ Err を返す
+    pub fn coerce(self, typ: Type) -> Result<Data, String> {
+        Ok(match (self, typ) {
+            (Data::U64(v), Type::I64) if v <= i64::MAX as u64 => Data::I64(v as i64),
+            (Data::U64(v), Type::F64) if v as f64 as u64 == v => Data::F64(v as f64),
+            (Data::I64(v), Type::U64) if 0 <= v => Data::U64(v as u64),
+            (Data::I64(v), Type::F64) if v as f64 as i64 == v => Data::F64(v as f64),
+            (Data::String(s), Type::U64) if s.parse::<u64>().is_ok() => {
+                Data::U64(s.parse().unwrap())
+            }
+            (Data::String(s), Type::I64) if s.parse::<i64>().is_ok() => {
+                Data::I64(s.parse().unwrap())
+            }
+            (Data::String(s), Type::F64) if s.parse::<f64>().is_ok() => {
+                Data::F64(s.parse().unwrap())
+            }
+            (Data::String(s), Type::Bool) if s == "true" || s == "false" => Data::Bool(s == "true"),
+            (Data::Bool(v), Type::String) => Data::String(v.to_string()),
+            (Data::String(s), Type::Bytes) if parse_bytes_literal(&s).is_some() => {
+                Data::Bytes(parse_bytes_literal(&s).unwrap())
+            }
+            (Data::String(s), Type::Date) if parse_date(&s).is_some() => {
+                Data::Date(parse_date(&s).unwrap())
+            }
+            (Data::String(s), Type::Time) if parse_time(&s).is_some() => {
+                Data::Time(parse_time(&s).unwrap())
+            }
+            (Data::String(s), Type::Timestamp) if parse_timestamp(&s).is_some() => {
+                Data::Timestamp(parse_timestamp(&s).unwrap())
+            }
+            (data, typ) if data.is_of_type(typ) => data,
+            (data, typ) => return Err(format!("cannot convert {:?} to {:?}", data, typ)),
+        })
+    }
+
+    // 値が typ の列にそのまま入るか
+    // Null はどの型にも合うものとし、nullable かどうかは列の側で確かめる
+    pub fn is_of_type(&self, typ: Type) -> bool {
+        matches!(
+            (self, typ),
+            (Data::Null, _)
+                | (Data::U64(_), Type::U64)
+                | (Data::String(_), Type::String)
+                | (Data::OptionU64(_), Type::OptionU64)
+                | (Data::Lancer(_), Type::Lancer)
+                | (Data::I64(_), Type::I64)
+                | (Data::F64(_), Type::F64)
+                | (Data::Bool(_), Type::Bool)
+                | (Data::Bytes(_), Type::Bytes)
+                | (Data::Date(_), Type::Date)
+                | (Data::Time(_), Type::Time)
+                | (Data::Timestamp(_), Type::Timestamp)
+        )
+    }
+
+    fn as_number(&self) -> Option<Number> {
+        match self {
+            Data::U64(v) => Some(Number::Int(*v as i128)),
+            Data::I64(v) => Some(Number::Int(*v as i128)),
+            Data::F64(v) => Some(Number::Float(canonical_f64(*v))),
+            _ => None,
         }
     }
 }
 
-impl PartialOrd for Data {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        // match (self, other) {
-        //     (Data::U64(left), Data::U64(right)) => {
-        //         left.to_le_bytes().partial_cmp(&right.to_le_bytes())
-        //     },
-        //     (Data::String(left), Data::String(right)) => todo!(),
-        //     (Data::OptionU64(left), Data::OptionU64(right)) => todo!(),
-        //     (Data::Lancer(left), Data::Lancer(right)) => todo!(),
-        //     _ => panic!("different types are compared")
-        // }
+enum Number {
+    Int(i128),
+    Float(f64),
+}
 
-        // TODO: refine!
-        data_vec_to_bytes(&[self.clone()]).partial_cmp(&data_vec_to_bytes(&[other.clone()]))
+impl Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Number::Int(l), Number::Int(r)) => l.cmp(r),
+            (Number::Float(l), Number::Float(r)) => l.total_cmp(r),
+            (Number::Int(l), Number::Float(r)) => cmp_int_float(*l, *r),
+            (Number::Float(l), Number::Int(r)) => cmp_int_float(*r, *l).reverse(),
+        }
+    }
+}
+
+// 丸めずに比べる
+fn cmp_int_float(i: i128, f: f64) -> Ordering {
+    if f.is_nan() {
+        return Ordering::Less;
+    }
+    // 範囲外の floor は飽和するので、それでも大小は正しい
+    let floor = f.floor();
+    match i.cmp(&(floor as i128)) {
+        Ordering::Equal if floor < f => Ordering::Less,
+        ordering => ordering,
+    }
+}
+
+// -0.0 を 0.0 に、NaN を一つの NaN にそろえる
+fn canonical_f64(v: f64) -> f64 {
+    if v.is_nan() {
+        f64::NAN
+    } else if v == 0.0 {
+        0.0
+    } else {
+        v
+    }
+}
+
+impl PartialEq for Data {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Data::String(l), Data::String(r)) => l == r,
+            (Data::OptionU64(l), Data::OptionU64(r)) => l == r,
+            (Data::Lancer(l), Data::Lancer(r)) => l == r,
+            (Data::Bool(l), Data::Bool(r)) => l == r,
+            (Data::Null, Data::Null) => true,
+            (Data::Bytes(l), Data::Bytes(r)) => l == r,
+            (Data::Date(l), Data::Date(r)) => l == r,
+            (Data::Time(l), Data::Time(r)) => l == r,
+            (Data::Timestamp(l), Data::Timestamp(r)) => l == r,
+            _ => match (self.as_number(), other.as_number()) {
+                (Some(l), Some(r)) => l.cmp(&r) == Ordering::Equal,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Eq for Data {}
+
+// 等しい数値は型によらず同じハッシュになるように、整数になる F64 は整数として扱う
+impl Hash for Data {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.as_number() {
+            Some(Number::Int(v)) => v.hash(state),
+            Some(Number::Float(v)) if v.fract() == 0.0 && v.abs() < 2f64.powi(127) => {
+                (v as i128).hash(state)
+            }
+            Some(Number::Float(v)) => v.to_bits().hash(state),
+            None => {
+                std::mem::discriminant(self).hash(state);
+                match self {
+                    Data::String(v) => v.hash(state),
+                    Data::OptionU64(v) => v.hash(state),
+                    Data::Lancer(v) => v.hash(state),
+                    Data::Bool(v) => v.hash(state),
+                    Data::Null => {}
+                    Data::Bytes(v) => v.hash(state),
+                    Data::Date(v) => v.hash(state),
+                    Data::Time(v) => v.hash(state),
+                    Data::Timestamp(v) => v.hash(state),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+impl PartialOrd for Data {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Data::Null, Data::Null) => return Some(Ordering::Equal),
+            (Data::Null, _) => return Some(Ordering::Less),
+            (_, Data::Null) => return Some(Ordering::Greater),
+            _ => {}
+        }
+        if let (Some(l), Some(r)) = (self.as_number(), other.as_number()) {
+            return Some(l.cmp(&r));
+        }
+        // キーのエンコードと同じ順序になるように比べる
+        // 型の違うものは比べられない
+        match (self, other) {
+            (Data::String(l), Data::String(r)) => Some(l.as_bytes().cmp(r.as_bytes())),
+            (Data::Bytes(l), Data::Bytes(r)) => Some(l.cmp(r)),
+            (Data::OptionU64(l), Data::OptionU64(r)) => Some(l.cmp(r)),
+            (Data::Lancer(l), Data::Lancer(r)) => Some(l.cmp(r)),
+            (Data::Bool(l), Data::Bool(r)) => Some(l.cmp(r)),
+            (Data::Date(l), Data::Date(r)) => Some(l.cmp(r)),
+            (Data::Time(l), Data::Time(r)) => Some(l.cmp(r)),
+            (Data::Timestamp(l), Data::Timestamp(r)) => Some(l.cmp(r)),
+            _ => None,
+        }
     }
 }
 
@@ -72,6 +298,20 @@ impl std::fmt::Display for Data {
                 }
             }
             Data::Lancer(size) => write!(f, "<{}>", size),
+            Data::I64(v) => write!(f, "{:?}", v),
+            Data::F64(v) => write!(f, "{:?}", v),
+            Data::Bool(v) => write!(f, "{}", v),
+            Data::Null => write!(f, "null"),
+            Data::Bytes(v) => {
+                write!(f, "0x")?;
+                for b in v {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+            Data::Date(v) => write!(f, "{}", format_date(*v)),
+            Data::Time(v) => write!(f, "{}", format_time(*v)),
+            Data::Timestamp(v) => write!(f, "{}", format_timestamp(*v)),
         }
     }
 }
@@ -87,13 +327,33 @@ pub fn data_vec_from_bytes(types: &[Type], bytes: &[u8]) -> Option<Vec<Data>> {
                 i += 8;
             }
             Type::String => {
-                let size = parse_u16(&bytes[i..i + 2]) as usize;
+                let (size, header_size) = parse_string_size(&bytes[i..]);
                 vec.push(Data::String(
-                    String::from_utf8(bytes[i + 2..i + 2 + size].to_vec())
-                        .ok()
-                        .unwrap(),
+                    String::from_utf8(bytes[i + header_size..i + header_size + size].to_vec())
+                        .ok()?,
                 ));
-                i += 2 + size;
+                i += header_size + size;
+            }
+            Type::Bytes => {
+                let (size, header_size) = parse_string_size(&bytes[i..]);
+                vec.push(Data::Bytes(
+                    bytes[i + header_size..i + header_size + size].to_vec(),
+                ));
+                i += header_size + size;
+            }
+            Type::Date => {
+                vec.push(Data::Date(i32::from_le_bytes(
+                    bytes[i..i + 4].try_into().unwrap(),
+                )));
+                i += 4;
+            }
+            Type::Time => {
+                vec.push(Data::Time(parse_u64(&bytes[i..i + 8])));
+                i += 8;
+            }
+            Type::Timestamp => {
+                vec.push(Data::Timestamp(parse_u64(&bytes[i..i + 8]) as i64));
+                i += 8;
             }
             Type::OptionU64 => {
                 if bytes[i] == 0 {
@@ -108,39 +368,379 @@ pub fn data_vec_from_bytes(types: &[Type], bytes: &[u8]) -> Option<Vec<Data>> {
                 vec.push(Data::Lancer(size as u16));
                 i += 2 + size;
             }
+            Type::I64 => {
+                vec.push(Data::I64(parse_u64(&bytes[i..i + 8]) as i64));
+                i += 8;
+            }
+            Type::F64 => {
+                vec.push(Data::F64(f64::from_bits(parse_u64(&bytes[i..i + 8]))));
+                i += 8;
+            }
+            Type::Bool => {
+                vec.push(Data::Bool(bytes[i] != 0));
+                i += 1;
+            }
         }
     }
     debug_assert_eq!(bytes.len(), i, "length mismatched, bytes: {:?}", &bytes);
     Some(vec)
 }
 
-pub fn data_vec_to_bytes(datas: &[Data]) -> Vec<u8> {
+// null は値のエンコードを持たないので、null があれば Err を返す
+// (行は row_to_bytes で null のビットマップと一緒に書く)
+pub fn data_vec_to_bytes(datas: &[Data]) -> Result<Vec<u8>, String> {
+    if datas.iter().any(|d| matches!(d, Data::Null)) {
+        return Err("null has no value encoding".to_owned());
+    }
     let mut bytes = Vec::new();
     for data in datas {
-        match data {
-            Data::U64(v) => bytes.extend(v.to_le_bytes()),
-            Data::String(s) => {
-                bytes.extend((s.len() as u16).to_le_bytes());
-                bytes.extend(s.as_bytes())
+        write_data(&mut bytes, data);
+    }
+    // dbg!((&datas, &bytes));
+    Ok(bytes)
+}
+
+// null は何も書かない
+fn write_data(bytes: &mut Vec<u8>, data: &Data) {
+    match data {
+        Data::U64(v) => bytes.extend(v.to_le_bytes()),
+        Data::String(s) => write_sized(bytes, s.as_bytes()),
+        Data::Bytes(v) => write_sized(bytes, v),
+        Data::Date(v) => bytes.extend(v.to_le_bytes()),
+        Data::Time(v) => bytes.extend(v.to_le_bytes()),
+        Data::Timestamp(v) => bytes.extend(v.to_le_bytes()),
+        Data::OptionU64(v) => {
+            if let Some(v) = v {
+                bytes.push(1);
+                bytes.extend(v.to_le_bytes());
+            } else {
+                bytes.extend([0; 9]);
+            }
+        }
+        Data::Lancer(size) => {
+            bytes.extend(size.to_le_bytes());
+            bytes.extend((0..*size).map(|_| 0));
+        }
+        Data::I64(v) => bytes.extend(v.to_le_bytes()),
+        Data::F64(v) => bytes.extend(v.to_bits().to_le_bytes()),
+        Data::Bool(v) => bytes.push(*v as u8),
+        Data::Null => {}
+    }
+}
+
+// 長さは String と同じく u16 で、LONG_STRING_MARK の後なら u32
+fn write_sized(bytes: &mut Vec<u8>, v: &[u8]) {
+    if v.len() < LONG_STRING_MARK as usize {
+        bytes.extend((v.len() as u16).to_le_bytes());
+    } else {
+        bytes.extend(LONG_STRING_MARK.to_le_bytes());
+        bytes.extend((v.len() as u32).to_le_bytes());
+    }
+    bytes.extend(v)
+}
+
+// # row encoding
+// [ceil(n / 8)] null bitmap (i 番目の値が null なら i ビット目が 1)
+// null でない値だけを data_vec_to_bytes でつなげたもの
+
+pub fn null_bitmap_size(columns_num: usize) -> usize {
+    columns_num.div_ceil(8)
+}
+
+pub fn row_to_bytes(datas: &[Data]) -> Vec<u8> {
+    let mut bytes = vec![0; null_bitmap_size(datas.len())];
+    for (i, data) in datas.iter().enumerate() {
+        if let Data::Null = data {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+    }
+    for data in datas {
+        write_data(&mut bytes, data);
+    }
+    bytes
+}
+
+pub fn row_from_bytes(types: &[Type], bytes: &[u8]) -> Option<Vec<Data>> {
+    let bitmap = bytes.get(..null_bitmap_size(types.len()))?;
+    let is_null = |i: usize| bitmap[i / 8] & (1 << (i % 8)) != 0;
+    let value_types: Vec<_> = types
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !is_null(*i))
+        .map(|(_, t)| *t)
+        .collect();
+    let mut values = data_vec_from_bytes(&value_types, &bytes[bitmap.len()..])?.into_iter();
+    Some(
+        (0..types.len())
+            .map(|i| {
+                if is_null(i) {
+                    Data::Null
+                } else {
+                    values.next().unwrap()
+                }
+            })
+            .collect(),
+    )
+}
+
+// # key encoding
+// B-tree のキーに使う、バイト列の比較で順序が保たれるエンコード
+// 値の行フォーマット (data_vec_to_bytes) とは別物
+//
+// U64: big endian
+// String: 0x00 を 0x00 0xff にエスケープし、0x00 0x00 で終端する
+// OptionU64: None は 0x00、Some は 0x01 の後に big endian (null が先に並ぶ)
+// Lancer: big endian の長さの後に 0 を長さ分
+// I64: 符号ビットを反転した big endian
+// F64: -0.0 と NaN をそろえ、正なら符号ビットを、負なら全ビットを反転した big endian
+//      (-inf < 負 < 0 < 正 < inf < NaN)
+// Bool: false は 0x00、true は 0x01
+// Bytes: String と同じ (バイトごとに比べる)
+// Date: 符号ビットを反転した 4 バイトの big endian
+// Time: big endian
+// Timestamp: I64 と同じ
+//
+// nullable な列では、null は 0x00、それ以外は 0x01 の後に上の値 (null が先に並ぶ)
+
+pub fn data_vec_to_key(datas: &[Data]) -> Vec<u8> {
+    data_vec_to_nullable_key(datas, &[])
+}
+
+// nullable[k] が true の列は null を持てる (足りない分は nullable でない)
+pub fn data_vec_to_nullable_key(datas: &[Data], nullable: &[bool]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (k, data) in datas.iter().enumerate() {
+        if nullable.get(k) == Some(&true) {
+            if let Data::Null = data {
+                bytes.push(0);
+                continue;
             }
+            bytes.push(1);
+        }
+        match data {
+            Data::U64(v) => bytes.extend(v.to_be_bytes()),
+            Data::String(s) => write_escaped(&mut bytes, s.as_bytes()),
+            Data::Bytes(v) => write_escaped(&mut bytes, v),
+            Data::Date(v) => bytes.extend(((*v as u32) ^ (1 << 31)).to_be_bytes()),
+            Data::Time(v) => bytes.extend(v.to_be_bytes()),
+            Data::Timestamp(v) => bytes.extend(((*v as u64) ^ SIGN_BIT).to_be_bytes()),
             Data::OptionU64(v) => {
                 if let Some(v) = v {
                     bytes.push(1);
-                    bytes.extend(v.to_le_bytes());
+                    bytes.extend(v.to_be_bytes());
                 } else {
-                    bytes.extend([0; 9]);
+                    bytes.push(0);
                 }
             }
             Data::Lancer(size) => {
-                bytes.extend(size.to_le_bytes());
+                bytes.extend(size.to_be_bytes());
                 bytes.extend((0..*size).map(|_| 0));
             }
+            Data::I64(v) => bytes.extend(((*v as u64) ^ SIGN_BIT).to_be_bytes()),
+            Data::F64(v) => {
+                let bits = canonical_f64(*v).to_bits();
+                let bits = if bits & SIGN_BIT == 0 {
+                    bits ^ SIGN_BIT
+                } else {
+                    !bits
+                };
+                bytes.extend(bits.to_be_bytes());
+            }
+            Data::Bool(v) => bytes.push(*v as u8),
+            Data::Null => bytes.push(0),
         }
     }
-    // dbg!((&datas, &bytes));
     bytes
 }
 
+const SIGN_BIT: u64 = 1 << 63;
+
+fn write_escaped(bytes: &mut Vec<u8>, v: &[u8]) {
+    for b in v {
+        bytes.push(*b);
+        if *b == 0 {
+            bytes.push(0xff);
+        }
+    }
+    bytes.extend([0, 0]);
+}
+
+fn read_escaped(bytes: &[u8], i: &mut usize) -> Option<Vec<u8>> {
+    let mut v = Vec::new();
+    loop {
+        match (bytes.get(*i)?, bytes.get(*i + 1)) {
+            (0, Some(0)) => break,
+            (0, Some(0xff)) => {
+                v.push(0);
+                *i += 2;
+            }
+            (0, _) => return None,
+            (b, _) => {
+                v.push(*b);
+                *i += 1;
+            }
+        }
+    }
+    *i += 2;
+    Some(v)
+}
+
+pub fn data_vec_from_key(types: &[Type], bytes: &[u8]) -> Option<Vec<Data>> {
+    data_vec_from_nullable_key(types, &[], bytes)
+}
+
+pub fn data_vec_from_nullable_key(
+    types: &[Type],
+    nullable: &[bool],
+    bytes: &[u8],
+) -> Option<Vec<Data>> {
+    let mut i = 0;
+    let mut vec = Vec::with_capacity(types.len());
+    for (k, typ) in types.iter().enumerate() {
+        if nullable.get(k) == Some(&true) {
+            i += 1;
+            match bytes.get(i - 1)? {
+                0 => {
+                    vec.push(Data::Null);
+                    continue;
+                }
+                1 => {}
+                _ => return None,
+            }
+        }
+        match typ {
+            Type::U64 => {
+                vec.push(Data::U64(u64::from_be_bytes(
+                    bytes.get(i..i + 8)?.try_into().unwrap(),
+                )));
+                i += 8;
+            }
+            Type::String => {
+                let s = read_escaped(bytes, &mut i)?;
+                vec.push(Data::String(String::from_utf8(s).ok()?));
+            }
+            Type::Bytes => vec.push(Data::Bytes(read_escaped(bytes, &mut i)?)),
+            Type::Date => {
+                let bits = u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().unwrap());
+                vec.push(Data::Date((bits ^ (1 << 31)) as i32));
+                i += 4;
+            }
+            Type::Time => {
+                vec.push(Data::Time(u64::from_be_bytes(
+                    bytes.get(i..i + 8)?.try_into().unwrap(),
+                )));
+                i += 8;
+            }
+            Type::Timestamp => {
+                let bits = u64::from_be_bytes(bytes.get(i..i + 8)?.try_into().unwrap());
+                vec.push(Data::Timestamp((bits ^ SIGN_BIT) as i64));
+                i += 8;
+            }
+            Type::OptionU64 => {
+                if *bytes.get(i)? == 0 {
+                    vec.push(Data::OptionU64(None));
+                    i += 1;
+                } else {
+                    vec.push(Data::OptionU64(Some(u64::from_be_bytes(
+                        bytes.get(i + 1..i + 9)?.try_into().unwrap(),
+                    ))));
+                    i += 9;
+                }
+            }
+            Type::Lancer => {
+                let size = u16::from_be_bytes(bytes.get(i..i + 2)?.try_into().unwrap());
+                vec.push(Data::Lancer(size));
+                i += 2 + size as usize;
+            }
+            Type::I64 => {
+                let bits = u64::from_be_bytes(bytes.get(i..i + 8)?.try_into().unwrap());
+                vec.push(Data::I64((bits ^ SIGN_BIT) as i64));
+                i += 8;
+            }
+            Type::F64 => {
+                let bits = u64::from_be_bytes(bytes.get(i..i + 8)?.try_into().unwrap());
+                let bits = if bits & SIGN_BIT != 0 {
+                    bits ^ SIGN_BIT
+                } else {
+                    !bits
+                };
+                vec.push(Data::F64(f64::from_bits(bits)));
+                i += 8;
+            }
+            Type::Bool => {
+                match bytes.get(i)? {
+                    0 => vec.push(Data::Bool(false)),
+                    1 => vec.push(Data::Bool(true)),
+                    _ => return None,
+                }
+                i += 1;
+            }
+        }
+    }
+    if i == bytes.len() {
+        Some(vec)
+    } else {
+        None
+    }
+}
+
+// 文字列の長さは u16 で、LONG_STRING_MARK の後なら u32
+const LONG_STRING_MARK: u16 = u16::MAX;
+
+// バイト列のリテラル: 0x の後に 16 進数、または base64: の後に base64 (標準の文字で、= の埋めは任意)
+pub fn parse_bytes_literal(s: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|k| u8::from_str_radix(hex.get(k..k + 2)?, 16).ok())
+            .collect()
+    } else if let Some(b64) = s.strip_prefix("base64:") {
+        let b64 = b64.trim_end_matches('=');
+        let mut v = Vec::with_capacity(b64.len() * 3 / 4);
+        let mut acc = 0u32;
+        for (k, c) in b64.bytes().enumerate() {
+            let sextet = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            acc = (acc << 6) | sextet as u32;
+            if k % 4 == 3 {
+                v.extend(&acc.to_be_bytes()[1..]);
+                acc = 0;
+            }
+        }
+        match b64.len() % 4 {
+            0 => {}
+            2 => v.push((acc >> 4) as u8),
+            3 => v.extend(&((acc >> 2) as u16).to_be_bytes()),
+            _ => return None,
+        }
+        Some(v)
+    } else {
+        None
+    }
+}
+
+// (文字列の長さ, 長さ自体のバイト数)
+fn parse_string_size(bytes: &[u8]) -> (usize, usize) {
+    let size = parse_u16(&bytes[0..2]);
+    if size == LONG_STRING_MARK {
+        (
+            u32::from_le_bytes(bytes[2..6].try_into().unwrap()) as usize,
+            6,
+        )
+    } else {
+        (size as usize, 2)
+    }
+}
+
 fn parse_u16(bytes: &[u8]) -> u16 {
     u16::from_le_bytes(bytes.try_into().unwrap())
 }
@@ -157,12 +757,376 @@ fn test() {
         Data::Lancer(10),
         Data::U64(321),
     ];
-    let bytes = data_vec_to_bytes(&datas);
+    let bytes = data_vec_to_bytes(&datas).unwrap();
     dbg!(&bytes);
-    let decoded = data_vec_from_bytes(
-        &vec![Type::U64, Type::String, Type::Lancer, Type::U64],
-        &bytes,
-    )
-    .unwrap();
+    let decoded =
+        data_vec_from_bytes(&[Type::U64, Type::String, Type::Lancer, Type::U64], &bytes).unwrap();
     assert_eq!(datas, decoded);
+
+    let datas = vec![
+        Data::String("a".repeat(u16::MAX as usize)),
+        Data::String("b".repeat(100_000)),
+        Data::String("c".repeat(u16::MAX as usize - 1)),
+    ];
+    let bytes = data_vec_to_bytes(&datas).unwrap();
+    let decoded = data_vec_from_bytes(&[Type::String, Type::String, Type::String], &bytes).unwrap();
+    assert_eq!(datas, decoded);
+}
+
+#[test]
+fn test_key() {
+    let datas = vec![
+        Data::U64(123),
+        Data::String("hello\0, わーるど😸".to_owned()),
+        Data::OptionU64(None),
+        Data::OptionU64(Some(7)),
+        Data::Lancer(3),
+    ];
+    let types = vec![
+        Type::U64,
+        Type::String,
+        Type::OptionU64,
+        Type::OptionU64,
+        Type::Lancer,
+    ];
+    let bytes = data_vec_to_key(&datas);
+    assert_eq!(data_vec_from_key(&types, &bytes), Some(datas));
+
+    // バイト列の順序と値の順序が一致する
+    let ordered = vec![
+        vec![Data::U64(1), Data::String("".to_owned())],
+        vec![Data::U64(1), Data::String("\0".to_owned())],
+        vec![Data::U64(1), Data::String("\0\0".to_owned())],
+        vec![Data::U64(1), Data::String("a".to_owned())],
+        vec![Data::U64(1), Data::String("a\0b".to_owned())],
+        vec![Data::U64(1), Data::String("ab".to_owned())],
+        vec![Data::U64(1), Data::String("b".to_owned())],
+        vec![Data::U64(256), Data::String("".to_owned())],
+        vec![Data::U64(u64::MAX), Data::String("".to_owned())],
+    ];
+    for w in ordered.windows(2) {
+        assert!(data_vec_to_key(&w[0]) < data_vec_to_key(&w[1]), "{:?}", w);
+    }
+    assert!(Data::U64(1) < Data::U64(256));
+    assert!(Data::OptionU64(None) < Data::OptionU64(Some(0)));
+}
+
+#[test]
+fn test_signed_and_float() {
+    use std::collections::HashSet;
+
+    let datas = vec![Data::I64(-123), Data::F64(-1.5), Data::F64(f64::INFINITY)];
+    let types = vec![Type::I64, Type::F64, Type::F64];
+    let bytes = data_vec_to_bytes(&datas).unwrap();
+    assert_eq!(bytes.len(), 24);
+    assert_eq!(data_vec_from_bytes(&types, &bytes).unwrap(), datas);
+    let key = data_vec_to_key(&datas);
+    assert_eq!(data_vec_from_key(&types, &key), Some(datas));
+
+    let ordered = [
+        Data::I64(i64::MIN),
+        Data::I64(-256),
+        Data::I64(-1),
+        Data::I64(0),
+        Data::I64(1),
+        Data::I64(256),
+        Data::I64(i64::MAX),
+    ];
+    for w in ordered.windows(2) {
+        assert!(
+            data_vec_to_key(&w[..1]) < data_vec_to_key(&w[1..]),
+            "{:?}",
+            w
+        );
+        assert!(w[0] < w[1], "{:?}", w);
+    }
+    let ordered = vec![
+        Data::F64(f64::NEG_INFINITY),
+        Data::F64(f64::MIN),
+        Data::F64(-1.5),
+        Data::F64(-f64::MIN_POSITIVE),
+        Data::F64(0.0),
+        Data::F64(f64::MIN_POSITIVE),
+        Data::F64(1.5),
+        Data::F64(f64::MAX),
+        Data::F64(f64::INFINITY),
+        Data::F64(f64::NAN),
+    ];
+    for w in ordered.windows(2) {
+        assert!(
+            data_vec_to_key(&w[..1]) < data_vec_to_key(&w[1..]),
+            "{:?}",
+            w
+        );
+        assert!(w[0] < w[1], "{:?}", w);
+    }
+
+    // -0.0 と 0.0、NaN 同士は同じキーになる
+    assert_eq!(
+        data_vec_to_key(&[Data::F64(-0.0)]),
+        data_vec_to_key(&[Data::F64(0.0)])
+    );
+    assert_eq!(
+        data_vec_to_key(&[Data::F64(-f64::NAN)]),
+        data_vec_to_key(&[Data::F64(f64::NAN)])
+    );
+    assert_eq!(Data::F64(-0.0), Data::F64(0.0));
+    assert_eq!(Data::F64(f64::NAN), Data::F64(f64::NAN));
+
+    // 数値は型によらず値で比べる
+    assert!(Data::I64(-1) < Data::U64(0));
+    assert!(Data::U64(u64::MAX) > Data::I64(i64::MAX));
+    assert!(Data::F64(0.5) < Data::U64(1));
+    assert!(Data::F64(-0.5) > Data::I64(-1));
+    assert!(Data::F64(9007199254740992.0) < Data::U64(9007199254740993));
+    assert_eq!(Data::F64(3.0), Data::I64(3));
+    assert_eq!(Data::U64(3), Data::I64(3));
+    let set: HashSet<_> = vec![Data::U64(3), Data::I64(3), Data::F64(3.0), Data::F64(3.5)]
+        .into_iter()
+        .collect();
+    assert_eq!(set.len(), 2);
+
+    assert_eq!(Data::U64(5).coerce(Type::I64), Ok(Data::I64(5)));
+    assert!(matches!(Data::U64(5).coerce(Type::F64), Ok(Data::F64(v)) if v == 5.0));
+    assert!(Data::I64(-5).coerce(Type::U64).is_err());
+    assert!(matches!(
+        Data::String("nan".to_owned()).coerce(Type::F64),
+        Ok(Data::F64(v)) if v.is_nan()
+    ));
+    assert!(Data::String("x".to_owned()).coerce(Type::I64).is_err());
+    assert_eq!(Data::Null.coerce(Type::I64), Ok(Data::Null));
+    assert_eq!(format!("{} {}", Data::I64(-3), Data::F64(2.0)), "-3 2.0");
+}
+
+#[test]
+fn test_bool() {
+    let datas = vec![Data::Bool(true), Data::U64(1), Data::Bool(false)];
+    let types = vec![Type::Bool, Type::U64, Type::Bool];
+    let bytes = data_vec_to_bytes(&datas).unwrap();
+    assert_eq!(bytes.len(), 10);
+    assert_eq!(data_vec_from_bytes(&types, &bytes).unwrap(), datas);
+    let key = data_vec_to_key(&datas);
+    assert_eq!(data_vec_from_key(&types, &key), Some(datas));
+    assert_eq!(data_vec_from_key(&[Type::Bool], &[2]), None);
+
+    assert!(data_vec_to_key(&[Data::Bool(false)]) < data_vec_to_key(&[Data::Bool(true)]));
+    assert!(Data::Bool(false) < Data::Bool(true));
+    assert_ne!(Data::Bool(true), Data::U64(1));
+    assert_eq!(
+        Data::String("true".to_owned()).coerce(Type::Bool),
+        Ok(Data::Bool(true))
+    );
+    assert_eq!(
+        Data::Bool(false).coerce(Type::String),
+        Ok(Data::String("false".to_owned()))
+    );
+    assert_eq!(format!("{}", Data::Bool(true)), "true");
+}
+
+#[test]
+fn test_null() {
+    let datas = vec![
+        Data::Null,
+        Data::String("a".to_owned()),
+        Data::Null,
+        Data::U64(3),
+        Data::Null,
+        Data::Null,
+        Data::Null,
+        Data::Null,
+        Data::Bool(true),
+    ];
+    let types = vec![
+        Type::String,
+        Type::String,
+        Type::U64,
+        Type::U64,
+        Type::F64,
+        Type::I64,
+        Type::Bool,
+        Type::String,
+        Type::Bool,
+    ];
+    let bytes = row_to_bytes(&datas);
+    assert_eq!(&bytes[..2], &[0b1111_0101, 0]);
+    assert_eq!(row_from_bytes(&types, &bytes), Some(datas.clone()));
+    assert_eq!(row_from_bytes(&[], &row_to_bytes(&[])), Some(vec![]));
+    // null のビットマップがないので、値のエンコードには null を入れられない
+    assert!(data_vec_to_bytes(&datas).is_err());
+
+    // null は先に並ぶ
+    let nullable = [true, false];
+    let ordered = vec![
+        vec![Data::Null, Data::U64(5)],
+        vec![Data::Null, Data::U64(6)],
+        vec![Data::String("".to_owned()), Data::U64(0)],
+        vec![Data::String("\0".to_owned()), Data::U64(0)],
+    ];
+    for w in ordered.windows(2) {
+        assert!(
+            data_vec_to_nullable_key(&w[0], &nullable) < data_vec_to_nullable_key(&w[1], &nullable),
+            "{:?}",
+            w
+        );
+        assert!(w[0] < w[1], "{:?}", w);
+    }
+    for datas in ordered {
+        let key = data_vec_to_nullable_key(&datas, &nullable);
+        assert_eq!(
+            data_vec_from_nullable_key(&[Type::String, Type::U64], &nullable, &key),
+            Some(datas)
+        );
+    }
+    assert_eq!(
+        data_vec_from_nullable_key(&[Type::U64], &[true], &[2]),
+        None
+    );
+    assert!(Data::Null < Data::I64(i64::MIN));
+    assert!(Data::Null < Data::F64(f64::NEG_INFINITY));
+    assert_eq!(Data::Null, Data::Null);
+    assert_ne!(Data::Null, Data::OptionU64(None));
+}
+
+#[test]
+fn test_bytes() {
+    let datas = vec![
+        Data::Bytes(vec![0xff, 0, 0xfe, 0x80]),
+        Data::Bytes(vec![]),
+        Data::Bytes(vec![7; 70_000]),
+    ];
+    let types = vec![Type::Bytes, Type::Bytes, Type::Bytes];
+    assert_eq!(
+        data_vec_from_bytes(&types, &data_vec_to_bytes(&datas).unwrap()),
+        Some(datas.clone())
+    );
+    assert_eq!(
+        data_vec_from_key(&types, &data_vec_to_key(&datas)),
+        Some(datas)
+    );
+    // 不正な UTF-8 は String としては読めない
+    let bytes = data_vec_to_bytes(&[Data::Bytes(vec![0xff, 0xfe])]).unwrap();
+    assert_eq!(data_vec_from_bytes(&[Type::String], &bytes), None);
+
+    let ordered = [
+        vec![],
+        vec![0],
+        vec![0, 0],
+        vec![0, 1],
+        vec![1],
+        vec![0x7f, 0xff],
+        vec![0x80],
+        vec![0xff],
+    ];
+    for w in ordered.windows(2) {
+        let l = Data::Bytes(w[0].clone());
+        let r = Data::Bytes(w[1].clone());
+        assert!(
+            data_vec_to_key(&[l.clone()]) < data_vec_to_key(&[r.clone()]),
+            "{:?}",
+            w
+        );
+        assert!(l < r, "{:?}", w);
+    }
+    let l = Data::Bytes(vec![0x61]);
+    let r = Data::String("a".to_owned());
+    assert_eq!(l.partial_cmp(&r), None);
+    assert_eq!(r.partial_cmp(&l), None);
+
+    assert_eq!(parse_bytes_literal("0x00ff10"), Some(vec![0, 0xff, 0x10]));
+    assert_eq!(parse_bytes_literal("0xABcd"), Some(vec![0xab, 0xcd]));
+    assert_eq!(parse_bytes_literal("0x"), Some(vec![]));
+    assert_eq!(parse_bytes_literal("0x0"), None);
+    assert_eq!(parse_bytes_literal("0xzz"), None);
+    assert_eq!(parse_bytes_literal("0xé0"), None);
+    assert_eq!(parse_bytes_literal("base64:"), Some(vec![]));
+    assert_eq!(parse_bytes_literal("base64:TWFu"), Some(b"Man".to_vec()));
+    assert_eq!(parse_bytes_literal("base64:TWE="), Some(b"Ma".to_vec()));
+    assert_eq!(parse_bytes_literal("base64:TQ=="), Some(b"M".to_vec()));
+    assert_eq!(parse_bytes_literal("base64:TQ"), Some(b"M".to_vec()));
+    assert_eq!(
+        parse_bytes_literal("base64:/+8A"),
+        Some(vec![0xff, 0xef, 0x00])
+    );
+    assert_eq!(parse_bytes_literal("base64:T"), None);
+    assert_eq!(parse_bytes_literal("base64:T!=="), None);
+    assert_eq!(parse_bytes_literal("ff"), None);
+
+    assert_eq!(
+        Data::String("0x0102".to_owned()).coerce(Type::Bytes),
+        Ok(Data::Bytes(vec![1, 2]))
+    );
+    assert_eq!(
+        Data::String("0x0102".to_owned()).coerce(Type::String),
+        Ok(Data::String("0x0102".to_owned()))
+    );
+    assert_eq!(format!("{}", Data::Bytes(vec![0, 0xab, 0x10])), "0x00ab10");
+    assert_eq!(format!("{}", Data::Bytes(vec![])), "0x");
+}
+
+#[test]
+fn test_datetime() {
+    let datas = vec![
+        Data::Date(-719162),
+        Data::Time(45_296_500_000),
+        Data::Timestamp(-1),
+    ];
+    let types = vec![Type::Date, Type::Time, Type::Timestamp];
+    let bytes = data_vec_to_bytes(&datas).unwrap();
+    assert_eq!(bytes.len(), 20);
+    assert_eq!(data_vec_from_bytes(&types, &bytes), Some(datas.clone()));
+    let key = data_vec_to_key(&datas);
+    assert_eq!(key.len(), 20);
+    assert_eq!(data_vec_from_key(&types, &key), Some(datas));
+
+    for ordered in [
+        vec![
+            "0001-01-01",
+            "1969-12-31",
+            "1970-01-01",
+            "2024-02-29",
+            "9999-12-31",
+        ]
+        .into_iter()
+        .map(|s| Data::String(s.to_owned()).coerce(Type::Date).unwrap())
+        .collect::<Vec<_>>(),
+        vec![
+            "00:00",
+            "00:00:00.000001",
+            "09:59:59",
+            "10:00",
+            "23:59:59.999999",
+        ]
+        .into_iter()
+        .map(|s| Data::String(s.to_owned()).coerce(Type::Time).unwrap())
+        .collect(),
+        vec![
+            "1969-12-31T23:59:59Z",
+            "1970-01-01",
+            "2024-05-06T07:00:00+09:00",
+            "2024-05-05T23:00:00Z",
+            "2024-05-06T07:00:00Z",
+        ]
+        .into_iter()
+        .map(|s| Data::String(s.to_owned()).coerce(Type::Timestamp).unwrap())
+        .collect(),
+    ] {
+        for w in ordered.windows(2) {
+            assert!(
+                data_vec_to_key(&w[..1]) < data_vec_to_key(&w[1..]),
+                "{:?}",
+                w
+            );
+            assert!(w[0] < w[1], "{:?}", w);
+        }
+    }
+
+    assert!(Data::String("2024-02-30".to_owned())
+        .coerce(Type::Date)
+        .is_err());
+    assert_eq!(format!("{}", Data::Date(19782)), "2024-02-29");
+    assert_eq!(format!("{}", Data::Time(45_296_500_000)), "12:34:56.5");
+    assert_eq!(
+        format!("{}", Data::Timestamp(1_000_000)),
+        "1970-01-01T00:00:01Z"
+    );
 }
@@ -0,0 +1,221 @@
+// Date, Time, Timestamp の ISO-8601 での読み書き
+//
+// Date: 1970-01-01 からの日数
+// Time: 0 時からのマイクロ秒
+// Timestamp: 1970-01-01T00:00:00Z からのマイクロ秒 (UTC)
+
+pub const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+// 日付の計算は先発グレゴリオ暦で、Howard Hinnant の days_from_civil による
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+fn days_in_month(y: i64, m: u32) -> u32 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// 数字だけからなる固定長の数
+fn parse_digits(s: &str) -> Option<u32> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// YYYY-MM-DD
+pub fn parse_date(s: &str) -> Option<i32> {
+    let b = s.as_bytes();
+    if b.len() != 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+    let y = parse_digits(&s[0..4])? as i64;
+    let m = parse_digits(&s[5..7])?;
+    let d = parse_digits(&s[8..10])?;
+    if !(1..=12).contains(&m) || d < 1 || days_in_month(y, m) < d {
+        return None;
+    }
+    Some(days_from_civil(y, m, d) as i32)
+}
+
+// HH:MM、HH:MM:SS、HH:MM:SS.ffffff (小数は 6 桁まで)
+pub fn parse_time(s: &str) -> Option<u64> {
+    let (hms, frac) = match s.split_once('.') {
+        Some((hms, frac)) => (hms, Some(frac)),
+        None => (s, None),
+    };
+    let mut parts = hms.split(':');
+    let h = parts
+        .next()
+        .filter(|p| p.len() == 2)
+        .and_then(parse_digits)?;
+    let m = parts
+        .next()
+        .filter(|p| p.len() == 2)
+        .and_then(parse_digits)?;
+    let sec = match parts.next() {
+        Some(p) if p.len() == 2 => parse_digits(p)?,
+        Some(_) => return None,
+        None if frac.is_none() => 0,
+        None => return None,
+    };
+    if parts.next().is_some() || 23 < h || 59 < m || 59 < sec {
+        return None;
+    }
+    let micros = match frac {
+        Some(f) if f.len() <= 6 => parse_digits(f)? as u64 * 10u64.pow(6 - f.len() as u32),
+        Some(_) => return None,
+        None => 0,
+    };
+    Some(((h as u64 * 60 + m as u64) * 60 + sec as u64) * 1_000_000 + micros)
+}
+
+// YYYY-MM-DD[(T| )time][Z|±HH:MM|±HHMM|±HH]
+// 時刻がなければ 0 時、時差がなければ UTC とする
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let days = parse_date(s.get(0..10)?)? as i64;
+    let rest = &s[10..];
+    if rest.is_empty() {
+        return Some(days * MICROS_PER_DAY);
+    }
+    let rest = rest.strip_prefix(['T', ' '])?;
+    let (time, offset) = match rest.find(['Z', '+', '-']) {
+        Some(k) => (&rest[..k], &rest[k..]),
+        None => (rest, ""),
+    };
+    let time = parse_time(time)? as i64;
+    let offset_minutes = match offset {
+        "" | "Z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let hm = offset[1..].replace(':', "");
+            if !(hm.len() == 2 || hm.len() == 4) {
+                return None;
+            }
+            let h = parse_digits(hm.get(0..2)?)? as i64;
+            let m = if hm.len() == 4 {
+                parse_digits(hm.get(2..4)?)? as i64
+            } else {
+                0
+            };
+            if 23 < h || 59 < m {
+                return None;
+            }
+            sign * (h * 60 + m)
+        }
+    };
+    Some(days * MICROS_PER_DAY + time - offset_minutes * 60_000_000)
+}
+
+pub fn format_date(days: i32) -> String {
+    let (y, m, d) = civil_from_days(days as i64);
+    if (0..=9999).contains(&y) {
+        format!("{:04}-{:02}-{:02}", y, m, d)
+    } else {
+        format!("{:+05}-{:02}-{:02}", y, m, d)
+    }
+}
+
+// 小数は 0 でなければ、末尾の 0 を除いて書く
+pub fn format_time(micros: u64) -> String {
+    let sec = micros / 1_000_000;
+    let frac = micros % 1_000_000;
+    let hms = format!("{:02}:{:02}:{:02}", sec / 3600, sec / 60 % 60, sec % 60);
+    if frac == 0 {
+        hms
+    } else {
+        format!("{}.{}", hms, format!("{:06}", frac).trim_end_matches('0'))
+    }
+}
+
+pub fn format_timestamp(micros: i64) -> String {
+    format!(
+        "{}T{}Z",
+        format_date(micros.div_euclid(MICROS_PER_DAY) as i32),
+        format_time(micros.rem_euclid(MICROS_PER_DAY) as u64)
+    )
+}
+
+#[test]
+fn test() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(days_from_civil(2000, 3, 1), 11017);
+    assert_eq!(days_from_civil(1969, 12, 31), -1);
+    for days in (-800_000..800_000).step_by(997) {
+        let (y, m, d) = civil_from_days(days);
+        assert_eq!(days_from_civil(y, m, d), days);
+    }
+
+    assert_eq!(parse_date("1970-01-01"), Some(0));
+    assert_eq!(parse_date("2024-02-29"), Some(19782));
+    assert_eq!(parse_date("0001-01-01"), Some(-719162));
+    assert_eq!(parse_date("2023-02-29"), None);
+    assert_eq!(parse_date("1900-02-29"), None);
+    assert_eq!(parse_date("2000-02-29"), Some(11016));
+    assert_eq!(parse_date("2024-13-01"), None);
+    assert_eq!(parse_date("2024-00-01"), None);
+    assert_eq!(parse_date("2024-1-01"), None);
+    assert_eq!(parse_date("2024-01-+1"), None);
+    assert_eq!(format_date(19782), "2024-02-29");
+    assert_eq!(format_date(-719162), "0001-01-01");
+    assert_eq!(format_date(-719528), "0000-01-01");
+    assert_eq!(format_date(-719529), "-0001-12-31");
+
+    assert_eq!(parse_time("00:00"), Some(0));
+    assert_eq!(parse_time("12:34:56"), Some(45_296_000_000));
+    assert_eq!(parse_time("12:34:56.5"), Some(45_296_500_000));
+    assert_eq!(parse_time("23:59:59.999999"), Some(86_399_999_999));
+    assert_eq!(parse_time("24:00:00"), None);
+    assert_eq!(parse_time("12:60"), None);
+    assert_eq!(parse_time("12:00:00.1234567"), None);
+    assert_eq!(parse_time("12:00."), None);
+    assert_eq!(parse_time("1:00"), None);
+    assert_eq!(format_time(45_296_500_000), "12:34:56.5");
+    assert_eq!(format_time(86_399_999_999), "23:59:59.999999");
+    assert_eq!(format_time(60_000_000), "00:01:00");
+
+    assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+    assert_eq!(parse_timestamp("1970-01-01T00:00:01Z"), Some(1_000_000));
+    assert_eq!(
+        parse_timestamp("2024-05-06T07:08:09.01+09:00"),
+        parse_timestamp("2024-05-05 22:08:09.010Z")
+    );
+    assert_eq!(
+        parse_timestamp("2024-05-06T07:08-0130"),
+        parse_timestamp("2024-05-06T08:38:00")
+    );
+    assert_eq!(parse_timestamp("1969-12-31T23:59:59.999999Z"), Some(-1));
+    assert_eq!(parse_timestamp("2024-05-06T07:08:09+9"), None);
+    assert_eq!(parse_timestamp("2024-05-06X07:08:09"), None);
+    assert_eq!(parse_timestamp("2024-05-06T"), None);
+    assert_eq!(parse_timestamp("2024-05-06T07:08+€0"), None);
+    assert_eq!(format_timestamp(-1), "1969-12-31T23:59:59.999999Z");
+    assert_eq!(
+        format_timestamp(parse_timestamp("2024-05-06T07:08:09+09:00").unwrap()),
+        "2024-05-05T22:08:09Z"
+    );
+}
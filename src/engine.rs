@@ -1,4 +1,7 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
 
 use crate::{
     data::Data,
@@ -7,13 +10,23 @@ use crate::{
     storage::Storage,
 };
 
+// select は複数のスレッドから同時に実行でき、書き込み中も開始時点の内容を読む
+// insert, delete, update は writer のロックで一つずつ実行する
 pub struct Engine<S: Storage> {
     storage: S,
+    writer: Mutex<()>,
 }
 
 impl<S: Storage> Engine<S> {
     pub fn from_storage(storage: S) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            writer: Mutex::new(()),
+        }
+    }
+
+    fn lock_writer(&self) -> Writer<'_> {
+        self.writer.lock().unwrap()
     }
 
     pub fn schema(&self) -> &Schema {
@@ -28,7 +41,7 @@ impl<S: Storage> Engine<S> {
         self.storage.add_table(table);
     }
 
-    pub fn execute_query(&mut self, query: &Query) -> Result<(Vec<String>, Vec<Data>), String> {
+    pub fn execute_query(&self, query: &Query) -> Result<(Vec<String>, Vec<Data>), String> {
         match query {
             Query::Select(select) => self.execute_select(select),
             Query::Insert(insert) => self.execute_insert(insert).map(|_| (vec![], vec![])),
@@ -38,44 +51,99 @@ impl<S: Storage> Engine<S> {
     }
 
     pub fn execute_select(&self, select: &Select) -> Result<(Vec<String>, Vec<Data>), String> {
-        let mut rows = vec![];
+        self.select(self.snapshot(), select)
+    }
+
+    // 結果を一行ずつ取り出す
+    // 行は取り出されるたびにカーソルを進めて作るので、途中でやめれば残りは読まない
+    pub fn execute_select_stream<'e>(
+        &'e self,
+        select: &'e Select,
+    ) -> Result<RowStream<'e, S>, String> {
+        self.select_stream(self.snapshot(), select)
+    }
+
+    fn snapshot(&self) -> S::Snapshot {
+        // 書き込みの途中で snapshot を取らない
+        let writer = self.lock_writer();
+        self.snapshot_in(&writer)
+    }
+
+    // 書き込みの中で読むときは、持っている writer のロックを渡す
+    fn snapshot_in(&self, _writer: &Writer<'_>) -> S::Snapshot {
+        self.storage.snapshot()
+    }
 
-        let columns = stream_columns(self.schema(), &select.streams[0]);
+    // 読み取り中に書き込まれても、snapshot の時点の内容を読む
+    fn select_stream<'e>(
+        &'e self,
+        snapshot: S::Snapshot,
+        select: &'e Select,
+    ) -> Result<RowStream<'e, S>, String> {
+        // テーブルは snapshot の時点のスキーマから引く
+        let schema = self.storage.schema_at(&snapshot);
+        let columns = stream_columns(schema, &select.streams[0]);
         for stream in select.streams.iter().skip(1) {
-            if stream_columns(self.schema(), stream) != columns {
+            if stream_columns(schema, stream) != columns {
                 return Err(format!("streams have different columns"));
             }
         }
 
-        for stream in select.streams.iter() {
-            let appender: RowAppender<_> = {
-                let rows =
-                    unsafe { std::mem::transmute::<*mut Vec<Data>, &mut Vec<Data>>(&mut rows) };
-                Box::new(move |_, row| {
-                    rows.extend(row);
-                })
-            };
+        Ok(RowStream {
+            engine: self,
+            snapshot,
+            columns,
+            streams: select.streams.iter(),
+            scan: None,
+            rows: VecDeque::new(),
+        })
+    }
 
-            self.scan(stream, appender)?;
+    fn select(
+        &self,
+        snapshot: S::Snapshot,
+        select: &Select,
+    ) -> Result<(Vec<String>, Vec<Data>), String> {
+        let stream = self.select_stream(snapshot, select)?;
+        let columns = stream.columns().to_vec();
+        let mut rows = vec![];
+        for row in stream {
+            rows.extend(row?);
         }
-
         Ok((columns, rows))
     }
 
-    pub fn execute_insert(&mut self, insert: &query::Insert) -> Result<(), String> {
+    pub fn execute_insert(&self, insert: &query::Insert) -> Result<(), String> {
+        let writer = self.lock_writer();
+        self.insert(&writer, insert)
+    }
+
+    pub fn execute_delete(&self, delete: &query::Delete) -> Result<usize, String> {
+        let writer = self.lock_writer();
+        self.delete(&writer, delete)
+    }
+
+    pub fn execute_update(&self, update: &query::Update) -> Result<(), String> {
+        let writer = self.lock_writer();
+        self.update(&writer, update)
+    }
+
+    // 以下は writer のロックを持って呼び、そのガードを受け取る
+
+    fn insert(&self, writer: &Writer<'_>, insert: &query::Insert) -> Result<(), String> {
         match insert {
             query::Insert::Row {
                 table_name,
                 column_names,
                 values,
-            } => self.execute_insert_row(table_name, column_names, values),
+            } => self.execute_insert_row(writer, table_name, column_names, values),
             query::Insert::Select { table_name, select } => {
-                self.execute_insert_from_select(table_name, select)
+                self.execute_insert_from_select(writer, table_name, select)
             }
         }
     }
 
-    pub fn execute_delete(&mut self, delete: &query::Delete) -> Result<usize, String> {
+    fn delete(&self, _writer: &Writer<'_>, delete: &query::Delete) -> Result<usize, String> {
         let source_table = if let SelectSource::Table(table) = &delete.source {
             table
         } else {
@@ -91,18 +159,24 @@ impl<S: Storage> Engine<S> {
             .source_index(&source_table.table_name, &source_table.keys)
             .unwrap();
         let mut cursor = if let Some(from) = &source_table.from {
-            self.storage.get_cursor_just(source, from)
+            self.storage
+                .get_cursor_just(source, &coerce_keys(table, &source_table.keys, from)?)
         } else {
             self.storage.get_cursor_first(source)
         };
-        let end_check_columns = source_table.to.as_ref().map(|to| {
+        let to = source_table
+            .to
+            .as_ref()
+            .map(|to| coerce_keys(table, &source_table.keys, to))
+            .transpose()?;
+        let end_check_columns = to.map(|to| {
             (
                 source_table
                     .keys
                     .iter()
                     .map(|name| table.get_column(name).unwrap().0)
                     .collect::<Vec<_>>(),
-                to.clone(),
+                to,
             )
         });
         let mut count = 0;
@@ -126,7 +200,7 @@ impl<S: Storage> Engine<S> {
         Ok(count)
     }
 
-    pub fn execute_update(&mut self, update: &query::Update) -> Result<(), String> {
+    fn update(&self, _writer: &Writer<'_>, update: &query::Update) -> Result<(), String> {
         let table = if let Some((_, table)) = self.schema().get_table(&update.source.table_name) {
             table.clone()
         } else {
@@ -138,11 +212,18 @@ impl<S: Storage> Engine<S> {
             .source_index(&table.name, &update.source.keys)
             .unwrap();
         let mut cursor = if let Some(from) = &update.source.from {
-            self.storage.get_cursor_just(source, from)
+            self.storage
+                .get_cursor_just(source, &coerce_keys(&table, &update.source.keys, from)?)
         } else {
             self.storage.get_cursor_first(source)
         };
-        let end_check_columns = update.source.to.as_ref().map(|to| {
+        let to = update
+            .source
+            .to
+            .as_ref()
+            .map(|to| coerce_keys(&table, &update.source.keys, to))
+            .transpose()?;
+        let end_check_columns = to.map(|to| {
             (
                 update
                     .source
@@ -150,7 +231,7 @@ impl<S: Storage> Engine<S> {
                     .iter()
                     .map(|name| table.get_column(name).unwrap().0)
                     .collect::<Vec<_>>(),
-                to.clone(),
+                to,
             )
         });
 
@@ -199,62 +280,92 @@ impl<S: Storage> Engine<S> {
     }
 
     fn execute_insert_row(
-        &mut self,
-        table_name: &String,
+        &self,
+        writer: &Writer<'_>,
+        table_name: &str,
         column_names: &Vec<String>,
         values: &[Data],
     ) -> Result<(), String> {
+        let row = self.build_insert_row(writer, table_name, column_names, values)?;
+        self.storage.add_row(table_name, row)
+    }
+
+    // 指定されていない列は default で埋め、default がなければ null にする
+    fn build_insert_row(
+        &self,
+        writer: &Writer<'_>,
+        table_name: &str,
+        column_names: &[String],
+        values: &[Data],
+    ) -> Result<Vec<Data>, String> {
         let (_, table) = self
             .schema()
             .get_table(table_name)
-            .expect("table not found");
-        let columns = table.columns.clone();
-        let values = columns
+            .ok_or_else(|| "missing table".to_owned())?;
+        let table = table.clone();
+        let row = table
+            .columns
             .iter()
             .map(|column| {
                 if let Some(i) = column_names.iter().position(|n| &column.name == n) {
                     if let Some(crate::schema::Default::AutoIncrement) = &column.default {
-                        self.update_auto_inc(&table_name, &column_names[i], &values[i]);
+                        self.update_auto_inc(writer, table_name, &column_names[i], &values[i]);
                     }
-                    values[i].clone()
+                    values[i]
+                        .clone()
+                        .coerce(column.dtype)
+                        .map_err(|e| format!("{}.{}: {}", table.name, column.name, e))
                 } else {
-                    match column.default.as_ref().expect("no default") {
-                        crate::schema::Default::Data(d) => d.clone(),
-                        crate::schema::Default::AutoIncrement => {
-                            self.auto_inc(table_name, &column.name)
+                    match &column.default {
+                        Some(crate::schema::Default::Data(d)) => Ok(d.clone()),
+                        Some(crate::schema::Default::AutoIncrement) => {
+                            Ok(self.auto_inc(writer, table_name, &column.name))
                         }
+                        None if column.nullable => Ok(Data::Null),
+                        None => Err(format!("{}.{} has no default", table.name, column.name)),
                     }
                 }
             })
-            .collect();
-        self.storage.add_row(&table_name, values)
+            .collect::<Result<_, _>>()?;
+        table.check_row_is_legal(&row)?;
+        Ok(row)
     }
 
+    // 行をまとめて storage に渡すので、空のテーブルなら木を下から作れる
     fn execute_insert_from_select(
-        &mut self,
-        table_name: &String,
+        &self,
+        writer: &Writer<'_>,
+        table_name: &str,
         select: &Select,
     ) -> Result<(), String> {
-        let (columns, rows) = self.execute_select(select)?;
-        for row in rows.chunks(columns.len()) {
-            self.execute_insert_row(table_name, &columns, row)?;
-        }
-        Ok(())
+        let (columns, rows) = self.select(self.snapshot_in(writer), select)?;
+        let rows = rows
+            .chunks(columns.len())
+            .map(|row| self.build_insert_row(writer, table_name, &columns, row))
+            .collect::<Result<_, _>>()?;
+        self.storage.add_rows(table_name, rows)
     }
 
-    fn scan(&self, stream: &Stream, appender: RowAppender<S>) -> Result<(), String> {
+    fn open_scan<'e>(
+        &'e self,
+        snapshot: &S::Snapshot,
+        stream: &'e Stream,
+    ) -> Result<Scan<'e, S>, String> {
+        // 結果の行は QueryContext の rows に積まれる
+        let appender: RowAppender<'e, S> = Box::new(|ctx, row| ctx.rows.push_back(row));
+        let schema = self.storage.schema_at(snapshot);
         match &stream.source {
             SelectSource::Table(source_table) => {
-                let table =
-                    if let Some((_, table)) = self.schema().get_table(&source_table.table_name) {
-                        table
-                    } else {
-                        return Err(format!("missing table"));
-                    };
+                let table = if let Some((_, table)) = schema.get_table(&source_table.table_name) {
+                    table
+                } else {
+                    return Err(format!("missing table"));
+                };
                 let columns = table.columns.iter().map(|c| c.name.to_owned()).collect();
-                let mut appender = build_excecutable_query_process(
-                    self.schema(),
+                let appender = build_excecutable_query_process(
+                    schema,
                     &self.storage,
+                    snapshot,
                     columns,
                     &stream.process,
                     appender,
@@ -262,70 +373,68 @@ impl<S: Storage> Engine<S> {
 
                 let source = self
                     .storage
-                    .source_index(&table.name, &source_table.keys)
+                    .source_index_at(snapshot, &table.name, &source_table.keys)
                     .unwrap();
                 let mut cursor = if let Some(from) = &source_table.from {
-                    self.storage.get_cursor_just(source, from)
+                    self.storage.get_cursor_just_at(
+                        snapshot,
+                        source,
+                        &coerce_keys(table, &source_table.keys, from)?,
+                    )
                 } else {
-                    self.storage.get_cursor_first(source)
+                    self.storage.get_cursor_first_at(snapshot, source)
                 };
                 self.storage.cursor_next_occupied(&mut cursor); // get_cursor_justでページの最後を示すカーソルが返ってくる可能性がある
-                let end_check_columns = source_table.to.as_ref().map(|to| {
+                let to = source_table
+                    .to
+                    .as_ref()
+                    .map(|to| coerce_keys(table, &source_table.keys, to))
+                    .transpose()?;
+                let end_check_columns = to.map(|to| {
                     (
                         source_table
                             .keys
                             .iter()
                             .map(|name| table.get_column(name).unwrap().0)
                             .collect::<Vec<_>>(),
-                        to.clone(),
+                        to,
                     )
                 });
-                let mut ctx = QueryContext {
-                    storage: &self.storage,
+                Ok(Scan {
+                    source: ScanSource::Table {
+                        cursor,
+                        end_check_columns,
+                    },
+                    appender,
                     ended: false,
-                };
-                while !ctx.ended && !self.storage.cursor_is_end(&cursor) {
-                    if let Some(row) = self.storage.cursor_get_row(&cursor) {
-                        if let Some((cs, to)) = &end_check_columns {
-                            let now = cs.iter().map(|i| row[*i].clone()).collect::<Vec<_>>();
-                            if to < &now {
-                                break;
-                            }
-                        }
-                        appender(&mut ctx, row);
-                        self.storage.cursor_advance(&mut cursor);
-                    } else {
-                        break;
-                    }
-                }
+                })
             }
             SelectSource::Iota {
                 column_name,
                 from,
                 to,
             } => {
-                let mut appender = build_excecutable_query_process(
-                    self.schema(),
+                let appender = build_excecutable_query_process(
+                    schema,
                     &self.storage,
+                    snapshot,
                     vec![column_name.clone()],
                     &stream.process,
                     appender,
                 );
-
-                let mut ctx = QueryContext {
-                    storage: &self.storage,
+                Ok(Scan {
+                    source: ScanSource::Iota {
+                        next: *from,
+                        to: *to,
+                    },
+                    appender,
                     ended: false,
-                };
-                for i in *from..*to {
-                    appender(&mut ctx, vec![Data::U64(i)]);
-                }
+                })
             }
         }
-
-        Ok(())
     }
 
-    fn auto_inc(&mut self, table_name: &str, column_name: &str) -> Data {
+    fn auto_inc(&self, writer: &Writer<'_>, table_name: &str, column_name: &str) -> Data {
         let select_source_table = SelectSourceTable {
             table_name: "auto_increment".to_owned(),
             keys: vec!["table".to_owned(), "column".to_owned()],
@@ -339,46 +448,64 @@ impl<S: Storage> Engine<S> {
             ]),
         };
         let (_, datas) = self
-            .execute_select(&Select {
-                sub_queries: vec![],
-                streams: vec![Stream {
-                    source: SelectSource::Table(select_source_table.clone()),
-                    process: vec![ProcessItem::Select {
-                        columns: vec![("num".to_owned(), query::Expr::Column("num".to_owned()))],
+            .select(
+                self.snapshot_in(writer),
+                &Select {
+                    sub_queries: vec![],
+                    streams: vec![Stream {
+                        source: SelectSource::Table(select_source_table.clone()),
+                        process: vec![ProcessItem::Select {
+                            columns: vec![(
+                                "num".to_owned(),
+                                query::Expr::Column("num".to_owned()),
+                            )],
+                        }],
                     }],
-                }],
-                post_process: vec![],
-            })
+                    post_process: vec![],
+                },
+            )
             .unwrap();
         if datas.is_empty() {
-            self.execute_insert(&query::Insert::Row {
-                table_name: "auto_increment".to_owned(),
-                column_names: vec!["table".to_owned(), "column".to_owned(), "num".to_owned()],
-                values: vec![
-                    Data::String(table_name.to_owned()),
-                    Data::String(column_name.to_owned()),
-                    Data::U64(2),
-                ],
-            })
+            self.insert(
+                writer,
+                &query::Insert::Row {
+                    table_name: "auto_increment".to_owned(),
+                    column_names: vec!["table".to_owned(), "column".to_owned(), "num".to_owned()],
+                    values: vec![
+                        Data::String(table_name.to_owned()),
+                        Data::String(column_name.to_owned()),
+                        Data::U64(2),
+                    ],
+                },
+            )
             .unwrap();
             Data::U64(1)
         } else {
             let data = datas[0].clone();
-            self.execute_update(&query::Update {
-                source: select_source_table,
-                filter_items: vec![],
-                column_names: vec!["num".to_owned()],
-                exprs: vec![query::Expr::Data(match &data {
-                    Data::U64(v) => Data::U64(v + 1),
-                    _ => panic!(),
-                })],
-            })
+            self.update(
+                writer,
+                &query::Update {
+                    source: select_source_table,
+                    filter_items: vec![],
+                    column_names: vec!["num".to_owned()],
+                    exprs: vec![query::Expr::Data(match &data {
+                        Data::U64(v) => Data::U64(v + 1),
+                        _ => panic!(),
+                    })],
+                },
+            )
             .unwrap();
             data
         }
     }
 
-    fn update_auto_inc(&mut self, table_name: &str, column_name: &str, data: &Data) {
+    fn update_auto_inc(
+        &self,
+        writer: &Writer<'_>,
+        table_name: &str,
+        column_name: &str,
+        data: &Data,
+    ) {
         let select_source_table = SelectSourceTable {
             table_name: "auto_increment".to_owned(),
             keys: vec!["table".to_owned(), "column".to_owned()],
@@ -393,16 +520,22 @@ impl<S: Storage> Engine<S> {
         };
 
         let (_, datas) = self
-            .execute_select(&Select {
-                sub_queries: vec![],
-                streams: vec![Stream {
-                    source: SelectSource::Table(select_source_table.clone()),
-                    process: vec![ProcessItem::Select {
-                        columns: vec![("num".to_owned(), query::Expr::Column("num".to_owned()))],
+            .select(
+                self.snapshot_in(writer),
+                &Select {
+                    sub_queries: vec![],
+                    streams: vec![Stream {
+                        source: SelectSource::Table(select_source_table.clone()),
+                        process: vec![ProcessItem::Select {
+                            columns: vec![(
+                                "num".to_owned(),
+                                query::Expr::Column("num".to_owned()),
+                            )],
+                        }],
                     }],
-                }],
-                post_process: vec![],
-            })
+                    post_process: vec![],
+                },
+            )
             .unwrap();
 
         let insert_num = match &data {
@@ -411,15 +544,18 @@ impl<S: Storage> Engine<S> {
         };
 
         if datas.is_empty() {
-            self.execute_insert(&query::Insert::Row {
-                table_name: "auto_increment".to_owned(),
-                column_names: vec!["table".to_owned(), "column".to_owned(), "num".to_owned()],
-                values: vec![
-                    Data::String(table_name.to_owned()),
-                    Data::String(column_name.to_owned()),
-                    Data::U64(insert_num + 1),
-                ],
-            })
+            self.insert(
+                writer,
+                &query::Insert::Row {
+                    table_name: "auto_increment".to_owned(),
+                    column_names: vec!["table".to_owned(), "column".to_owned(), "num".to_owned()],
+                    values: vec![
+                        Data::String(table_name.to_owned()),
+                        Data::String(column_name.to_owned()),
+                        Data::U64(insert_num + 1),
+                    ],
+                },
+            )
             .unwrap();
         } else {
             let ai_num = match &datas[0] {
@@ -428,12 +564,15 @@ impl<S: Storage> Engine<S> {
             };
 
             if ai_num < insert_num {
-                self.execute_update(&query::Update {
-                    source: select_source_table,
-                    filter_items: vec![],
-                    column_names: vec!["num".to_owned()],
-                    exprs: vec![query::Expr::Data(Data::U64(insert_num + 1))],
-                })
+                self.update(
+                    writer,
+                    &query::Update {
+                        source: select_source_table,
+                        filter_items: vec![],
+                        column_names: vec!["num".to_owned()],
+                        exprs: vec![query::Expr::Data(Data::U64(insert_num + 1))],
+                    },
+                )
                 .unwrap();
             }
         }
@@ -442,16 +581,197 @@ impl<S: Storage> Engine<S> {
 
 impl<S: Storage> Engine<S> {
     pub fn flush(&self) {
+        let _writer = self.lock_writer();
         self.storage.flush();
     }
+
+    // 他のクエリと同時には実行できない
+    pub fn vacuum(&mut self) -> Result<(), String> {
+        self.storage.vacuum()
+    }
+
+    // 呼んだ時点の内容を path に書き出す
+    // 書き出している間も他のクエリは実行できる
+    pub fn backup_to(&self, path: &str) -> Result<(), String> {
+        let snapshot = self.snapshot();
+        self.storage.backup_to(&snapshot, path)
+    }
+
+    pub fn begin(&mut self) -> Transaction<'_, S> {
+        self.storage.begin();
+        Transaction {
+            engine: self,
+            finished: false,
+        }
+    }
+}
+
+// Engine::begin で始まるトランザクション
+// Engine として使え、commit せずに drop すると rollback する
+pub struct Transaction<'a, S: Storage> {
+    engine: &'a mut Engine<S>,
+    finished: bool,
+}
+
+impl<'a, S: Storage> Transaction<'a, S> {
+    pub fn commit(mut self) {
+        self.engine.storage.commit();
+        self.finished = true;
+    }
+
+    pub fn rollback(mut self) {
+        self.engine.storage.rollback();
+        self.finished = true;
+    }
+}
+
+impl<'a, S: Storage> std::ops::Deref for Transaction<'a, S> {
+    type Target = Engine<S>;
+
+    fn deref(&self) -> &Self::Target {
+        self.engine
+    }
+}
+
+impl<'a, S: Storage> std::ops::DerefMut for Transaction<'a, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.engine
+    }
+}
+
+impl<'a, S: Storage> Drop for Transaction<'a, S> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.engine.storage.rollback();
+        }
+    }
 }
 
 pub struct QueryContext<'a, S: Storage> {
     storage: &'a S,
+    snapshot: &'a S::Snapshot,
     ended: bool,
+    rows: &'a mut VecDeque<Row>,
 }
 
-type RowAppender<S> = Box<dyn for<'a> FnMut(&mut QueryContext<'a, S>, Vec<Data>)>;
+// Engine の writer のロックのガード
+type Writer<'a> = MutexGuard<'a, ()>;
+
+type RowAppender<'r, S> = Box<dyn for<'a> FnMut(&mut QueryContext<'a, S>, Vec<Data>) + 'r>;
+
+pub type Row = Vec<Data>;
+
+// select の結果を返すイテレータ
+// snapshot を持っているので、途中で書き込まれても開始時点の内容を返す
+pub struct RowStream<'e, S: Storage> {
+    engine: &'e Engine<S>,
+    snapshot: S::Snapshot,
+    columns: Vec<String>,
+    streams: std::slice::Iter<'e, Stream>,
+    scan: Option<Scan<'e, S>>,
+    // appender が作ったがまだ返していない行
+    rows: VecDeque<Row>,
+}
+
+impl<'e, S: Storage> RowStream<'e, S> {
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+impl<'e, S: Storage> Iterator for RowStream<'e, S> {
+    type Item = Result<Row, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.pop_front() {
+                return Some(Ok(row));
+            }
+            let scan = match &mut self.scan {
+                Some(scan) => scan,
+                None => {
+                    let stream = self.streams.next()?;
+                    match self.engine.open_scan(&self.snapshot, stream) {
+                        Ok(scan) => self.scan.insert(scan),
+                        Err(e) => {
+                            self.streams = [].iter();
+                            return Some(Err(e));
+                        }
+                    }
+                }
+            };
+            if !scan.step(&self.engine.storage, &self.snapshot, &mut self.rows) {
+                self.scan = None;
+            }
+        }
+    }
+}
+
+// 一つの stream を読む途中の状態
+struct Scan<'e, S: Storage> {
+    source: ScanSource<S>,
+    appender: RowAppender<'e, S>,
+    ended: bool,
+}
+
+enum ScanSource<S: Storage> {
+    Table {
+        cursor: S::Cursor,
+        end_check_columns: Option<(Vec<usize>, Vec<Data>)>,
+    },
+    Iota {
+        next: u64,
+        to: u64,
+    },
+}
+
+impl<'e, S: Storage> Scan<'e, S> {
+    // 元の行を一つ appender に流す。読み終わっていたら false
+    fn step(&mut self, storage: &S, snapshot: &S::Snapshot, rows: &mut VecDeque<Row>) -> bool {
+        if self.ended {
+            return false;
+        }
+        let row = match &mut self.source {
+            ScanSource::Table {
+                cursor,
+                end_check_columns,
+            } => {
+                if storage.cursor_is_end(cursor) {
+                    return false;
+                }
+                let row = if let Some(row) = storage.cursor_get_row(cursor) {
+                    row
+                } else {
+                    return false;
+                };
+                if let Some((cs, to)) = end_check_columns {
+                    let now = cs.iter().map(|i| row[*i].clone()).collect::<Vec<_>>();
+                    if *to < now {
+                        return false;
+                    }
+                }
+                storage.cursor_advance(cursor);
+                row
+            }
+            ScanSource::Iota { next, to } => {
+                if next >= to {
+                    return false;
+                }
+                *next += 1;
+                vec![Data::U64(*next - 1)]
+            }
+        };
+        let mut ctx = QueryContext {
+            storage,
+            snapshot,
+            ended: false,
+            rows,
+        };
+        (self.appender)(&mut ctx, row);
+        self.ended = ctx.ended;
+        true
+    }
+}
 
 fn stream_columns(schema: &Schema, stream: &Stream) -> Vec<String> {
     let mut columns = match &stream.source {
@@ -473,13 +793,14 @@ fn stream_columns(schema: &Schema, stream: &Stream) -> Vec<String> {
     columns
 }
 
-fn build_excecutable_query_process<S: Storage>(
+fn build_excecutable_query_process<'r, S: Storage>(
     schema: &Schema,
     storage: &S,
+    snapshot: &S::Snapshot,
     columns: Vec<String>,
     process: &[ProcessItem],
-    mut appender: RowAppender<S>,
-) -> RowAppender<S> {
+    mut appender: RowAppender<'r, S>,
+) -> RowAppender<'r, S> {
     let mut columns_vec = vec![columns];
     for p in process {
         let columns = columns_vec.last().unwrap();
@@ -490,7 +811,15 @@ fn build_excecutable_query_process<S: Storage>(
     for (p, pre_post_columns) in process.iter().zip(columns_vec.windows(2)).rev() {
         let pre_columns = &pre_post_columns[0];
         let post_columns = &pre_post_columns[1];
-        appender = process_item_appender(p, appender, pre_columns, post_columns, schema, storage);
+        appender = process_item_appender(
+            p,
+            appender,
+            pre_columns,
+            post_columns,
+            schema,
+            storage,
+            snapshot,
+        );
     }
 
     appender
@@ -528,14 +857,15 @@ fn select_process_item_column(
     }
 }
 
-fn process_item_appender<S: Storage>(
+fn process_item_appender<'r, S: Storage>(
     p: &ProcessItem,
-    mut appender: Box<dyn FnMut(&mut QueryContext<S>, Vec<Data>)>,
+    mut appender: Box<dyn FnMut(&mut QueryContext<S>, Vec<Data>) + 'r>,
     pre_columns: &Vec<String>,
     post_columns: &Vec<String>,
     schema: &Schema,
     storage: &S,
-) -> Box<dyn FnMut(&mut QueryContext<S>, Vec<Data>)> {
+    snapshot: &S::Snapshot,
+) -> Box<dyn FnMut(&mut QueryContext<S>, Vec<Data>) + 'r> {
     let convert_expr = |expr: &query::Expr| -> Expr {
         match expr {
             query::Expr::Column(name) => {
@@ -583,10 +913,11 @@ fn process_item_appender<S: Storage>(
                 .map(|key| table.columns.iter().position(|c| &c.name == key).unwrap())
                 .collect();
             let source_index = storage
-                .source_index(table_name, &right_keys)
+                .source_index_at(snapshot, table_name, &right_keys)
                 .expect(&format!("{:?} are not found in {}", right_keys, table_name)); // TODO!
             Box::new(move |ctx, row| {
-                let mut cursor = ctx.storage.get_cursor_just(
+                let mut cursor = ctx.storage.get_cursor_just_at(
+                    ctx.snapshot,
                     source_index,
                     &left_is.iter().map(|i| row[*i].clone()).collect::<Vec<_>>(),
                 );
@@ -695,12 +1026,12 @@ fn convert_filter_item(
 impl Item {
     fn eval(&mut self, row: &[Data]) -> bool {
         match self {
-            Item::Eq(left, right) => left.eval(&row) == right.eval(&row),
-            Item::Ne(left, right) => left.eval(&row) != right.eval(&row),
-            Item::Lt(left, right) => left.eval(&row) < right.eval(&row),
-            Item::Le(left, right) => left.eval(&row) <= right.eval(&row),
-            Item::Gt(left, right) => left.eval(&row) > right.eval(&row),
-            Item::Ge(left, right) => left.eval(&row) >= right.eval(&row),
+            Item::Eq(left, right) => left.eval(row) == right.eval(row),
+            Item::Ne(left, right) => left.eval(row) != right.eval(row),
+            Item::Lt(left, right) => left.eval(row) < right.eval(row),
+            Item::Le(left, right) => left.eval(row) <= right.eval(row),
+            Item::Gt(left, right) => left.eval(row) > right.eval(row),
+            Item::Ge(left, right) => left.eval(row) >= right.eval(row),
             Item::And(left, right) => left.eval(row) && right.eval(row),
             Item::Or(left, right) => left.eval(row) || right.eval(row),
         }
@@ -713,6 +1044,24 @@ enum Expr {
     Enumerate(Data),
 }
 
+// from, to のリテラルをキーの列の型に合わせる (u64 で書いた値で i64 の列を引くなど)
+fn coerce_keys(
+    table: &schema::Table,
+    keys: &[String],
+    datas: &[Data],
+) -> Result<Vec<Data>, String> {
+    datas
+        .iter()
+        .zip(keys)
+        .map(|(data, name)| {
+            data.clone()
+                .coerce(table.get_column(name).unwrap().1.dtype)
+                .map_err(|e| format!("{}.{}: {}", table.name, name, e))
+        })
+        .chain(datas.iter().skip(keys.len()).cloned().map(Ok))
+        .collect()
+}
+
 impl Expr {
     fn eval(&mut self, row: &[Data]) -> Data {
         match self {
@@ -729,9 +1078,841 @@ impl Expr {
                         }
                     }
                     Data::Lancer(size) => *size += 1,
+                    Data::I64(v) => *v += 1,
+                    Data::F64(v) => *v += 1.0,
+                    Data::Bool(_) => panic!(),
+                    Data::Null => {}
+                    Data::Bytes(_) => panic!(),
+                    Data::Date(v) => *v += 1,
+                    Data::Time(_) | Data::Timestamp(_) => panic!(),
                 }
                 ret
             }
         }
     }
 }
+
+#[cfg(test)]
+use crate::test_util::{user_table, TestDir};
+
+#[cfg(test)]
+fn insert_user(i: u64) -> query::Insert {
+    query::Insert::Row {
+        table_name: "user".to_owned(),
+        column_names: vec!["id".to_owned(), "name".to_owned()],
+        values: vec![Data::U64(i), Data::String(format!("user{}", i))],
+    }
+}
+
+// table を key の順に from から to まで読む
+#[cfg(test)]
+fn select_range(table_name: &str, key: &str, from: Option<Data>, to: Option<Data>) -> Select {
+    Select {
+        sub_queries: vec![],
+        streams: vec![Stream {
+            source: SelectSource::Table(SelectSourceTable {
+                table_name: table_name.to_owned(),
+                keys: vec![key.to_owned()],
+                from: from.map(|d| vec![d]),
+                to: to.map(|d| vec![d]),
+            }),
+            process: vec![],
+        }],
+        post_process: vec![],
+    }
+}
+
+#[cfg(test)]
+fn select_all(table_name: &str, key: &str) -> Select {
+    select_range(table_name, key, None, None)
+}
+
+#[test]
+fn test_transaction() {
+    use crate::{query::Delete, storage::file::File};
+
+    let dir = TestDir::new("test_transaction");
+    let filepath = &dir.path("test_transaction.rdb");
+    let select_all = select_all("user", "id");
+    let count = |engine: &Engine<File>| {
+        let (cs, vs) = engine.execute_select(&select_all).unwrap();
+        vs.len() / cs.len()
+    };
+
+    {
+        let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+        engine.storage.set_cache_capacity(4);
+        engine.create_table(user_table());
+
+        let tx = engine.begin();
+        for i in 0..100 {
+            tx.execute_insert(&insert_user(i)).unwrap();
+        }
+        tx.commit();
+        assert_eq!(count(&engine), 100);
+
+        // rollback すると begin の時点に戻る
+        let tx = engine.begin();
+        for i in 100..200 {
+            tx.execute_insert(&insert_user(i)).unwrap();
+        }
+        tx.execute_delete(&Delete {
+            source: select_all.streams[0].source.clone(),
+            filter: vec![],
+        })
+        .unwrap();
+        tx.flush();
+        assert_eq!(count(&tx), 0);
+        tx.rollback();
+        assert_eq!(count(&engine), 100);
+        assert_eq!(engine.storage.check_integrity(), Ok(()));
+
+        // commit せずに drop すると rollback する
+        {
+            let tx = engine.begin();
+            tx.execute_insert(&insert_user(100)).unwrap();
+        }
+        assert_eq!(count(&engine), 100);
+
+        // commit していない変更はファイルに書かれない
+        let tx = engine.begin();
+        for i in 100..200 {
+            tx.execute_insert(&insert_user(i)).unwrap();
+        }
+        std::mem::forget(tx);
+    }
+    let engine = Engine::from_storage(File::open(filepath).unwrap());
+    assert_eq!(count(&engine), 100);
+    assert_eq!(engine.storage.check_integrity(), Ok(()));
+}
+
+#[test]
+fn test_concurrent() {
+    use crate::{front::yaml::schema::parse_table_from_yaml, query::Insert, storage::file::File};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Engine<File>>();
+
+    let dir = TestDir::new("test_concurrent");
+    let filepath = &dir.path("test_concurrent.rdb");
+    let table = parse_table_from_yaml(
+        r"
+name: item
+columns:
+-   name: id
+    type: u64
+-   name: version
+    type: u64
+-   name: name
+    type: string
+primary_key: [id]
+indices:
+-   name: version
+    columns: [version]
+",
+    )
+    .unwrap();
+    const ROWS: u64 = 200;
+    const VERSIONS: u64 = 20;
+
+    let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+    // 追い出しも並行して起きるように小さくする
+    engine.storage.set_cache_capacity(16);
+    engine.create_table(table);
+    for i in 0..ROWS {
+        engine
+            .execute_insert(&Insert::Row {
+                table_name: "item".to_owned(),
+                column_names: vec!["id".to_owned(), "version".to_owned(), "name".to_owned()],
+                values: vec![
+                    Data::U64(i),
+                    Data::U64(0),
+                    Data::String(format!("item{}", i).repeat(20)),
+                ],
+            })
+            .unwrap();
+    }
+    engine.flush();
+
+    let done = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for t in 0..4 {
+            let engine = &engine;
+            let done = &done;
+            let key = if t % 2 == 0 { "id" } else { "version" };
+            scope.spawn(move || {
+                let mut last_version = 0;
+                let mut selects = 0;
+                while !done.load(std::sync::atomic::Ordering::SeqCst) || selects == 0 {
+                    let (columns, rows) = engine.execute_select(&select_all("item", key)).unwrap();
+                    let rows: Vec<_> = rows.chunks(columns.len()).collect();
+                    // 書き込みの途中の状態は見えない
+                    assert_eq!(rows.len() as u64, ROWS);
+                    let version = rows[0][1].clone();
+                    assert!(rows.iter().all(|row| row[1] == version), "{:?}", rows);
+                    let version = if let Data::U64(v) = version {
+                        v
+                    } else {
+                        panic!()
+                    };
+                    assert!(last_version <= version);
+                    last_version = version;
+                    selects += 1;
+                }
+            });
+        }
+
+        for v in 1..=VERSIONS {
+            engine
+                .execute_update(&query::Update {
+                    source: SelectSourceTable {
+                        table_name: "item".to_owned(),
+                        keys: vec!["id".to_owned()],
+                        from: None,
+                        to: None,
+                    },
+                    filter_items: vec![],
+                    column_names: vec!["version".to_owned()],
+                    exprs: vec![query::Expr::Data(Data::U64(v))],
+                })
+                .unwrap();
+            engine.flush();
+        }
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+    });
+
+    let (columns, rows) = engine
+        .execute_select(&select_all("item", "version"))
+        .unwrap();
+    assert_eq!(rows.len() / columns.len(), ROWS as usize);
+    assert!(rows
+        .chunks(columns.len())
+        .all(|row| row[1] == Data::U64(VERSIONS)));
+    assert_eq!(engine.storage.check_integrity(), Ok(()));
+}
+
+#[test]
+fn test_select_stream() {
+    use crate::storage::file::File;
+
+    let dir = TestDir::new("test_select_stream");
+    let filepath = &dir.path("test_select_stream.rdb");
+    let select_all = select_all("user", "id");
+
+    let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+    engine.storage.set_cache_capacity(4);
+    engine.create_table(user_table());
+    for i in 0..500 {
+        engine.execute_insert(&insert_user(i)).unwrap();
+    }
+
+    // 途中でやめられる
+    let mut stream = engine.execute_select_stream(&select_all).unwrap();
+    assert_eq!(stream.columns(), ["id".to_owned(), "name".to_owned()]);
+    for i in 0..3 {
+        assert_eq!(
+            stream.next().unwrap().unwrap(),
+            vec![Data::U64(i), Data::String(format!("user{}", i))]
+        );
+    }
+    drop(stream);
+
+    // 読んでいる途中の書き込みは見えない
+    let mut stream = engine.execute_select_stream(&select_all).unwrap();
+    assert_eq!(stream.next().unwrap().unwrap()[0], Data::U64(0));
+    for i in 500..600 {
+        engine.execute_insert(&insert_user(i)).unwrap();
+    }
+    let rest: Vec<_> = stream.map(|row| row.unwrap()[0].clone()).collect();
+    assert_eq!(rest, (1..500).map(Data::U64).collect::<Vec<_>>());
+    assert_eq!(engine.execute_select(&select_all).unwrap().1.len(), 600 * 2);
+
+    // 複数の stream は順につながり、limit で打ち切られる
+    let iota = |from, to| Stream {
+        source: SelectSource::Iota {
+            column_name: "i".to_owned(),
+            from,
+            to,
+        },
+        process: vec![ProcessItem::Limit { num: 2 }],
+    };
+    let select = Select {
+        sub_queries: vec![],
+        streams: vec![iota(0, 10), iota(100, 101), iota(200, 210)],
+        post_process: vec![],
+    };
+    let rows: Vec<_> = engine
+        .execute_select_stream(&select)
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+    assert_eq!(
+        rows,
+        [0, 1, 100, 200, 201]
+            .iter()
+            .map(|i| vec![Data::U64(*i)])
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_backup() {
+    use crate::{
+        query::{Delete, Insert},
+        storage::file::File,
+    };
+
+    let dir = TestDir::new("test_backup");
+    let filepath = &dir.path("test_backup.rdb");
+    let backup_path = &dir.path("test_backup_copy.rdb");
+    let insert = |i: u64| Insert::Row {
+        table_name: "user".to_owned(),
+        column_names: vec!["id".to_owned(), "name".to_owned()],
+        values: vec![Data::U64(i), Data::String(format!("user{}", i).repeat(20))],
+    };
+    let select_all = select_all("user", "id");
+
+    let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+    engine.storage.set_cache_capacity(16);
+    engine.create_table(user_table());
+    for i in 0..200 {
+        engine.execute_insert(&insert(i)).unwrap();
+    }
+    // 空きページがある状態にする
+    engine
+        .execute_delete(&Delete {
+            source: select_all.streams[0].source.clone(),
+            filter: vec![],
+        })
+        .unwrap();
+    for i in 0..100 {
+        engine.execute_insert(&insert(i)).unwrap();
+    }
+
+    let started = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let engine = &engine;
+        let started = &started;
+        scope.spawn(move || {
+            for i in 100..400 {
+                engine.execute_insert(&insert(i)).unwrap();
+                if i % 50 == 0 {
+                    engine.flush();
+                }
+                started.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+        while !started.load(std::sync::atomic::Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+        // 書き込みを止めずにバックアップする
+        engine.backup_to(backup_path).unwrap();
+    });
+    engine.flush();
+    assert_eq!(engine.execute_select(&select_all).unwrap().1.len(), 400 * 2);
+    assert!(!std::path::Path::new(&format!("{}-tmp", backup_path)).exists());
+
+    let backup = File::open(backup_path).unwrap();
+    assert_eq!(backup.check_integrity(), Ok(()));
+    let backup = Engine::from_storage(backup);
+    // 書き込みの途中の状態は含まれない
+    let (columns, rows) = backup.execute_select(&select_all).unwrap();
+    let ids: Vec<_> = rows
+        .chunks(columns.len())
+        .map(|row| row[0].clone())
+        .collect();
+    assert!(100 <= ids.len() && ids.len() <= 400, "{}", ids.len());
+    assert_eq!(
+        ids,
+        (0..ids.len() as u64).map(Data::U64).collect::<Vec<_>>()
+    );
+    // 空きページの連結リストも snapshot の時点のものになっている
+    for i in 1000..1300 {
+        backup.execute_insert(&insert(i)).unwrap();
+    }
+    backup.flush();
+    assert_eq!(backup.storage.check_integrity(), Ok(()));
+    let (_, rows) = backup.execute_select(&select_all).unwrap();
+    assert_eq!(rows.len(), (ids.len() + 300) * 2);
+}
+
+#[test]
+fn test_signed_and_float() {
+    use crate::{front::yaml::schema::parse_table_from_yaml, query::Insert, storage::file::File};
+
+    let dir = TestDir::new("test_signed_and_float");
+    let filepath = &dir.path("test_signed_and_float.rdb");
+    let table = parse_table_from_yaml(
+        r"
+name: reading
+columns:
+-   name: id
+    type: i64
+-   name: value
+    type: f64
+-   name: offset
+    type: i64
+    default: '-1'
+primary_key: [id]
+indices:
+-   name: value
+    columns: [value]
+",
+    )
+    .unwrap();
+    let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+    engine.create_table(table);
+    // u64 で書いたリテラルも列の型に合わせて入る
+    for (id, value) in [
+        (Data::I64(-3), Data::F64(f64::NAN)),
+        (Data::U64(2), Data::F64(-0.5)),
+        (Data::I64(-10), Data::F64(2.0)),
+        (Data::U64(0), Data::U64(1)),
+        (Data::I64(7), Data::F64(f64::NEG_INFINITY)),
+    ] {
+        engine
+            .execute_insert(&Insert::Row {
+                table_name: "reading".to_owned(),
+                column_names: vec!["id".to_owned(), "value".to_owned()],
+                values: vec![id, value],
+            })
+            .unwrap();
+    }
+    let select = |key: &str, from: Option<Data>, to: Option<Data>| {
+        let (_, rows) = engine
+            .execute_select(&select_range("reading", key, from, to))
+            .unwrap();
+        rows
+    };
+
+    let rows = select("id", None, None);
+    let ids: Vec<_> = rows.chunks(3).map(|r| r[0].clone()).collect();
+    assert_eq!(
+        ids,
+        vec![
+            Data::I64(-10),
+            Data::I64(-3),
+            Data::I64(0),
+            Data::I64(2),
+            Data::I64(7)
+        ]
+    );
+    assert!(rows.chunks(3).all(|r| matches!(r[0], Data::I64(_))
+        && matches!(r[1], Data::F64(_))
+        && r[2] == Data::I64(-1)));
+
+    let values: Vec<_> = select("value", None, None)
+        .chunks(3)
+        .map(|r| r[0].clone())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Data::I64(7),
+            Data::I64(2),
+            Data::I64(0),
+            Data::I64(-10),
+            Data::I64(-3)
+        ]
+    );
+
+    let rows = select("id", Some(Data::I64(-5)), Some(Data::U64(2)));
+    assert_eq!(rows.len() / 3, 3);
+    let rows = select("value", Some(Data::U64(0)), Some(Data::U64(5)));
+    assert_eq!(
+        rows.chunks(3).map(|r| r[0].clone()).collect::<Vec<_>>(),
+        vec![Data::I64(0), Data::I64(-10)]
+    );
+
+    // 列の型に変換できない値は入れられず、キーの範囲にも使えない
+    let err = engine
+        .execute_insert(&Insert::Row {
+            table_name: "reading".to_owned(),
+            column_names: vec!["id".to_owned(), "value".to_owned()],
+            values: vec![Data::F64(1.5), Data::F64(0.0)],
+        })
+        .unwrap_err();
+    assert!(err.contains("reading.id"), "{}", err);
+    let err = engine
+        .execute_select(&select_range(
+            "reading",
+            "id",
+            Some(Data::String("x".to_owned())),
+            None,
+        ))
+        .unwrap_err();
+    assert!(err.contains("reading.id"), "{}", err);
+
+    // 列の型に合わない default のテーブルは作れない
+    let err = parse_table_from_yaml(
+        r"
+name: reading
+columns:
+-   name: id
+    type: i64
+-   name: offset
+    type: i64
+    default: '1.5'
+primary_key: [id]
+",
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("default of reading.offset"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_bool() {
+    use crate::{
+        front::yaml::schema::parse_table_from_yaml,
+        query::{Expr, FilterItem, Insert},
+        storage::file::File,
+    };
+
+    let dir = TestDir::new("test_bool");
+    let filepath = &dir.path("test_bool.rdb");
+    let table = parse_table_from_yaml(
+        r"
+name: user
+columns:
+-   name: id
+    type: u64
+-   name: is_admin
+    type: bool
+-   name: deleted
+    type: bool
+    default: 'false'
+primary_key: [id]
+indices:
+-   name: is_admin
+    columns: [is_admin]
+",
+    )
+    .unwrap();
+    let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+    engine.create_table(table);
+    for i in 0..10 {
+        engine
+            .execute_insert(&Insert::Row {
+                table_name: "user".to_owned(),
+                column_names: vec!["id".to_owned(), "is_admin".to_owned()],
+                values: vec![Data::U64(i), Data::Bool(i % 3 == 0)],
+            })
+            .unwrap();
+    }
+    let select = |key: &str, just: Option<Data>, process: Vec<ProcessItem>| {
+        let mut select = select_range("user", key, just.clone(), just);
+        select.streams[0].process = process;
+        let (_, rows) = engine.execute_select(&select).unwrap();
+        rows.chunks(3).map(|r| r[0].clone()).collect::<Vec<_>>()
+    };
+
+    let admins = vec![Data::U64(0), Data::U64(3), Data::U64(6), Data::U64(9)];
+    let filtered = select(
+        "id",
+        None,
+        vec![ProcessItem::Filter {
+            items: vec![FilterItem::Eq(
+                Expr::Column("is_admin".to_owned()),
+                Expr::Data(Data::Bool(true)),
+            )],
+        }],
+    );
+    assert_eq!(filtered, admins);
+    let filtered = select(
+        "id",
+        None,
+        vec![ProcessItem::Filter {
+            items: vec![FilterItem::Eq(
+                Expr::Column("deleted".to_owned()),
+                Expr::Data(Data::Bool(true)),
+            )],
+        }],
+    );
+    assert!(filtered.is_empty());
+
+    // index は false が先に並ぶ
+    assert_eq!(select("is_admin", Some(Data::Bool(true)), vec![]), admins);
+    let all = select("is_admin", None, vec![]);
+    assert_eq!(&all[6..], &admins[..]);
+    assert_eq!(
+        select("is_admin", Some(Data::String("false".to_owned())), vec![]).len(),
+        6
+    );
+}
+
+#[test]
+fn test_nullable() {
+    use crate::{front::yaml::schema::parse_table_from_yaml, query::Insert, storage::file::File};
+
+    let dir = TestDir::new("test_nullable");
+    let filepath = &dir.path("test_nullable.rdb");
+    let table = parse_table_from_yaml(
+        r"
+name: user
+columns:
+-   name: id
+    type: u64
+-   name: email
+    type: string
+    nullable: true
+-   name: age
+    type: u64
+    nullable: true
+-   name: name
+    type: string
+primary_key: [id]
+indices:
+-   name: email
+    columns: [email]
+",
+    )
+    .unwrap();
+    let insert = |id: u64, email: Option<&str>| Insert::Row {
+        table_name: "user".to_owned(),
+        column_names: if email.is_some() {
+            vec!["id".to_owned(), "name".to_owned(), "email".to_owned()]
+        } else {
+            vec!["id".to_owned(), "name".to_owned()]
+        },
+        values: vec![Data::U64(id), Data::String(format!("user{}", id))]
+            .into_iter()
+            .chain(email.map(|e| Data::String(e.to_owned())))
+            .collect(),
+    };
+    let select = |engine: &Engine<File>, key: &str| {
+        let (_, rows) = engine.execute_select(&select_all("user", key)).unwrap();
+        rows.chunks(4).map(|r| r.to_vec()).collect::<Vec<_>>()
+    };
+
+    {
+        let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+        engine.create_table(table);
+        engine
+            .execute_insert(&insert(1, Some("b@example.com")))
+            .unwrap();
+        engine.execute_insert(&insert(2, None)).unwrap();
+        engine
+            .execute_insert(&insert(3, Some("a@example.com")))
+            .unwrap();
+        engine.execute_insert(&insert(4, None)).unwrap();
+
+        // nullable でない列に null は入らない
+        let err = engine
+            .execute_insert(&Insert::Row {
+                table_name: "user".to_owned(),
+                column_names: vec!["id".to_owned(), "name".to_owned()],
+                values: vec![Data::U64(5), Data::Null],
+            })
+            .unwrap_err();
+        assert!(err.contains("not nullable"), "{}", err);
+
+        // 列の型に合わない値は入らない
+        let err = engine
+            .execute_insert(&Insert::Row {
+                table_name: "user".to_owned(),
+                column_names: vec!["id".to_owned(), "name".to_owned(), "age".to_owned()],
+                values: vec![
+                    Data::U64(5),
+                    Data::String("user5".to_owned()),
+                    Data::I64(-5),
+                ],
+            })
+            .unwrap_err();
+        assert!(err.contains("user.age"), "{}", err);
+
+        // default がなく nullable でもない列は省けない
+        let err = engine
+            .execute_insert(&Insert::Row {
+                table_name: "user".to_owned(),
+                column_names: vec!["id".to_owned()],
+                values: vec![Data::U64(5)],
+            })
+            .unwrap_err();
+        assert!(err.contains("user.name has no default"), "{}", err);
+
+        // 無いテーブルには入らない
+        let err = engine
+            .execute_insert(&Insert::Row {
+                table_name: "nobody".to_owned(),
+                column_names: vec!["id".to_owned()],
+                values: vec![Data::U64(5)],
+            })
+            .unwrap_err();
+        assert!(err.contains("missing table"), "{}", err);
+        engine.flush();
+    }
+
+    let engine = Engine::from_storage(File::open(filepath).unwrap());
+    assert_eq!(engine.storage.check_integrity(), Ok(()));
+    let rows = select(&engine, "id");
+    assert_eq!(
+        rows[1],
+        vec![
+            Data::U64(2),
+            Data::Null,
+            Data::Null,
+            Data::String("user2".to_owned())
+        ]
+    );
+    assert_eq!(rows[0][1], Data::String("b@example.com".to_owned()));
+    assert!(rows.iter().all(|r| r[2] == Data::Null));
+
+    let ids: Vec<_> = select(&engine, "email")
+        .into_iter()
+        .map(|r| r[0].clone())
+        .collect();
+    assert_eq!(
+        ids,
+        vec![Data::U64(2), Data::U64(4), Data::U64(3), Data::U64(1)]
+    );
+}
+
+#[test]
+fn test_bytes() {
+    use crate::{front::yaml::schema::parse_table_from_yaml, query::Insert, storage::file::File};
+
+    let dir = TestDir::new("test_bytes");
+    let filepath = &dir.path("test_bytes.rdb");
+    let table = parse_table_from_yaml(
+        r"
+name: token
+columns:
+-   name: token
+    type: bytes
+-   name: image
+    type: bytes
+    default: '0x89504e47'
+primary_key: [token]
+",
+    )
+    .unwrap();
+    let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+    engine.create_table(table);
+    // YAML から来る文字列のリテラルは列の型に合わせて読む
+    for token in ["0xff00", "base64:AAE=", "0x00", "0x"] {
+        engine
+            .execute_insert(&Insert::Row {
+                table_name: "token".to_owned(),
+                column_names: vec!["token".to_owned()],
+                values: vec![Data::String(token.to_owned())],
+            })
+            .unwrap();
+    }
+    let (_, rows) = engine
+        .execute_select(&select_range(
+            "token",
+            "token",
+            Some(Data::String("0x00".to_owned())),
+            None,
+        ))
+        .unwrap();
+    let image = Data::Bytes(vec![0x89, 0x50, 0x4e, 0x47]);
+    assert_eq!(
+        rows,
+        vec![
+            Data::Bytes(vec![0]),
+            image.clone(),
+            Data::Bytes(vec![0, 1]),
+            image.clone(),
+            Data::Bytes(vec![0xff, 0]),
+            image,
+        ]
+    );
+}
+
+#[test]
+fn test_datetime() {
+    use crate::{front::yaml::schema::parse_table_from_yaml, query::Insert, storage::file::File};
+
+    let dir = TestDir::new("test_datetime");
+    let filepath = &dir.path("test_datetime.rdb");
+    let table = parse_table_from_yaml(
+        r"
+name: event
+columns:
+-   name: created_at
+    type: timestamp
+-   name: day
+    type: date
+-   name: at
+    type: time
+    default: '09:00'
+primary_key: [created_at]
+indices:
+-   name: day
+    columns: [day]
+",
+    )
+    .unwrap();
+    let mut engine = Engine::from_storage(File::open(filepath).unwrap());
+    engine.create_table(table);
+    for (created_at, day) in [
+        ("2024-05-06T10:00:00+09:00", "2024-05-06"),
+        ("2024-05-06T00:30:00Z", "2024-05-06"),
+        ("2024-05-05T23:59:59.5Z", "2024-05-05"),
+        ("2024-05-07 08:00:00", "2024-05-07"),
+        ("2023-12-31T23:00:00-02:00", "2024-01-01"),
+    ] {
+        engine
+            .execute_insert(&Insert::Row {
+                table_name: "event".to_owned(),
+                column_names: vec!["created_at".to_owned(), "day".to_owned()],
+                values: vec![
+                    Data::String(created_at.to_owned()),
+                    Data::String(day.to_owned()),
+                ],
+            })
+            .unwrap();
+    }
+    // 日時として読めない文字列は入らない
+    for (created_at, day) in [("2024-05-08T00:00:00Z", "hello"), ("hello", "2024-05-08")] {
+        let err = engine
+            .execute_insert(&Insert::Row {
+                table_name: "event".to_owned(),
+                column_names: vec!["created_at".to_owned(), "day".to_owned()],
+                values: vec![
+                    Data::String(created_at.to_owned()),
+                    Data::String(day.to_owned()),
+                ],
+            })
+            .unwrap_err();
+        assert!(err.contains("String(\"hello\")"), "{}", err);
+    }
+    let select = |key: &str, from: &str, to: &str| {
+        let (_, rows) = engine
+            .execute_select(&select_range(
+                "event",
+                key,
+                Some(Data::String(from.to_owned())),
+                Some(Data::String(to.to_owned())),
+            ))
+            .unwrap();
+        rows.chunks(3)
+            .map(|r| {
+                r.iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        select("created_at", "2024-05-05T12:00:00Z", "2024-05-06T01:00:00Z"),
+        vec![
+            "2024-05-05T23:59:59.5Z 2024-05-05 09:00:00",
+            "2024-05-06T00:30:00Z 2024-05-06 09:00:00",
+            "2024-05-06T01:00:00Z 2024-05-06 09:00:00",
+        ]
+    );
+    assert_eq!(
+        select("created_at", "2024-01-01", "2024-01-02"),
+        vec!["2024-01-01T01:00:00Z 2024-01-01 09:00:00"]
+    );
+    assert_eq!(select("day", "2024-05-06", "2024-05-31").len(), 3);
+    assert_eq!(select("day", "2024-01-01", "2024-05-05").len(), 2);
+}
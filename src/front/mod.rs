@@ -29,9 +29,13 @@ pub fn print_table(column_names: &[String], values: &[Data]) {
     for row in values.chunks(column_names.len()) {
         for i in 0..column_names.len() {
             let left = match &row[i] {
-                Data::U64(_) | Data::OptionU64(_) => false,
+                Data::U64(_) | Data::OptionU64(_) | Data::I64(_) | Data::F64(_) => false,
                 Data::String(_) => true,
                 Data::Lancer(_) => true,
+                Data::Bool(_) => true,
+                Data::Null => true,
+                Data::Bytes(_) => true,
+                Data::Date(_) | Data::Time(_) | Data::Timestamp(_) => true,
             };
             print!("|");
             show_with_pad(&row[i], widths[i], left);
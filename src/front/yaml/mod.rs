@@ -4,9 +4,24 @@ pub mod query;
 pub mod schema;
 
 pub fn string_to_data(str: String) -> Data {
-    if let Ok(v) = str.parse() {
+    if str == "true" || str == "false" {
+        Data::Bool(str == "true")
+    } else if let Ok(v) = str.parse() {
         Data::U64(v)
+    } else if let Ok(v) = str.parse() {
+        Data::I64(v)
+    } else if looks_like_float(&str) {
+        Data::F64(str.parse().unwrap())
     } else {
         Data::String(str.clone())
     }
 }
+
+// "inf" や "nan" は文字列のままにする (f64 の列に入れるときに変換される)
+fn looks_like_float(str: &str) -> bool {
+    str.bytes().any(|b| b.is_ascii_digit())
+        && str
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b))
+        && str.parse::<f64>().is_ok()
+}
@@ -1,7 +1,8 @@
 use serde::Deserialize;
 
 use crate::{
-    data::Data,
+    data::{parse_bytes_literal, Data},
+    datetime::{parse_date, parse_time, parse_timestamp},
     front::yaml::{query::mapping::ProcessSelectColumn, string_to_data},
     query::{
         Delete, Expr, FilterItem, Insert, PostProcessItem, ProcessItem, Query, Select,
@@ -321,6 +322,23 @@ fn map_expr(expr: mapping::Expr) -> Expr {
         mapping::Expr::Column(column_name) => Expr::Column(column_name),
         mapping::Expr::String(string) => Expr::Data(Data::String(string)),
         mapping::Expr::U64(u64) => Expr::Data(Data::U64(u64)),
+        mapping::Expr::I64(i64) => Expr::Data(Data::I64(i64)),
+        mapping::Expr::F64(f64) => Expr::Data(Data::F64(f64)),
+        mapping::Expr::Bool(bool) => Expr::Data(Data::Bool(bool)),
+        mapping::Expr::Bytes(literal) => Expr::Data(Data::Bytes(
+            parse_bytes_literal(&literal)
+                .unwrap_or_else(|| panic!("unexpected bytes literal {:?}", literal)),
+        )),
+        mapping::Expr::Date(literal) => Expr::Data(Data::Date(
+            parse_date(&literal).unwrap_or_else(|| panic!("unexpected date {:?}", literal)),
+        )),
+        mapping::Expr::Time(literal) => Expr::Data(Data::Time(
+            parse_time(&literal).unwrap_or_else(|| panic!("unexpected time {:?}", literal)),
+        )),
+        mapping::Expr::Timestamp(literal) => Expr::Data(Data::Timestamp(
+            parse_timestamp(&literal)
+                .unwrap_or_else(|| panic!("unexpected timestamp {:?}", literal)),
+        )),
         mapping::Expr::Enumerate(v) => Expr::Enumerate(Data::U64(v)),
     }
 }
@@ -430,6 +448,15 @@ mod mapping {
         Column(String),
         String(String),
         U64(u64),
+        I64(i64),
+        F64(f64),
+        Bool(bool),
+        // 0x... または base64:...
+        Bytes(String),
+        // ISO-8601
+        Date(String),
+        Time(String),
+        Timestamp(String),
         Enumerate(u64),
     }
 
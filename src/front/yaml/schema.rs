@@ -1,3 +1,5 @@
+use serde::de::Error;
+
 use crate::{
     data::Type,
     schema::{Column, Default, Index, Schema, Table},
@@ -22,28 +24,54 @@ pub fn parse_table_from_yaml(src: &str) -> Result<Table, serde_yaml::Error> {
 }
 
 fn map_table(table: mapping::Table) -> Result<Table, serde_yaml::Error> {
-    let columns: Vec<_> = table
+    let columns = table
         .columns
         .iter()
-        .map(|c| Column {
-            name: c.name.clone(),
-            dtype: match c.r#type.as_str() {
+        .map(|c| {
+            let dtype = match c.r#type.as_str() {
                 "u64" => Type::U64,
                 "string" => Type::String,
+                "i64" => Type::I64,
+                "f64" => Type::F64,
+                "bool" => Type::Bool,
+                "bytes" => Type::Bytes,
+                "date" => Type::Date,
+                "time" => Type::Time,
+                "timestamp" => Type::Timestamp,
                 _ => panic!("unexpected {:?}", c.r#type),
-            },
-            default: match (&c.default, &c.auto_increment) {
-                (Some(default), false) => Some(Default::Data(string_to_data(default.clone()))),
+            };
+            // 列の型に合わない default はテーブルを作る前に拒む
+            let default = match (&c.default, &c.auto_increment) {
+                (Some(default), false) => Some(Default::Data(
+                    string_to_data(default.clone()).coerce(dtype).map_err(|e| {
+                        serde_yaml::Error::custom(format!(
+                            "default of {}.{}: {}",
+                            table.name, c.name, e
+                        ))
+                    })?,
+                )),
                 (None, true) => Some(Default::AutoIncrement),
                 (None, false) => None,
                 _ => panic!("default???"),
-            },
+            };
+            Ok(Column {
+                name: c.name.clone(),
+                dtype,
+                nullable: c.nullable,
+                default,
+            })
         })
-        .collect();
+        .collect::<Result<Vec<_>, _>>()?;
     let primary_key = table
         .primary_key
         .into_iter()
-        .map(|name| columns.iter().position(|c| c.name == name).unwrap())
+        .map(|name| {
+            let i = columns.iter().position(|c| c.name == name).unwrap();
+            if columns[i].nullable {
+                panic!("primary key {:?} cannot be nullable", name);
+            }
+            i
+        })
         .collect();
     let indices = table
         .indices
@@ -94,6 +122,8 @@ mod mapping {
         pub default: Option<String>,
         #[serde(default)]
         pub auto_increment: bool,
+        #[serde(default)]
+        pub nullable: bool,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,6 +1,7 @@
 pub mod btree;
 pub mod builtin_schema;
 pub mod data;
+pub mod datetime;
 pub mod engine;
 pub mod front;
 pub mod query;
@@ -9,3 +10,5 @@ pub mod storage;
 
 #[cfg(test)]
 pub mod monkey_test;
+#[cfg(test)]
+pub mod test_util;
@@ -69,7 +69,7 @@ tables:
     if let Ok(_) = std::fs::remove_file(filepath) {
         println!("{:?} removed", filepath);
     };
-    let mut s = rdb::storage::file::File::open(filepath);
+    let mut s = rdb::storage::file::File::open(filepath).unwrap();
     s.add_table(new_auto_increment_table());
     for table in schema.tables.iter() {
         s.add_table(table.clone());
@@ -111,7 +111,7 @@ tables:
     )
     .unwrap();
 
-    let mut engine = Engine::from_storage(s);
+    let engine = Engine::from_storage(s);
 
     let query = Select {
         sub_queries: vec![],
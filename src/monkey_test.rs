@@ -9,6 +9,7 @@ use crate::{
         Delete, Expr, FilterItem, Insert, ProcessItem, Select, SelectSource, SelectSourceTable,
         Stream,
     },
+    test_util::TestDir,
 };
 
 #[test]
@@ -42,11 +43,9 @@ indices:
     // let id_data = |i: u64| Data::U64(i);
     let id_data = |i: u64| Data::String(i.to_string());
 
-    let filepath = "main.rdb";
-    if let Ok(_) = std::fs::remove_file(filepath) {
-        println!("{:?} removed", filepath);
-    };
-    let mut engine = Engine::from_storage(crate::storage::file::File::open(filepath));
+    let dir = TestDir::new("main");
+    let filepath = &dir.path("main.rdb");
+    let mut engine = Engine::from_storage(crate::storage::file::File::open(filepath).unwrap());
     engine.create_table(new_auto_increment_table());
     engine.create_table(table);
 
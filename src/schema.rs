@@ -20,6 +20,8 @@ pub struct Table {
 pub struct Column {
     pub name: String,
     pub dtype: Type,
+    // Data::Null を入れられる
+    pub nullable: bool,
     pub default: Option<Default>,
 }
 
@@ -105,7 +107,28 @@ impl Table {
     }
 
     pub fn check_row_is_legal(&self, row: &Vec<Data>) -> Result<(), String> {
-        todo!()
+        if row.len() != self.columns.len() {
+            return Err(format!(
+                "{} has {} columns, but the row has {} values",
+                self.name,
+                self.columns.len(),
+                row.len()
+            ));
+        }
+        for (column, data) in self.columns.iter().zip(row) {
+            if let Data::Null = data {
+                if !column.nullable {
+                    return Err(format!("{}.{} is not nullable", self.name, column.name));
+                }
+            }
+            if !data.is_of_type(column.dtype) {
+                return Err(format!(
+                    "{}.{} is {:?}, but the value is {:?}",
+                    self.name, column.name, column.dtype, data
+                ));
+            }
+        }
+        Ok(())
     }
 
     // pub fn row_to_key(&self, row:&Vec<Data>) ->Vec<Data> {
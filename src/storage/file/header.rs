@@ -0,0 +1,502 @@
+use std::convert::TryInto;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    impl_btree::Meta,
+    page::Page,
+    pager::{is_valid_page_size, PageRaw, Pager, DEFAULT_PAGE_SIZE, KEY_CHECK_SIZE, SALT_SIZE},
+    read_sources,
+    simple_store::{is_object_table, read_object, write_object},
+    write_free_page_head, Source,
+};
+use crate::{
+    btree::{BTree, BTreeNode},
+    data::{data_vec_from_bytes, data_vec_to_key, null_bitmap_size, row_to_bytes, Type},
+    schema::{self, Column, Constraint, Index, Schema, Table},
+};
+
+// # header page (page 0)
+// [8] MAGIC
+// [4] format version
+// [4] page size (4K から 64K の 2 のべき乗)
+// [8] feature flags
+// [4] object table page
+// [16] salt (FLAG_PAGE_ENCRYPTION のとき、鍵を作るのに使う)
+// [16] key check (FLAG_PAGE_ENCRYPTION のとき、鍵が正しいかを調べるのに使う)
+//
+// version 0 のファイルにはヘッダがなく、page 0 がオブジェクトテーブルだった
+// また、ページにチェックサムがなく、長いキーや値もページ内に置き、キーを値と同じ
+// (little endian の) エンコードで持ち、値の行に null のビットマップがなく、列に nullable がなかった
+
+const MAGIC: [u8; 8] = *b"rdb\0\x10\x06\r\n";
+const HEADER_SIZE: usize = 60;
+pub const FORMAT_VERSION: u32 = 1;
+
+// 各ページの後ろにチェックサムがある
+pub const FLAG_PAGE_CHECKSUM: u64 = 1 << 0;
+// page 0 以外のページを圧縮し、可変長の領域に置く (pager::page_map)
+pub const FLAG_PAGE_COMPRESSION: u64 = 1 << 1;
+// page 0 以外のページと wal を暗号化する (pager::cipher)
+pub const FLAG_PAGE_ENCRYPTION: u64 = 1 << 2;
+// このバージョンが理解できる機能フラグ
+pub const SUPPORTED_FLAGS: u64 = FLAG_PAGE_CHECKSUM | FLAG_PAGE_COMPRESSION | FLAG_PAGE_ENCRYPTION;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub version: u32,
+    pub page_size: u32,
+    pub flags: u64,
+    pub object_table_page: u32,
+    pub salt: [u8; SALT_SIZE],
+    pub key_check: [u8; KEY_CHECK_SIZE],
+}
+
+impl Header {
+    // ページの大きさや圧縮、暗号化の有無はページャーに合わせる
+    pub fn new(object_table_page: usize, pager: &Pager<Page>) -> Self {
+        let mut header = Self {
+            version: FORMAT_VERSION,
+            page_size: pager.page_size() as u32,
+            flags: FLAG_PAGE_CHECKSUM,
+            object_table_page: object_table_page as u32,
+            salt: [0; SALT_SIZE],
+            key_check: [0; KEY_CHECK_SIZE],
+        };
+        if pager.compression() {
+            header.flags |= FLAG_PAGE_COMPRESSION;
+        }
+        if let Some(cipher) = pager.cipher() {
+            header.flags |= FLAG_PAGE_ENCRYPTION;
+            header.salt = cipher.salt();
+            header.key_check = cipher.key_check();
+        }
+        header
+    }
+
+    // マジックが一致しなければ None
+    pub fn read(page: &Page) -> Option<Self> {
+        Self::from_bytes(page.slice(0, HEADER_SIZE))
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[0..8] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            version: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            page_size: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            flags: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            object_table_page: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            salt: bytes[28..44].try_into().unwrap(),
+            key_check: bytes[44..60].try_into().unwrap(),
+        })
+    }
+
+    pub fn write(&self, page: &mut Page) {
+        page.slice_mut(0, 8).copy_from_slice(&MAGIC);
+        page.slice_mut(8, 4)
+            .copy_from_slice(&self.version.to_le_bytes());
+        page.slice_mut(12, 4)
+            .copy_from_slice(&self.page_size.to_le_bytes());
+        page.slice_mut(16, 8)
+            .copy_from_slice(&self.flags.to_le_bytes());
+        page.slice_mut(24, 4)
+            .copy_from_slice(&self.object_table_page.to_le_bytes());
+        page.slice_mut(28, SALT_SIZE).copy_from_slice(&self.salt);
+        page.slice_mut(44, KEY_CHECK_SIZE)
+            .copy_from_slice(&self.key_check);
+    }
+
+    // このバージョンで開けないファイルならエラーを返す
+    pub fn validate(&self) -> Result<(), String> {
+        if FORMAT_VERSION < self.version {
+            return Err(format!(
+                "file format version {} is newer than supported version {}",
+                self.version, FORMAT_VERSION
+            ));
+        }
+        // ヘッダのあるファイルは version 1 から
+        if self.version < FORMAT_VERSION {
+            return Err(format!(
+                "file format version {} is not supported",
+                self.version
+            ));
+        }
+        if !is_valid_page_size(self.page_size as usize) {
+            return Err(format!("page size {} is not supported", self.page_size));
+        }
+        let unknown_flags = self.flags & !SUPPORTED_FLAGS;
+        if unknown_flags != 0 {
+            return Err(format!("unknown feature flags {:#x}", unknown_flags));
+        }
+        Ok(())
+    }
+}
+
+// ページを読む前に、ファイルの先頭から分かること
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub page_size: usize,
+    pub checksum: bool,
+    pub compression: bool,
+    // 暗号化されていれば (salt, key check)
+    pub encryption: Option<([u8; SALT_SIZE], [u8; KEY_CHECK_SIZE])>,
+}
+
+// 圧縮や暗号化をするファイルでも page 0 はそのまま先頭にある
+// 空のファイルは None (新しく初期化するので、呼んだ側で形式を決める)
+// ヘッダのないファイル (version 0) は 4K でチェックサムがない
+// ヘッダのページの大きさが正しくなければ、ヘッダを読めるように 4K にしておく (validate で弾く)
+pub fn probe_file(filepath: &str) -> Option<Probe> {
+    let mut bytes = vec![];
+    if let Ok(file) = std::fs::File::open(filepath) {
+        std::io::Read::read_to_end(
+            &mut std::io::Read::take(file, HEADER_SIZE as u64),
+            &mut bytes,
+        )
+        .unwrap();
+    }
+    if bytes.is_empty() {
+        return None;
+    }
+    let header = match Header::from_bytes(&bytes) {
+        Some(header) => header,
+        None => {
+            return Some(Probe {
+                page_size: DEFAULT_PAGE_SIZE,
+                checksum: false,
+                compression: false,
+                encryption: None,
+            })
+        }
+    };
+    Some(Probe {
+        page_size: if is_valid_page_size(header.page_size as usize) {
+            header.page_size as usize
+        } else {
+            DEFAULT_PAGE_SIZE
+        },
+        checksum: header.flags & FLAG_PAGE_CHECKSUM != 0,
+        compression: header.flags & FLAG_PAGE_COMPRESSION != 0,
+        encryption: if header.flags & FLAG_PAGE_ENCRYPTION != 0 {
+            Some((header.salt, header.key_check))
+        } else {
+            None
+        },
+    })
+}
+
+pub fn read_header(pager: &Pager<Page>) -> Option<Header> {
+    Header::read(&pager.get_ref(0))
+}
+
+pub fn write_header(pager: &Pager<Page>, header: &Header) {
+    header.write(&mut pager.get_mut(0));
+}
+
+// ヘッダを読み、ヘッダのない古いファイル (version 0) なら今のフォーマットに上げる
+pub fn open_header(pager: &mut Pager<Page>) -> Result<Header, String> {
+    match read_header(pager) {
+        Some(header) => {
+            header.validate()?;
+            Ok(header)
+        }
+        None if is_object_table(pager, 0) => {
+            migrate_v0(pager)?;
+            Ok(read_header(pager).unwrap())
+        }
+        None => Err("not an rdb file (bad magic)".to_owned()),
+    }
+}
+
+// version 0 のスキーマ
+#[derive(Serialize, Deserialize)]
+struct SchemaV0 {
+    tables: Vec<TableV0>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableV0 {
+    name: String,
+    columns: Vec<ColumnV0>,
+    primary_key: Vec<usize>,
+    constraints: Vec<Constraint>,
+    indices: Vec<Index>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ColumnV0 {
+    name: String,
+    dtype: Type,
+    default: Option<schema::Default>,
+}
+
+// version 0 のファイルを今のフォーマットにする
+// page 0 のオブジェクトテーブルを新しいページに移して page 0 にヘッダを書き、各 Source の木を作り直す
+// 最後にファイルをチェックサム付きの形式で書き直して置き換えるので、途中で落ちても元のファイルが残る
+fn migrate_v0(pager: &mut Pager<Page>) -> Result<(), String> {
+    // オブジェクトテーブルの続きのページはそのまま使える
+    let object_table_page = pager.allocate();
+    let raw: PageRaw = (**pager.get_ref(0)).into();
+    pager.swap(object_table_page, Page::from(raw));
+    pager.swap(0, Page::new(pager.page_size()));
+    write_header(pager, &Header::new(object_table_page, pager));
+
+    // 列は nullable でないものとして読む
+    let schema: SchemaV0 = read_object(pager, "schema").ok_or("cannot read schema")?;
+    let schema = Schema {
+        tables: schema
+            .tables
+            .into_iter()
+            .map(|t| Table {
+                name: t.name,
+                columns: t
+                    .columns
+                    .into_iter()
+                    .map(|c| Column {
+                        name: c.name,
+                        dtype: c.dtype,
+                        nullable: false,
+                        default: c.default,
+                    })
+                    .collect(),
+                primary_key: t.primary_key,
+                constraints: t.constraints,
+                indices: t.indices,
+            })
+            .collect(),
+    };
+    write_object(pager, "schema", &schema);
+
+    // キーを順序を保つエンコードにし、長いキーや値はオーバーフローページに置く
+    // インデックスの値 (主キー) もキーのエンコードにし、テーブルの値の行には null のビットマップを足す
+    let mut sources = read_sources(pager);
+    let free_page_head: u32 = read_object(pager, "free_page_head").unwrap_or(0);
+    pager.set_free_head(free_page_head as usize);
+    {
+        let _writer = pager.lock_writer();
+        for source in sources.iter_mut() {
+            let is_index = source.parent_source_index.is_some();
+            let entries = read_entries(pager, source);
+            let meta = Meta {
+                key_size: source.key_types.iter().map(|t| t.key_size()).sum(),
+                value_size: if is_index {
+                    source.value_types.iter().map(|t| t.key_size()).sum()
+                } else {
+                    source
+                        .meta
+                        .value_size
+                        .map(|size| null_bitmap_size(source.value_types.len()) + size)
+                },
+                page_size: pager.page_size(),
+            };
+            let page_index = (&*pager).add_root_node();
+            for (key, value) in entries {
+                let read = |types, bytes: &[u8]| {
+                    data_vec_from_bytes(types, bytes).ok_or_else(|| "broken entry".to_owned())
+                };
+                let key = data_vec_to_key(&read(&source.key_types, &key)?);
+                let value = if is_index {
+                    data_vec_to_key(&read(&source.value_types, &value)?)
+                } else {
+                    row_to_bytes(&read(&source.value_types, &value)?)
+                };
+                pager.insert_entry(&meta, page_index, &key, &value)?;
+            }
+            free_nodes(pager, &source.meta, source.page_index);
+            source.page_index = page_index;
+            source.meta = meta;
+        }
+        write_object(pager, "sources", &sources);
+        write_free_page_head(pager);
+    }
+
+    pager.enable_checksum();
+    Ok(())
+}
+
+// version 0 の木のエントリ
+// オーバーフローページはないので、長いものもそのまま読む
+fn read_entries(pager: &Pager<Page>, source: &Source) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let view = pager.view(None);
+    let mut entries = vec![];
+    let cursor = view.first_cursor(&source.meta, source.page_index);
+    let mut cursor = view.cursor_next_occupied(&source.meta, cursor);
+    while !view.cursor_is_end(&source.meta, &cursor) {
+        entries.push(view.cursor_get(&source.meta, &cursor).unwrap());
+        cursor = view.cursor_next(&source.meta, cursor);
+    }
+    entries
+}
+
+// version 0 の木のノードを解放する
+// 長いエントリを stub として読まないように、オーバーフローページはたどらない
+fn free_nodes(pager: &Pager<Page>, meta: &Meta, node_i: usize) {
+    let node = pager.get_ref(node_i);
+    let children = if node.is_leaf(meta) {
+        vec![]
+    } else {
+        node.get_children(meta)
+    };
+    drop(node);
+    for child_i in children {
+        free_nodes(pager, meta, child_i);
+    }
+    pager.free(node_i);
+}
+
+#[cfg(test)]
+use crate::test_util::TestDir;
+
+#[test]
+fn test_refuse() {
+    use super::File;
+
+    let dir = TestDir::new("test_refuse");
+    let filepath = &dir.path("test_refuse.rdb");
+    std::fs::write(filepath, "not a database").unwrap();
+    assert!(File::open(filepath).is_err());
+    std::fs::write(filepath, vec![0xff; DEFAULT_PAGE_SIZE * 2]).unwrap();
+    assert!(File::open(filepath).is_err());
+
+    std::fs::remove_file(filepath).unwrap();
+    {
+        use crate::storage::Storage;
+        File::open(filepath).unwrap().flush();
+    }
+    for (modify, message) in [
+        (
+            (|h: &mut Header| h.version = FORMAT_VERSION + 1) as fn(&mut Header),
+            "newer",
+        ),
+        (|h: &mut Header| h.page_size = 1000, "page size"),
+        (|h: &mut Header| h.flags = 1 << 63, "feature flags"),
+    ] {
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        let original = read_header(&pager).unwrap();
+        let mut header = original.clone();
+        modify(&mut header);
+        write_header(&pager, &header);
+        pager.save();
+        drop(pager);
+
+        let err = File::open(filepath).err().unwrap();
+        assert!(err.contains(message), "{}", err);
+
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        write_header(&pager, &original);
+        pager.save();
+    }
+    assert!(File::open(filepath).is_ok());
+}
+
+#[test]
+fn test_migrate_v0() {
+    use super::File;
+    use crate::{
+        btree::BTree,
+        data::{data_vec_to_bytes, Data},
+        storage::Storage,
+    };
+
+    let dir = TestDir::new("test_migrate_v0");
+    let filepath = &dir.path("test_migrate_v0.rdb");
+    let table = crate::front::yaml::schema::parse_table_from_yaml(
+        r"
+name: user
+columns:
+-   name: id
+    type: u64
+-   name: name
+    type: string
+primary_key: [id]
+",
+    )
+    .unwrap();
+    let name = |i: u64| format!("{}", i).repeat(1000);
+    {
+        let mut f = File::open(filepath).unwrap();
+        f.add_table(table);
+        for i in 0..10 {
+            // version 0 では長い値もページ内に置かれていた
+            let _writer = f.pager.lock_writer();
+            let source = &f.sources[0];
+            (&f.pager)
+                .insert(
+                    &source.meta,
+                    source.page_index,
+                    &data_vec_to_bytes(&[Data::U64(i * 100)]).unwrap(),
+                    &data_vec_to_bytes(&[Data::String(name(i))]).unwrap(),
+                )
+                .unwrap();
+        }
+        // version 0 では列に nullable がなかった
+        let schema = SchemaV0 {
+            tables: f
+                .schema
+                .tables
+                .iter()
+                .map(|t| TableV0 {
+                    name: t.name.clone(),
+                    columns: t
+                        .columns
+                        .iter()
+                        .map(|c| ColumnV0 {
+                            name: c.name.clone(),
+                            dtype: c.dtype,
+                            default: c.default.clone(),
+                        })
+                        .collect(),
+                    primary_key: t.primary_key.clone(),
+                    constraints: t.constraints.clone(),
+                    indices: t.indices.clone(),
+                })
+                .collect(),
+        };
+        write_object(&f.pager, "schema", &schema);
+        f.flush();
+    }
+    {
+        // version 0 にはヘッダもチェックサムもなく、page 0 がオブジェクトテーブル
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        let ot_i = read_header(&pager).unwrap().object_table_page as usize;
+        let mut bytes = vec![];
+        for i in 0..pager.size() {
+            bytes.extend(pager.get_ref(if i == 0 { ot_i } else { i }).iter());
+        }
+        drop(pager);
+        std::fs::write(filepath, bytes).unwrap();
+    }
+    {
+        let f = File::open(filepath).unwrap();
+        assert_eq!(f.check_integrity(), Ok(()));
+        let source_index = f.source_index("user", &["id".to_owned()]).unwrap();
+        let cursor = f.get_cursor_just(source_index, &vec![Data::U64(700)]);
+        assert_eq!(
+            f.cursor_get_row(&cursor),
+            Some(vec![Data::U64(700), Data::String(name(7))])
+        );
+
+        let mut cursor = f.get_cursor_first(source_index);
+        let mut ids = vec![];
+        while !f.cursor_is_end(&cursor) {
+            ids.push(f.cursor_get_row(&cursor).unwrap()[0].clone());
+            f.cursor_advance(&mut cursor);
+        }
+        assert_eq!(ids, (0..10).map(|i| Data::U64(i * 100)).collect::<Vec<_>>());
+    }
+    assert_eq!(
+        probe_file(filepath),
+        Some(Probe {
+            page_size: DEFAULT_PAGE_SIZE,
+            checksum: true,
+            compression: false,
+            encryption: None,
+        })
+    );
+    let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+    let header = read_header(&pager).unwrap();
+    assert_eq!(header.version, FORMAT_VERSION);
+    assert_eq!(header.flags, FLAG_PAGE_CHECKSUM);
+}
@@ -1,15 +1,25 @@
 use std::convert::TryInto;
 
-use crate::btree::{BTree, BTreeNode};
+use serde::{Deserialize, Serialize};
+
+use crate::btree::{BTree, BTreeCursor, BTreeNode};
 
 use super::{
     page::Page,
-    pager::{Pager, PAGE_SIZE},
+    pager::{PageMut, PageRef, Pager},
 };
 
 const INTERNAL_HEADER_SIZE: usize = 1 + 4 + 2;
 const LEAF_HEADER_SIZE: usize = 1 + 4 + 2 + 4;
-const INDEX_SIZE: usize = 2;
+
+// 64K のページでは末尾のオフセット (65536) が u16 に収まらないので 4 バイトにする
+fn index_size(page_size: usize) -> usize {
+    if page_size <= u16::MAX as usize {
+        2
+    } else {
+        4
+    }
+}
 
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
@@ -44,11 +54,17 @@ pub type Value = Vec<u8>;
 //
 // ## key_size variable, value_size variable
 // (key_index, value_index)...    , ...(key, value)
+//
+// key_index と value_index はページ内のオフセットで、幅は index_size
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Meta {
     pub key_size: Option<usize>,
     pub value_size: Option<usize>,
+    // ファイルのヘッダから決まるので保存しない
+    // sources を読んだときに設定する
+    #[serde(skip)]
+    pub page_size: usize,
 }
 
 impl BTreeNode<Key, Value> for Page {
@@ -76,14 +92,17 @@ impl BTreeNode<Key, Value> for Page {
     }
 
     fn split_out(&mut self, meta: &Self::Meta) -> (Key, Self) {
+        let page_size = self.len();
+        let index_size = self.index_size();
         let size = self.size(meta);
         if self.is_leaf(meta) {
-            let mut new_page = Page::new_leaf();
+            let mut new_page = Page::new_leaf(self.len());
 
             let (pivot_index, pivot_key) = match meta {
                 Meta {
                     key_size: Some(key_size),
                     value_size: Some(value_size),
+                    ..
                 } => {
                     let (pivot_index, pivot_key) = {
                         let mut index = size / 2;
@@ -106,11 +125,10 @@ impl BTreeNode<Key, Value> for Page {
                         );
 
                     // move values
-                    new_page[PAGE_SIZE as usize - value_size * (size - pivot_index)
-                        ..PAGE_SIZE as usize]
+                    new_page[page_size - value_size * (size - pivot_index)..page_size]
                         .copy_from_slice(
-                            &self[PAGE_SIZE as usize - value_size * size
-                                ..PAGE_SIZE as usize - value_size * pivot_index],
+                            &self[page_size - value_size * size
+                                ..page_size - value_size * pivot_index],
                         );
 
                     (pivot_index, pivot_key)
@@ -118,8 +136,9 @@ impl BTreeNode<Key, Value> for Page {
                 Meta {
                     key_size: Some(key_size),
                     value_size: None,
+                    ..
                 } => {
-                    let key_interval = key_size + INDEX_SIZE;
+                    let key_interval = key_size + index_size;
 
                     let (pivot_index, pivot_key) = {
                         let mut index = size / 2;
@@ -135,9 +154,9 @@ impl BTreeNode<Key, Value> for Page {
                     };
 
                     let offset = LEAF_HEADER_SIZE + key_interval * (size - 1) + key_size;
-                    let last_value_index = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let last_value_index = self.get_offset(offset);
                     let offset = LEAF_HEADER_SIZE + key_interval * (pivot_index - 1) + key_size; //?
-                    let pivot_value_index = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let pivot_value_index = self.get_offset(offset);
 
                     // move keys
                     new_page
@@ -149,16 +168,15 @@ impl BTreeNode<Key, Value> for Page {
 
                     // move values
                     let values_to_move_size = pivot_value_index - last_value_index;
-                    new_page[PAGE_SIZE as usize - values_to_move_size..PAGE_SIZE as usize]
+                    new_page[page_size - values_to_move_size..page_size]
                         .copy_from_slice(&self[last_value_index..pivot_value_index]);
 
                     // recalculate value_index
-                    let value_index_diff = PAGE_SIZE as u16 - pivot_value_index as u16;
+                    let value_index_diff = page_size - pivot_value_index;
                     for i in 0..(size - pivot_index) {
                         let offset = LEAF_HEADER_SIZE + key_interval * i + key_size;
-                        let s = parse_u16(&new_page[offset..offset + INDEX_SIZE]);
-                        new_page[offset..offset + INDEX_SIZE]
-                            .copy_from_slice(&(s + value_index_diff).to_le_bytes());
+                        let s = new_page.get_offset(offset);
+                        new_page.set_offset(offset, s + value_index_diff);
                     }
 
                     (pivot_index, pivot_key)
@@ -166,24 +184,20 @@ impl BTreeNode<Key, Value> for Page {
                 Meta {
                     key_size: None,
                     value_size: Some(value_size),
+                    ..
                 } => {
-                    let key_interval = INDEX_SIZE;
+                    let key_interval = index_size;
 
                     let key_iter = |this: &Self, mut index: usize| {
-                        let mut last_offset = parse_u16(
-                            this.slice(LEAF_HEADER_SIZE + key_interval * index, key_interval),
-                        ) as usize;
+                        let mut last_offset =
+                            this.get_offset(LEAF_HEADER_SIZE + key_interval * index);
                         move |this: &Self| {
-                            let offset =
-                                if index == 0 {
-                                    PAGE_SIZE as usize
-                                } else {
-                                    index -= 1;
-                                    parse_u16(this.slice(
-                                        LEAF_HEADER_SIZE + key_interval * index,
-                                        key_interval,
-                                    )) as usize
-                                };
+                            let offset = if index == 0 {
+                                page_size
+                            } else {
+                                index -= 1;
+                                this.get_offset(LEAF_HEADER_SIZE + key_interval * index)
+                            };
                             let range = last_offset..offset - value_size;
                             last_offset = offset;
                             range
@@ -204,7 +218,7 @@ impl BTreeNode<Key, Value> for Page {
                     };
 
                     let offset = LEAF_HEADER_SIZE + key_interval * (pivot_index - 1);
-                    let key_index_end = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let key_index_end = self.get_offset(offset);
 
                     // move key_indexs
                     new_page
@@ -215,20 +229,19 @@ impl BTreeNode<Key, Value> for Page {
                         );
 
                     let offset = LEAF_HEADER_SIZE + key_interval * (size - 1);
-                    let last_value_index = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let last_value_index = self.get_offset(offset);
 
                     // move values
                     let values_to_move_size = key_index_end - last_value_index; // key_index_start は key_index_endが正しかった
-                    new_page[PAGE_SIZE as usize - values_to_move_size..PAGE_SIZE as usize]
+                    new_page[page_size - values_to_move_size..page_size]
                         .copy_from_slice(&self[last_value_index..key_index_end]);
 
                     // recalculate value_index
-                    let value_index_diff = PAGE_SIZE as u16 - key_index_end as u16;
+                    let value_index_diff = page_size - key_index_end;
                     for i in 0..(size - pivot_index) {
-                        let slice =
-                            new_page.slice_mut(LEAF_HEADER_SIZE + key_interval * i, INDEX_SIZE);
-                        let s = parse_u16(slice);
-                        slice.copy_from_slice(&(s + value_index_diff).to_le_bytes());
+                        let offset = LEAF_HEADER_SIZE + key_interval * i;
+                        let s = new_page.get_offset(offset);
+                        new_page.set_offset(offset, s + value_index_diff);
                     }
 
                     (pivot_index, pivot_key)
@@ -236,17 +249,14 @@ impl BTreeNode<Key, Value> for Page {
                 Meta {
                     key_size: None,
                     value_size: None,
+                    ..
                 } => {
-                    let key_interval = INDEX_SIZE * 2;
+                    let key_interval = index_size * 2;
 
                     let get_key = |this: &Self, index: usize| {
-                        let key_offset = parse_u16(
-                            this.slice(LEAF_HEADER_SIZE + key_interval * index, INDEX_SIZE),
-                        ) as usize;
-                        let value_offset = parse_u16(this.slice(
-                            LEAF_HEADER_SIZE + key_interval * index + INDEX_SIZE,
-                            INDEX_SIZE,
-                        )) as usize;
+                        let key_offset = this.get_offset(LEAF_HEADER_SIZE + key_interval * index);
+                        let value_offset =
+                            this.get_offset(LEAF_HEADER_SIZE + key_interval * index + index_size);
                         key_offset..value_offset
                     };
                     let (pivot_index, pivot_key) = {
@@ -271,22 +281,21 @@ impl BTreeNode<Key, Value> for Page {
                         );
 
                     let offset = LEAF_HEADER_SIZE + key_interval * (size - 1);
-                    let last_key_index = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let last_key_index = self.get_offset(offset);
 
                     // move values
                     let offset = LEAF_HEADER_SIZE + key_interval * (pivot_index - 1);
-                    let key_index_end = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let key_index_end = self.get_offset(offset);
                     let to_move_size = key_index_end - last_key_index;
-                    new_page[PAGE_SIZE as usize - to_move_size..PAGE_SIZE as usize]
+                    new_page[page_size - to_move_size..page_size]
                         .copy_from_slice(&self[last_key_index..key_index_end]);
 
                     // recalculate key_index and value_index
-                    let value_index_diff = PAGE_SIZE as u16 - key_index_end as u16;
+                    let value_index_diff = page_size - key_index_end;
                     for i in 0..(size - pivot_index) * 2 {
-                        let slice =
-                            new_page.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE);
-                        let s = parse_u16(slice);
-                        slice.copy_from_slice(&(s + value_index_diff).to_le_bytes());
+                        let offset = LEAF_HEADER_SIZE + index_size * i;
+                        let s = new_page.get_offset(offset);
+                        new_page.set_offset(offset, s + value_index_diff);
                     }
 
                     (pivot_index, pivot_key)
@@ -298,7 +307,7 @@ impl BTreeNode<Key, Value> for Page {
             new_page.set_size(size - pivot_index);
             (pivot_key, new_page)
         } else {
-            let mut new_page = Page::new_internal(meta);
+            let mut new_page = Page::new(self.len());
 
             let value_size = 4;
             let (pivot_index, pivot_key) = match meta.key_size {
@@ -327,31 +336,26 @@ impl BTreeNode<Key, Value> for Page {
                         );
 
                     // move values
-                    new_page[PAGE_SIZE as usize - value_size * (size - pivot_index)
-                        ..PAGE_SIZE as usize]
+                    new_page[page_size - value_size * (size - pivot_index)..page_size]
                         .copy_from_slice(
-                            &self[PAGE_SIZE as usize - value_size * (size)
-                                ..PAGE_SIZE as usize - value_size * pivot_index],
+                            &self[page_size - value_size * (size)
+                                ..page_size - value_size * pivot_index],
                         );
 
                     (pivot_index, pivot_key)
                 }
                 None => {
-                    let key_interval = INDEX_SIZE;
+                    let key_interval = index_size;
 
                     let key_iter = |this: &Self, mut index: usize| {
-                        let mut last_offset = parse_u16(
-                            this.slice(INTERNAL_HEADER_SIZE + key_interval * index, key_interval),
-                        ) as usize;
+                        let mut last_offset =
+                            this.get_offset(INTERNAL_HEADER_SIZE + key_interval * index);
                         move |this: &Self| {
                             let offset = if index == 0 {
-                                PAGE_SIZE as usize
+                                page_size
                             } else {
                                 index -= 1;
-                                parse_u16(this.slice(
-                                    INTERNAL_HEADER_SIZE + key_interval * index,
-                                    key_interval,
-                                )) as usize
+                                this.get_offset(INTERNAL_HEADER_SIZE + key_interval * index)
                             };
                             let range = last_offset..offset - value_size;
                             last_offset = offset;
@@ -373,7 +377,7 @@ impl BTreeNode<Key, Value> for Page {
                     };
 
                     let offset = INTERNAL_HEADER_SIZE + key_interval * (pivot_index - 1);
-                    let key_index_start = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let key_index_start = self.get_offset(offset);
 
                     // move key_indexs
                     new_page[INTERNAL_HEADER_SIZE
@@ -384,20 +388,19 @@ impl BTreeNode<Key, Value> for Page {
                         );
 
                     let offset = INTERNAL_HEADER_SIZE + key_interval * (size - 2);
-                    let last_value_index = parse_u16(&self[offset..offset + INDEX_SIZE]) as usize;
+                    let last_value_index = self.get_offset(offset);
 
                     // move values
                     let values_to_move_size = key_index_start - (last_value_index - value_size);
-                    new_page[PAGE_SIZE as usize - values_to_move_size..PAGE_SIZE as usize]
+                    new_page[page_size - values_to_move_size..page_size]
                         .copy_from_slice(&self[last_value_index - value_size..key_index_start]);
 
                     // recalculate value_index
-                    let value_index_diff = PAGE_SIZE as u16 - key_index_start as u16;
+                    let value_index_diff = page_size - key_index_start;
                     for i in 0..(size - pivot_index - 1) {
-                        let slice =
-                            new_page.slice_mut(INTERNAL_HEADER_SIZE + key_interval * i, INDEX_SIZE);
-                        let s = parse_u16(slice);
-                        slice.copy_from_slice(&(s + value_index_diff).to_le_bytes());
+                        let offset = INTERNAL_HEADER_SIZE + key_interval * i;
+                        let s = new_page.get_offset(offset);
+                        new_page.set_offset(offset, s + value_index_diff);
                     }
 
                     (pivot_index, pivot_key)
@@ -410,15 +413,36 @@ impl BTreeNode<Key, Value> for Page {
         }
     }
 
+    fn is_underflow(&self, meta: &Self::Meta) -> bool {
+        let page_size = self.len();
+        if self.is_leaf(meta) {
+            self.used_size(meta) < (page_size - LEAF_HEADER_SIZE) / 4
+        } else {
+            self.size(meta) < 2 || self.used_size(meta) < (page_size - INTERNAL_HEADER_SIZE) / 4
+        }
+    }
+
+    fn fill_ratio(&self, meta: &Self::Meta) -> f64 {
+        let page_size = self.len();
+        let capacity = if self.is_leaf(meta) {
+            page_size - LEAF_HEADER_SIZE
+        } else {
+            page_size - INTERNAL_HEADER_SIZE
+        };
+        self.used_size(meta) as f64 / capacity as f64
+    }
+
     fn insert_node(&mut self, meta: &Self::Meta, key: &Key, node_i: usize) -> bool {
+        let page_size = self.len();
+        let index_size = self.index_size();
         let size = self.size(meta);
         let value_size = 4;
         match meta.key_size {
             Some(key_size) => {
                 // check if insertable
                 let insert_data_size = key_size + value_size;
-                let remain_data_size = PAGE_SIZE as usize
-                    - (INTERNAL_HEADER_SIZE + key_size * (size - 1) + value_size * size);
+                let remain_data_size =
+                    page_size - (INTERNAL_HEADER_SIZE + key_size * (size - 1) + value_size * size);
                 if insert_data_size > remain_data_size {
                     return false;
                 }
@@ -440,8 +464,8 @@ impl BTreeNode<Key, Value> for Page {
                     key_offset..INTERNAL_HEADER_SIZE + key_size * (size - 1),
                     key_offset + key_size,
                 );
-                let values_end = PAGE_SIZE as usize - value_size * size;
-                let value_offset = PAGE_SIZE as usize - value_size * (insert_index + 1);
+                let values_end = page_size - value_size * size;
+                let value_offset = page_size - value_size * (insert_index + 1);
                 self.copy_within(values_end..value_offset, values_end - value_size);
 
                 // insert
@@ -451,24 +475,21 @@ impl BTreeNode<Key, Value> for Page {
             }
             None => {
                 // check if insertable
-                let last_key_index = parse_u16(
-                    &self.slice(INTERNAL_HEADER_SIZE + INDEX_SIZE * (size - 2), INDEX_SIZE),
-                ) as usize;
+                let last_key_index =
+                    self.get_offset(INTERNAL_HEADER_SIZE + index_size * (size - 2));
 
-                let insert_data_size = key.len() + value_size + INDEX_SIZE;
+                let insert_data_size = key.len() + value_size + index_size;
                 let remain_data_size =
-                    last_key_index - value_size - (INTERNAL_HEADER_SIZE + INDEX_SIZE * (size - 1));
+                    last_key_index - value_size - (INTERNAL_HEADER_SIZE + index_size * (size - 1));
                 if insert_data_size > remain_data_size {
                     return false;
                 }
 
                 // find insert index
-                let mut last_offset = PAGE_SIZE as usize;
+                let mut last_offset = page_size;
                 let mut insert_index = size - 1;
                 for i in 0..size - 1 {
-                    let offset =
-                        parse_u16(self.slice(INTERNAL_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE))
-                            as usize;
+                    let offset = self.get_offset(INTERNAL_HEADER_SIZE + index_size * i);
                     let k = &self[offset..last_offset - value_size];
                     if key.as_slice() < k {
                         insert_index = i;
@@ -479,19 +500,16 @@ impl BTreeNode<Key, Value> for Page {
 
                 // recalculate key_index
                 for i in (insert_index..size - 1).rev() {
-                    let slice_from =
-                        self.slice_mut(INTERNAL_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE);
-                    let key_index = parse_u16(slice_from);
-                    let slice_to =
-                        self.slice_mut(INTERNAL_HEADER_SIZE + INDEX_SIZE * (i + 1), INDEX_SIZE);
-                    slice_to.copy_from_slice(
-                        &(key_index - (key.len() + value_size) as u16).to_le_bytes(),
-                    )
-                }
-                self.slice_mut(INTERNAL_HEADER_SIZE + INDEX_SIZE * insert_index, INDEX_SIZE)
-                    .copy_from_slice(
-                        &((last_offset - (key.len() + value_size)) as u16).to_le_bytes(),
+                    let key_index = self.get_offset(INTERNAL_HEADER_SIZE + index_size * i);
+                    self.set_offset(
+                        INTERNAL_HEADER_SIZE + index_size * (i + 1),
+                        key_index - (key.len() + value_size),
                     );
+                }
+                self.set_offset(
+                    INTERNAL_HEADER_SIZE + index_size * insert_index,
+                    last_offset - (key.len() + value_size),
+                );
 
                 // move forward keys and values
                 self.copy_within(
@@ -511,72 +529,43 @@ impl BTreeNode<Key, Value> for Page {
     }
 
     fn get_child(&self, meta: &Self::Meta, key: &Key) -> usize {
-        let size = self.size(meta);
-        debug_assert!(0 < size);
+        self.find_child(meta, key, false)
+    }
 
-        let value_size = 4;
-        match meta.key_size {
-            Some(key_size) => {
-                // TODO: binary search
-                let mut index = size;
-                for i in 0..size - 1 {
-                    let offset = INTERNAL_HEADER_SIZE + key_size * i;
-                    let k = &self[offset..offset + key_size];
-                    if key.as_slice() < k {
-                        index = i + 1;
-                        break;
-                    }
-                }
-                let offset = PAGE_SIZE as usize - value_size * index;
-                parse_u32(&self[offset..offset + value_size]) as usize
-            }
-            None => {
-                let mut last_offset = PAGE_SIZE as usize;
-                for i in 0..(size - 1) {
-                    let offset =
-                        parse_u16(self.slice(INTERNAL_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE))
-                            as usize;
-                    let k = &self[offset..last_offset - value_size];
-                    if key.as_slice() < k {
-                        break;
-                    }
-                    last_offset = offset;
-                }
-                parse_u32(self.slice(last_offset - value_size, value_size)) as usize
-            }
-        }
+    fn get_lower_child(&self, meta: &Self::Meta, key: &Key) -> usize {
+        self.find_child(meta, key, true)
     }
 
     fn get_first_child(&self, meta: &Self::Meta) -> usize {
+        let page_size = self.len();
         let value_size = 4;
         match meta.key_size {
             Some(_) | None => {
-                return parse_u32(&self.slice(PAGE_SIZE as usize - value_size, value_size))
-                    as usize;
+                return parse_u32(self.slice(page_size - value_size, value_size)) as usize;
             }
         }
     }
 
     fn get_children(&self, meta: &Self::Meta) -> Vec<usize> {
+        let page_size = self.len();
+        let index_size = self.index_size();
         let size = self.size(meta);
         let value_size = 4;
         match meta.key_size {
             Some(_) => {
                 let mut node_is = Vec::with_capacity(size);
                 for i in 0..size {
-                    let offset = PAGE_SIZE as usize - value_size * (i + 1);
+                    let offset = page_size - value_size * (i + 1);
                     node_is.push(parse_u32(self.slice(offset, value_size)) as usize);
                 }
                 node_is
             }
             None => {
                 let mut node_is = Vec::with_capacity(size);
-                node_is.push(
-                    parse_u32(self.slice(PAGE_SIZE as usize - value_size, value_size)) as usize,
-                );
+                node_is.push(parse_u32(self.slice(page_size - value_size, value_size)) as usize);
                 for i in 0..(size - 1) {
-                    let offset = INTERNAL_HEADER_SIZE + INDEX_SIZE * i;
-                    let key_index = parse_u16(self.slice(offset, INDEX_SIZE)) as usize;
+                    let offset = INTERNAL_HEADER_SIZE + index_size * i;
+                    let key_index = self.get_offset(offset);
                     node_is
                         .push(parse_u32(self.slice(key_index - value_size, value_size)) as usize);
                 }
@@ -585,11 +574,12 @@ impl BTreeNode<Key, Value> for Page {
         }
     }
 
-    fn new_internal(_: &Self::Meta) -> Self {
-        [0; PAGE_SIZE as usize].into()
+    fn new_internal(meta: &Self::Meta) -> Self {
+        Page::new(meta.page_size)
     }
 
     fn init_as_root_internal(&mut self, meta: &Self::Meta, key: &Key, i1: usize, i2: usize) {
+        let page_size = self.len();
         self[0] = 0;
         self.set_parent(0);
         self.set_size(2);
@@ -600,19 +590,18 @@ impl BTreeNode<Key, Value> for Page {
             Some(key_size) => {
                 self.slice_mut(INTERNAL_HEADER_SIZE, key_size)
                     .copy_from_slice(key);
-                self.slice_mut(PAGE_SIZE as usize - value_size, value_size)
+                self.slice_mut(page_size - value_size, value_size)
                     .copy_from_slice(&(i1 as u32).to_le_bytes());
-                self.slice_mut(PAGE_SIZE as usize - value_size * 2, value_size)
+                self.slice_mut(page_size - value_size * 2, value_size)
                     .copy_from_slice(&(i2 as u32).to_le_bytes());
             }
             None => {
-                let key_index = PAGE_SIZE as usize - value_size - key.len();
+                let key_index = page_size - value_size - key.len();
 
-                self.slice_mut(INTERNAL_HEADER_SIZE, INDEX_SIZE)
-                    .copy_from_slice(&(key_index as u16).to_le_bytes());
+                self.set_offset(INTERNAL_HEADER_SIZE, key_index);
 
                 self.slice_mut(key_index, key.len()).copy_from_slice(key);
-                self.slice_mut(PAGE_SIZE as usize - value_size, value_size)
+                self.slice_mut(page_size - value_size, value_size)
                     .copy_from_slice(&(i1 as u32).to_le_bytes());
                 self.slice_mut(key_index - value_size, value_size)
                     .copy_from_slice(&(i2 as u32).to_le_bytes());
@@ -620,12 +609,74 @@ impl BTreeNode<Key, Value> for Page {
         }
     }
 
+    fn get_internal_entries(&self, meta: &Self::Meta) -> (usize, Vec<(Key, usize)>) {
+        let page_size = self.len();
+        let index_size = self.index_size();
+        let size = self.size(meta);
+        let value_size = 4;
+        let children = self.get_children(meta);
+        let keys: Vec<_> = match meta.key_size {
+            Some(key_size) => (0..size - 1)
+                .map(|i| {
+                    self.slice(INTERNAL_HEADER_SIZE + key_size * i, key_size)
+                        .to_vec()
+                })
+                .collect(),
+            None => {
+                let mut last_offset = page_size;
+                (0..size - 1)
+                    .map(|i| {
+                        let offset = self.get_offset(INTERNAL_HEADER_SIZE + index_size * i);
+                        let key = self[offset..last_offset - value_size].to_vec();
+                        last_offset = offset;
+                        key
+                    })
+                    .collect()
+            }
+        };
+        (
+            children[0],
+            keys.into_iter()
+                .zip(children[1..].iter().cloned())
+                .collect(),
+        )
+    }
+
+    fn set_internal_entries(
+        &mut self,
+        meta: &Self::Meta,
+        first_child: usize,
+        entries: &[(Key, usize)],
+    ) -> bool {
+        let page_size = self.len();
+        let mut page = Page::new(self.len());
+        if let Some((key, child)) = entries.first() {
+            page.init_as_root_internal(meta, key, first_child, *child);
+            for (key, child) in &entries[1..] {
+                if !page.insert_node(meta, key, *child) {
+                    return false;
+                }
+            }
+        } else {
+            let value_size = 4;
+            page.set_size(1);
+            page.slice_mut(page_size - value_size, value_size)
+                .copy_from_slice(&(first_child as u32).to_le_bytes());
+        }
+        page[1..1 + 4].copy_from_slice(&self[1..1 + 4]);
+        *self = page;
+        true
+    }
+
     fn insert_value(&mut self, meta: &Self::Meta, key: &Key, value: &Value) -> bool {
+        let page_size = self.len();
+        let index_size = self.index_size();
         let size = self.size(meta);
         match meta {
             Meta {
                 key_size: Some(key_size),
                 value_size: Some(value_size),
+                ..
             } => {
                 debug_assert_eq!(*key_size, key.len());
                 debug_assert_eq!(*value_size, value.len());
@@ -633,7 +684,7 @@ impl BTreeNode<Key, Value> for Page {
                 // check if insertable
                 let insert_data_size = *key_size + *value_size;
                 let remain_data_size =
-                    PAGE_SIZE as usize - (LEAF_HEADER_SIZE + (key_size + value_size) * size);
+                    page_size - (LEAF_HEADER_SIZE + (key_size + value_size) * size);
                 if insert_data_size > remain_data_size {
                     return false;
                 }
@@ -655,8 +706,8 @@ impl BTreeNode<Key, Value> for Page {
                     key_offset..LEAF_HEADER_SIZE + key_size * size,
                     key_offset + key_size,
                 );
-                let values_end = PAGE_SIZE as usize - value_size * size;
-                let value_offset = PAGE_SIZE as usize - value_size * insert_index;
+                let values_end = page_size - value_size * size;
+                let value_offset = page_size - value_size * insert_index;
                 self.copy_within(values_end..value_offset, values_end - value_size);
 
                 // insert
@@ -666,19 +717,19 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: Some(key_size),
                 value_size: None,
+                ..
             } => {
                 debug_assert_eq!(*key_size, key.len());
 
-                let key_interval = key_size + INDEX_SIZE;
+                let key_interval = key_size + index_size;
 
                 // check if insertable
                 let last_value_index = if size == 0 {
-                    PAGE_SIZE as usize
+                    page_size
                 } else {
                     let last_value_index_index =
-                        LEAF_HEADER_SIZE + key_interval * size - INDEX_SIZE;
-                    parse_u16(&self[last_value_index_index..last_value_index_index + INDEX_SIZE])
-                        as usize
+                        LEAF_HEADER_SIZE + key_interval * size - index_size;
+                    self.get_offset(last_value_index_index)
                 };
                 let insert_data_size = key_interval + value.len();
                 let remain_data_size = last_value_index - (LEAF_HEADER_SIZE + key_interval * size);
@@ -704,30 +755,25 @@ impl BTreeNode<Key, Value> for Page {
                     key_offset + key_interval,
                 );
                 let end = if insert_index == 0 {
-                    PAGE_SIZE as usize
+                    page_size
                 } else {
-                    parse_u16(&self[key_offset - INDEX_SIZE..key_offset]) as usize
+                    self.get_offset(key_offset - index_size)
                 };
                 self.copy_within(last_value_index..end, last_value_index - value.len());
 
                 // recalculate value_index
                 for i in (insert_index..size).rev() {
                     let offset = LEAF_HEADER_SIZE + key_interval * (i + 1) + key_size;
-                    let s = parse_u16(&self[offset..offset + INDEX_SIZE]);
-                    self[offset..offset + INDEX_SIZE]
-                        .copy_from_slice(&(s - value.len() as u16).to_le_bytes());
+                    let s = self.get_offset(offset);
+                    self.set_offset(offset, s - value.len());
                 }
                 let value_index_offset = LEAF_HEADER_SIZE + key_interval * insert_index + key_size;
                 let s = if insert_index == 0 {
-                    PAGE_SIZE as u16
+                    page_size
                 } else {
-                    parse_u16(
-                        &self[value_index_offset - key_interval
-                            ..value_index_offset - key_interval + INDEX_SIZE],
-                    )
+                    self.get_offset(value_index_offset - key_interval)
                 };
-                self[value_index_offset..value_index_offset + INDEX_SIZE]
-                    .copy_from_slice(&(s - value.len() as u16).to_le_bytes());
+                self.set_offset(value_index_offset, s - value.len());
 
                 // insert
                 self[key_offset..key_offset + key_size].copy_from_slice(key);
@@ -736,28 +782,26 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: None,
                 value_size: Some(value_size),
+                ..
             } => {
                 // check if insertable
                 let last_value_offset = if size == 0 {
-                    PAGE_SIZE as usize
+                    page_size
                 } else {
-                    parse_u16(&self.slice(LEAF_HEADER_SIZE + INDEX_SIZE * (size - 1), INDEX_SIZE))
-                        as usize
+                    self.get_offset(LEAF_HEADER_SIZE + index_size * (size - 1))
                 };
 
-                let insert_data_size = INDEX_SIZE + key.len() + *value_size;
-                let remain_data_size = last_value_offset - (LEAF_HEADER_SIZE + INDEX_SIZE * size);
+                let insert_data_size = index_size + key.len() + *value_size;
+                let remain_data_size = last_value_offset - (LEAF_HEADER_SIZE + index_size * size);
                 if insert_data_size > remain_data_size {
                     return false;
                 }
 
                 // find insert index
-                let mut insert_offset = PAGE_SIZE as usize;
+                let mut insert_offset = page_size;
                 let mut insert_index = size;
                 for i in 0..size {
-                    let offset =
-                        parse_u16(self.slice(LEAF_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE))
-                            as usize;
+                    let offset = self.get_offset(LEAF_HEADER_SIZE + index_size * i);
                     let k = &self[offset..insert_offset - value_size];
                     if key.as_slice() < k {
                         insert_index = i;
@@ -768,18 +812,16 @@ impl BTreeNode<Key, Value> for Page {
 
                 // recalculate key_index
                 for i in (insert_index..size).rev() {
-                    let slice_from = self.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE);
-                    let key_index = parse_u16(slice_from);
-                    let slice_to =
-                        self.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * (i + 1), INDEX_SIZE);
-                    slice_to.copy_from_slice(
-                        &(key_index - (key.len() + *value_size) as u16).to_le_bytes(),
-                    )
-                }
-                self.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * insert_index, INDEX_SIZE)
-                    .copy_from_slice(
-                        &((insert_offset - value_size - key.len()) as u16).to_le_bytes(),
+                    let key_index = self.get_offset(LEAF_HEADER_SIZE + index_size * i);
+                    self.set_offset(
+                        LEAF_HEADER_SIZE + index_size * (i + 1),
+                        key_index - (key.len() + *value_size),
                     );
+                }
+                self.set_offset(
+                    LEAF_HEADER_SIZE + index_size * insert_index,
+                    insert_offset - value_size - key.len(),
+                );
 
                 // move forward keys and values
                 self.copy_within(
@@ -795,32 +837,29 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: None,
                 value_size: None,
+                ..
             } => {
-                let key_interval = INDEX_SIZE * 2;
+                let key_interval = index_size * 2;
                 // check if insertable
                 let last_value_offset = if size == 0 {
-                    PAGE_SIZE as usize
+                    page_size
                 } else {
-                    parse_u16(&self.slice(LEAF_HEADER_SIZE + key_interval * (size - 1), INDEX_SIZE))
-                        as usize
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (size - 1))
                 };
 
-                let insert_data_size = INDEX_SIZE * 2 + key.len() + value.len();
+                let insert_data_size = index_size * 2 + key.len() + value.len();
                 let remain_data_size = last_value_offset - (LEAF_HEADER_SIZE + key_interval * size);
                 if insert_data_size > remain_data_size {
                     return false;
                 }
 
                 // find insert index
-                let mut insert_offset = PAGE_SIZE as usize;
+                let mut insert_offset = page_size;
                 let mut insert_index = size;
                 for i in 0..size {
-                    let key_offset =
-                        parse_u16(self.slice(LEAF_HEADER_SIZE + key_interval * i, INDEX_SIZE))
-                            as usize;
-                    let value_offset = parse_u16(
-                        self.slice(LEAF_HEADER_SIZE + key_interval * i + INDEX_SIZE, INDEX_SIZE),
-                    ) as usize;
+                    let key_offset = self.get_offset(LEAF_HEADER_SIZE + key_interval * i);
+                    let value_offset =
+                        self.get_offset(LEAF_HEADER_SIZE + key_interval * i + index_size);
                     let k = &self[key_offset..value_offset];
                     if key.as_slice() < k {
                         insert_index = i;
@@ -831,23 +870,20 @@ impl BTreeNode<Key, Value> for Page {
 
                 // recalculate key_index
                 for i in (insert_index * 2..size * 2).rev() {
-                    let slice_from = self.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE);
-                    let key_index = parse_u16(slice_from);
-                    let slice_to =
-                        self.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * (i + 2), INDEX_SIZE);
-                    slice_to.copy_from_slice(
-                        &(key_index - (key.len() + value.len()) as u16).to_le_bytes(),
-                    )
-                }
-                self.slice_mut(LEAF_HEADER_SIZE + key_interval * insert_index, INDEX_SIZE)
-                    .copy_from_slice(
-                        &((insert_offset - value.len() - key.len()) as u16).to_le_bytes(),
+                    let key_index = self.get_offset(LEAF_HEADER_SIZE + index_size * i);
+                    self.set_offset(
+                        LEAF_HEADER_SIZE + index_size * (i + 2),
+                        key_index - (key.len() + value.len()),
                     );
-                self.slice_mut(
-                    LEAF_HEADER_SIZE + key_interval * insert_index + INDEX_SIZE,
-                    INDEX_SIZE,
-                )
-                .copy_from_slice(&((insert_offset - value.len()) as u16).to_le_bytes());
+                }
+                self.set_offset(
+                    LEAF_HEADER_SIZE + key_interval * insert_index,
+                    insert_offset - value.len() - key.len(),
+                );
+                self.set_offset(
+                    LEAF_HEADER_SIZE + key_interval * insert_index + index_size,
+                    insert_offset - value.len(),
+                );
 
                 // move forward keys and values
                 self.copy_within(
@@ -880,11 +916,14 @@ impl BTreeNode<Key, Value> for Page {
     }
 
     fn find_cursor(&self, meta: &Self::Meta, key: &Key) -> (usize, bool) {
+        let page_size = self.len();
+        let index_size = self.index_size();
         let size = self.size(meta);
         match meta {
             Meta {
                 key_size: Some(key_size),
                 value_size: Some(_),
+                ..
             } => {
                 for i in 0..size {
                     let offset = LEAF_HEADER_SIZE + key_size * i;
@@ -900,9 +939,10 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: Some(key_size),
                 value_size: None,
+                ..
             } => {
                 for i in 0..size {
-                    let offset = LEAF_HEADER_SIZE + (key_size + INDEX_SIZE) * i;
+                    let offset = LEAF_HEADER_SIZE + (key_size + index_size) * i;
                     let k = &self[offset..offset + key_size];
                     match key.as_slice().cmp(k) {
                         std::cmp::Ordering::Less => return (i, false),
@@ -915,12 +955,11 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: None,
                 value_size: Some(value_size),
+                ..
             } => {
-                let mut last_offset = PAGE_SIZE as usize;
+                let mut last_offset = page_size;
                 for i in 0..size {
-                    let offset =
-                        parse_u16(self.slice(LEAF_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE))
-                            as usize;
+                    let offset = self.get_offset(LEAF_HEADER_SIZE + index_size * i);
                     let k = &self[offset..last_offset - value_size];
                     match key.as_slice().cmp(k) {
                         std::cmp::Ordering::Less => return (i, false),
@@ -934,15 +973,13 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: None,
                 value_size: None,
+                ..
             } => {
-                let key_intarval = INDEX_SIZE * 2;
+                let key_intarval = index_size * 2;
                 for i in 0..size {
-                    let key_index =
-                        parse_u16(self.slice(LEAF_HEADER_SIZE + key_intarval * i, INDEX_SIZE))
-                            as usize;
-                    let value_index = parse_u16(
-                        self.slice(LEAF_HEADER_SIZE + key_intarval * i + INDEX_SIZE, INDEX_SIZE),
-                    ) as usize;
+                    let key_index = self.get_offset(LEAF_HEADER_SIZE + key_intarval * i);
+                    let value_index =
+                        self.get_offset(LEAF_HEADER_SIZE + key_intarval * i + index_size);
                     let k = &self[key_index..value_index];
                     match key.as_slice().cmp(k) {
                         std::cmp::Ordering::Less => return (i, false),
@@ -960,14 +997,17 @@ impl BTreeNode<Key, Value> for Page {
     }
 
     fn cursor_get(&self, meta: &Self::Meta, cursor: usize) -> Option<(Key, Value)> {
+        let page_size = self.len();
+        let index_size = self.index_size();
         match meta {
             Meta {
                 key_size: Some(key_size),
                 value_size: Some(value_size),
+                ..
             } => {
                 let key_index = LEAF_HEADER_SIZE + key_size * cursor;
                 let key = self.slice(key_index, *key_size).to_vec();
-                let value_index = PAGE_SIZE as usize - value_size * (cursor + 1);
+                let value_index = page_size - value_size * (cursor + 1);
                 let value = self.slice(value_index, *value_size).to_vec();
 
                 Some((key, value))
@@ -975,17 +1015,17 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: Some(key_size),
                 value_size: None,
+                ..
             } => {
-                let key_interval = key_size + INDEX_SIZE;
+                let key_interval = key_size + index_size;
                 let value_index_index = LEAF_HEADER_SIZE + key_interval * cursor + key_size;
-                let value_start =
-                    parse_u16(&self[value_index_index..value_index_index + INDEX_SIZE]) as usize;
+                let value_start = self.get_offset(value_index_index);
                 let value_end = if cursor == 0 {
-                    PAGE_SIZE as usize
+                    page_size
                 } else {
                     let value_index_index =
                         LEAF_HEADER_SIZE + key_interval * (cursor - 1) + key_size;
-                    parse_u16(&self[value_index_index..value_index_index + INDEX_SIZE]) as usize
+                    self.get_offset(value_index_index)
                 };
                 let key_index = LEAF_HEADER_SIZE + key_interval * cursor;
                 let key = self.slice(key_index, *key_size).to_vec();
@@ -996,16 +1036,14 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: None,
                 value_size: Some(value_size),
+                ..
             } => {
                 let end_offset = if cursor == 0 {
-                    PAGE_SIZE as usize
+                    page_size
                 } else {
-                    parse_u16(self.slice(LEAF_HEADER_SIZE + INDEX_SIZE * (cursor - 1), INDEX_SIZE))
-                        as usize
+                    self.get_offset(LEAF_HEADER_SIZE + index_size * (cursor - 1))
                 };
-                let start_offset =
-                    parse_u16(self.slice(LEAF_HEADER_SIZE + INDEX_SIZE * cursor, INDEX_SIZE))
-                        as usize;
+                let start_offset = self.get_offset(LEAF_HEADER_SIZE + index_size * cursor);
 
                 let key = self[start_offset..end_offset - value_size].to_vec();
                 let value = self.slice(end_offset - value_size, *value_size).to_vec();
@@ -1015,22 +1053,17 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: None,
                 value_size: None,
+                ..
             } => {
-                let key_interval = INDEX_SIZE * 2;
+                let key_interval = index_size * 2;
                 let key_index1 = if cursor == 0 {
-                    PAGE_SIZE as usize
+                    page_size
                 } else {
-                    parse_u16(
-                        self.slice(LEAF_HEADER_SIZE + key_interval * (cursor - 1), INDEX_SIZE),
-                    ) as usize
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (cursor - 1))
                 };
-                let key_index2 =
-                    parse_u16(self.slice(LEAF_HEADER_SIZE + key_interval * cursor, INDEX_SIZE))
-                        as usize;
-                let value_index = parse_u16(self.slice(
-                    LEAF_HEADER_SIZE + key_interval * cursor + INDEX_SIZE,
-                    INDEX_SIZE,
-                )) as usize;
+                let key_index2 = self.get_offset(LEAF_HEADER_SIZE + key_interval * cursor);
+                let value_index =
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * cursor + index_size);
 
                 let key = self[key_index2..value_index].to_vec();
                 let value = self[value_index..key_index1].to_vec();
@@ -1041,6 +1074,8 @@ impl BTreeNode<Key, Value> for Page {
     }
 
     fn cursor_delete(&mut self, meta: &Self::Meta, cursor: usize) -> bool {
+        let page_size = self.len();
+        let index_size = self.index_size();
         let size = self.size(meta);
         debug_assert!(0 < size);
         debug_assert!(cursor < size);
@@ -1048,12 +1083,12 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: Some(key_size),
                 value_size: Some(value_size),
+                ..
             } => {
                 // move forward keys and values
                 self.copy_within(
-                    PAGE_SIZE as usize - value_size * size
-                        ..PAGE_SIZE as usize - value_size * (cursor + 1),
-                    PAGE_SIZE as usize - value_size * size + value_size,
+                    page_size - value_size * size..page_size - value_size * (cursor + 1),
+                    page_size - value_size * size + value_size,
                 );
                 self.copy_within(
                     LEAF_HEADER_SIZE + key_size * (cursor + 1)..LEAF_HEADER_SIZE + key_size * size,
@@ -1063,30 +1098,24 @@ impl BTreeNode<Key, Value> for Page {
             Meta {
                 key_size: Some(key_size),
                 value_size: None,
+                ..
             } => {
-                let key_interval = key_size + INDEX_SIZE;
+                let key_interval = key_size + index_size;
 
                 let value_index1 = if cursor == 0 {
-                    PAGE_SIZE as u16
+                    page_size
                 } else {
-                    parse_u16(self.slice(
-                        LEAF_HEADER_SIZE + key_interval * (cursor - 1) + key_size,
-                        INDEX_SIZE,
-                    ))
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (cursor - 1) + key_size)
                 };
-                let value_index2 = parse_u16(self.slice(
-                    LEAF_HEADER_SIZE + key_interval * cursor + key_size,
-                    INDEX_SIZE,
-                ));
-                let last_value_index = parse_u16(self.slice(
-                    LEAF_HEADER_SIZE + key_interval * (size - 1) + key_size,
-                    INDEX_SIZE,
-                ));
+                let value_index2 =
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * cursor + key_size);
+                let last_value_index =
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (size - 1) + key_size);
 
                 // move forward keys and values
                 self.copy_within(
-                    last_value_index as usize..value_index2 as usize,
-                    (last_value_index + (value_index1 - value_index2)) as usize,
+                    last_value_index..value_index2,
+                    last_value_index + (value_index1 - value_index2),
                 );
                 self.copy_within(
                     LEAF_HEADER_SIZE + key_interval * (cursor + 1)
@@ -1097,82 +1126,72 @@ impl BTreeNode<Key, Value> for Page {
                 // recalculate value_index
                 for i in cursor..size - 1 {
                     let offset = LEAF_HEADER_SIZE + key_interval * i + key_size;
-                    let slice = self.slice_mut(offset, INDEX_SIZE);
-                    let value_index = parse_u16(slice);
-                    slice.copy_from_slice(
-                        &(value_index + (value_index1 - value_index2)).to_le_bytes(),
-                    )
+                    let value_index = self.get_offset(offset);
+                    self.set_offset(offset, value_index + (value_index1 - value_index2));
                 }
             }
             Meta {
                 key_size: None,
                 value_size: Some(_),
+                ..
             } => {
-                let key_interval = INDEX_SIZE;
+                let key_interval = index_size;
 
-                let last_value_offset = parse_u16(
-                    &self.slice(LEAF_HEADER_SIZE + key_interval * (size - 1), INDEX_SIZE),
-                ) as usize;
+                let last_value_offset =
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (size - 1));
 
                 let key_index1 = if cursor == 0 {
-                    PAGE_SIZE as u16
+                    page_size
                 } else {
-                    parse_u16(
-                        self.slice(LEAF_HEADER_SIZE + key_interval * (cursor - 1), INDEX_SIZE),
-                    )
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (cursor - 1))
                 };
-                let key_index2 =
-                    parse_u16(self.slice(LEAF_HEADER_SIZE + key_interval * cursor, INDEX_SIZE));
+                let key_index2 = self.get_offset(LEAF_HEADER_SIZE + key_interval * cursor);
 
                 // recalculate key_index
                 for i in (cursor + 1)..size {
-                    let slice_from =
-                        self.slice_mut(LEAF_HEADER_SIZE + key_interval * i, INDEX_SIZE);
-                    let key_index = parse_u16(slice_from);
-                    let slice_to =
-                        self.slice_mut(LEAF_HEADER_SIZE + key_interval * (i - 1), INDEX_SIZE);
-                    slice_to.copy_from_slice(&(key_index + (key_index1 - key_index2)).to_le_bytes())
+                    let key_index = self.get_offset(LEAF_HEADER_SIZE + key_interval * i);
+                    self.set_offset(
+                        LEAF_HEADER_SIZE + key_interval * (i - 1),
+                        key_index + (key_index1 - key_index2),
+                    );
                 }
 
                 // move forward keys and values
                 self.copy_within(
-                    last_value_offset..key_index2 as usize,
-                    last_value_offset + (key_index1 - key_index2) as usize,
+                    last_value_offset..key_index2,
+                    last_value_offset + (key_index1 - key_index2),
                 );
             }
             Meta {
                 key_size: None,
                 value_size: None,
+                ..
             } => {
-                let key_interval = INDEX_SIZE * 2;
+                let key_interval = index_size * 2;
 
-                let last_value_offset = parse_u16(
-                    &self.slice(LEAF_HEADER_SIZE + key_interval * (size - 1), INDEX_SIZE),
-                ) as usize;
+                let last_value_offset =
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (size - 1));
 
                 let key_index1 = if cursor == 0 {
-                    PAGE_SIZE as u16
+                    page_size
                 } else {
-                    parse_u16(
-                        self.slice(LEAF_HEADER_SIZE + key_interval * (cursor - 1), INDEX_SIZE),
-                    )
+                    self.get_offset(LEAF_HEADER_SIZE + key_interval * (cursor - 1))
                 };
-                let key_index2 =
-                    parse_u16(self.slice(LEAF_HEADER_SIZE + key_interval * cursor, INDEX_SIZE));
+                let key_index2 = self.get_offset(LEAF_HEADER_SIZE + key_interval * cursor);
 
                 // recalculate key_index
                 for i in (cursor + 1) * 2..size * 2 {
-                    let slice_from = self.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * i, INDEX_SIZE);
-                    let key_index = parse_u16(slice_from);
-                    let slice_to =
-                        self.slice_mut(LEAF_HEADER_SIZE + INDEX_SIZE * (i - 2), INDEX_SIZE);
-                    slice_to.copy_from_slice(&(key_index + (key_index1 - key_index2)).to_le_bytes())
+                    let key_index = self.get_offset(LEAF_HEADER_SIZE + index_size * i);
+                    self.set_offset(
+                        LEAF_HEADER_SIZE + index_size * (i - 2),
+                        key_index + (key_index1 - key_index2),
+                    );
                 }
 
                 // move forward keys and values
                 self.copy_within(
-                    last_value_offset..key_index2 as usize,
-                    last_value_offset + (key_index1 - key_index2) as usize,
+                    last_value_offset..key_index2,
+                    last_value_offset + (key_index1 - key_index2),
                 );
             }
         };
@@ -1180,14 +1199,166 @@ impl BTreeNode<Key, Value> for Page {
         self.set_size(size - 1);
         true
     }
+
+    fn new_leaf(meta: &Self::Meta) -> Self {
+        Page::new_leaf(meta.page_size)
+    }
+
+    fn set_leaf_entries(&mut self, meta: &Self::Meta, entries: &[(Key, Value)]) -> bool {
+        let mut page = Page::new_leaf(self.len());
+        for (key, value) in entries {
+            if !page.insert_value(meta, key, value) {
+                return false;
+            }
+        }
+        // parent と next はそのまま残す
+        page[1..1 + 4].copy_from_slice(&self[1..1 + 4]);
+        page[1 + 4 + 2..1 + 4 + 2 + 4].copy_from_slice(&self[1 + 4 + 2..1 + 4 + 2 + 4]);
+        *self = page;
+        true
+    }
 }
 
-impl BTree<Key, Value> for Pager<Page> {
+impl Page {
+    // ページ内のオフセットの幅
+    fn index_size(&self) -> usize {
+        index_size(self.len())
+    }
+
+    fn get_offset(&self, at: usize) -> usize {
+        match self.index_size() {
+            2 => parse_u16(self.slice(at, 2)) as usize,
+            _ => parse_u32(self.slice(at, 4)) as usize,
+        }
+    }
+
+    fn set_offset(&mut self, at: usize, offset: usize) {
+        match self.index_size() {
+            2 => self.write(at, &(offset as u16).to_le_bytes()),
+            _ => self.write(at, &(offset as u32).to_le_bytes()),
+        }
+    }
+
+    // or_equal なら key と同じキーの手前の子を返す
+    fn find_child(&self, meta: &Meta, key: &Key, or_equal: bool) -> usize {
+        let page_size = self.len();
+        let index_size = self.index_size();
+        let size = self.size(meta);
+        debug_assert!(0 < size);
+
+        let value_size = 4;
+        match meta.key_size {
+            Some(key_size) => {
+                // TODO: binary search
+                let mut index = size;
+                for i in 0..size - 1 {
+                    let offset = INTERNAL_HEADER_SIZE + key_size * i;
+                    let k = &self[offset..offset + key_size];
+                    if key.as_slice() < k || (or_equal && key.as_slice() == k) {
+                        index = i + 1;
+                        break;
+                    }
+                }
+                let offset = page_size - value_size * index;
+                parse_u32(&self[offset..offset + value_size]) as usize
+            }
+            None => {
+                let mut last_offset = page_size;
+                for i in 0..(size - 1) {
+                    let offset = self.get_offset(INTERNAL_HEADER_SIZE + index_size * i);
+                    let k = &self[offset..last_offset - value_size];
+                    if key.as_slice() < k || (or_equal && key.as_slice() == k) {
+                        break;
+                    }
+                    last_offset = offset;
+                }
+                parse_u32(self.slice(last_offset - value_size, value_size)) as usize
+            }
+        }
+    }
+
+    // ヘッダ以外で使われているバイト数
+    fn used_size(&self, meta: &Meta) -> usize {
+        let page_size = self.len();
+        let index_size = self.index_size();
+        let size = self.size(meta);
+        if self.is_leaf(meta) {
+            let (index_end, data_start) = match meta {
+                Meta {
+                    key_size: Some(key_size),
+                    value_size: Some(value_size),
+                    ..
+                } => (
+                    LEAF_HEADER_SIZE + key_size * size,
+                    page_size - value_size * size,
+                ),
+                Meta {
+                    key_size: Some(key_size),
+                    value_size: None,
+                    ..
+                } => {
+                    let index_end = LEAF_HEADER_SIZE + (key_size + index_size) * size;
+                    if size == 0 {
+                        (index_end, page_size)
+                    } else {
+                        (index_end, self.get_offset(index_end - index_size))
+                    }
+                }
+                Meta {
+                    key_size: None,
+                    value_size: _,
+                    ..
+                } => {
+                    let key_interval = if meta.value_size.is_some() {
+                        index_size
+                    } else {
+                        index_size * 2
+                    };
+                    let index_end = LEAF_HEADER_SIZE + key_interval * size;
+                    if size == 0 {
+                        (index_end, page_size)
+                    } else {
+                        (index_end, self.get_offset(index_end - key_interval))
+                    }
+                }
+            };
+            index_end - LEAF_HEADER_SIZE + page_size - data_start
+        } else {
+            let value_size = 4;
+            match meta.key_size {
+                Some(key_size) => key_size * (size - 1) + value_size * size,
+                None => {
+                    let data_start = if size <= 1 {
+                        page_size - value_size
+                    } else {
+                        self.get_offset(INTERNAL_HEADER_SIZE + index_size * (size - 2)) - value_size
+                    };
+                    index_size * (size - 1) + page_size - data_start
+                }
+            }
+        }
+    }
+}
+
+// 書き込みは &Pager から行う
+// 書き込むスレッドは同時に一つだけで、読むスレッドとはページのラッチで排他する
+// 書き換えるときは Pager::lock_writer のガードを持っていなければならない
+// 今のページを読むのは書き込むスレッドだけで、他のスレッドは snapshot の PagerView から読む
+impl BTree<Key, Value> for &Pager<Page> {
     type Node = Page;
+    type NodeRef<'a>
+        = PageRef<'a, Page>
+    where
+        Self: 'a;
+    type NodeMut<'a>
+        = PageMut<'a, Page>
+    where
+        Self: 'a;
 
     fn add_root_node(&mut self) -> usize {
-        let page_i = self.size();
-        let page = self.get_mut(page_i);
+        debug_assert!(self.holds_writer(), "{}", WITHOUT_WRITER);
+        let page_i = self.allocate();
+        let mut page = self.get_mut(page_i);
 
         page[0] = 1;
         page.set_parent(0);
@@ -1197,20 +1368,428 @@ impl BTree<Key, Value> for Pager<Page> {
         page_i
     }
 
-    fn node_ref(&self, node_i: usize) -> &Self::Node {
+    fn node_ref(&self, node_i: usize) -> Self::NodeRef<'_> {
         self.get_ref(node_i)
     }
 
-    fn node_mut(&mut self, node_i: usize) -> &mut Self::Node {
+    fn node_mut(&mut self, node_i: usize) -> Self::NodeMut<'_> {
+        debug_assert!(self.holds_writer(), "{}", WITHOUT_WRITER);
         self.get_mut(node_i)
     }
 
     fn push(&mut self, node: Self::Node) -> usize {
-        self.push(node)
+        debug_assert!(self.holds_writer(), "{}", WITHOUT_WRITER);
+        Pager::push(self, node)
     }
 
     fn swap(&mut self, node_i: usize, node: Self::Node) -> Self::Node {
-        self.swap(node_i, node)
+        debug_assert!(self.holds_writer(), "{}", WITHOUT_WRITER);
+        Pager::swap(self, node_i, node)
+    }
+
+    fn free_node(&mut self, node_i: usize) {
+        debug_assert!(self.holds_writer(), "{}", WITHOUT_WRITER);
+        self.free(node_i);
+    }
+}
+
+const WITHOUT_WRITER: &str = "the tree is written without Pager::lock_writer";
+
+// snapshot の時点のページを読む木
+// snapshot が None なら現在のページを読む
+// そのときは書き込みと同時には読めないので、書き込むスレッドか、書き込みがないときだけ使う
+pub struct PagerView<'a> {
+    pager: &'a Pager<Page>,
+    snapshot: Option<u64>,
+}
+
+impl<'a> BTree<Key, Value> for PagerView<'a> {
+    type Node = Page;
+    type NodeRef<'b>
+        = PageRef<'b, Page>
+    where
+        Self: 'b;
+    type NodeMut<'b>
+        = PageMut<'b, Page>
+    where
+        Self: 'b;
+
+    fn add_root_node(&mut self) -> usize {
+        unreachable!("snapshot is read only")
+    }
+
+    fn node_ref(&self, node_i: usize) -> Self::NodeRef<'_> {
+        self.pager.get_ref_at(node_i, self.snapshot)
+    }
+
+    fn node_mut(&mut self, _node_i: usize) -> Self::NodeMut<'_> {
+        unreachable!("snapshot is read only")
+    }
+
+    fn push(&mut self, _node: Self::Node) -> usize {
+        unreachable!("snapshot is read only")
+    }
+
+    fn swap(&mut self, _node_i: usize, _node: Self::Node) -> Self::Node {
+        unreachable!("snapshot is read only")
+    }
+
+    fn free_node(&mut self, _node_i: usize) {
+        unreachable!("snapshot is read only")
+    }
+}
+
+// # overflow
+// MAX_INLINE_SIZE より長い可変長のキーや値はオーバーフローページの連結リストに置き、
+// ページには stub を置く
+//
+// ## stub
+// [MAX_INLINE_SIZE] 先頭部分
+// [8] 順位 (big endian)
+// [4] 最初のオーバーフローページ
+// [8] 全体の長さ
+//
+// 固定長のものはオーバーフローしない
+// stub は MAX_INLINE_SIZE より長いので、インラインのものと区別できる
+// キーの stub は先頭部分と順位のバイト列で比べて、元のキーの順に並ぶ
+// 順位は先頭部分が同じキーの中での順番で、同じキーには同じ順位を、新しいキーには前後の間の順位を付ける
+// 間が空いていなければ、その先頭部分のキーの順位をすべて付け直す
+// 値の stub の順位は使わない (0)
+//
+// ## overflow page
+// [4] next page
+// [page size - 4] data
+
+pub const MAX_INLINE_SIZE: usize = 256;
+const RANK_SIZE: usize = 8;
+const STUB_SIZE: usize = MAX_INLINE_SIZE + RANK_SIZE + 4 + 8;
+// 端に足すキーの順位の間隔
+const RANK_STEP: u64 = 1 << 32;
+
+// size は Meta の key_size か value_size
+fn is_stub(size: Option<usize>, bytes: &[u8]) -> bool {
+    size.is_none() && MAX_INLINE_SIZE < bytes.len()
+}
+
+impl Pager<Page> {
+    pub fn view(&self, snapshot: Option<u64>) -> PagerView<'_> {
+        PagerView {
+            pager: self,
+            snapshot,
+        }
+    }
+
+    // 長ければオーバーフローページに書き出し、stub を返す
+    fn store_overflow(&self, size: Option<usize>, bytes: &[u8]) -> Vec<u8> {
+        if !is_stub(size, bytes) {
+            return bytes.to_vec();
+        }
+        let mut next_i = 0;
+        for chunk in bytes.chunks(self.page_size() - 4).rev() {
+            let page_i = self.allocate();
+            let mut page = self.get_mut(page_i);
+            page[0..4].copy_from_slice(&(next_i as u32).to_le_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+            next_i = page_i;
+        }
+        let mut stub = Vec::with_capacity(STUB_SIZE);
+        stub.extend(&bytes[..MAX_INLINE_SIZE]);
+        stub.extend([0; RANK_SIZE]);
+        stub.extend((next_i as u32).to_le_bytes());
+        stub.extend((bytes.len() as u64).to_le_bytes());
+        stub
+    }
+
+    fn free_overflow(&self, size: Option<usize>, bytes: &[u8]) {
+        if !is_stub(size, bytes) {
+            return;
+        }
+        let (mut page_i, _) = parse_stub(bytes);
+        while page_i != 0 {
+            let next_i = parse_u32(&self.get_ref(page_i)[0..4]) as usize;
+            self.free(page_i);
+            page_i = next_i;
+        }
+    }
+
+    // 先頭部分が prefix のキーの (stub, 値, 元のキー) を木の順に返す
+    fn prefix_group(&self, meta: &Meta, root_i: usize, prefix: &[u8]) -> Vec<(Key, Value, Key)> {
+        let view = self.view(None);
+        let mut group = vec![];
+        let (mut cursor, _) = self.find(meta, root_i, &prefix.to_vec());
+        while !self.cursor_is_end(meta, &cursor) {
+            let (k, v) = self.cursor_get(meta, &cursor).unwrap();
+            if !k.starts_with(prefix) {
+                break;
+            }
+            // 先頭部分と同じインラインのキーは stub より前にある
+            if is_stub(meta.key_size, &k) {
+                let loaded = view.load_overflow(meta.key_size, k.clone());
+                group.push((k, v, loaded));
+            }
+            cursor = self.cursor_next(meta, cursor);
+        }
+        group
+    }
+
+    // 長いキーの順位を決める
+    fn rank_key(&self, meta: &Meta, root_i: usize, key: &[u8]) -> Result<u64, String> {
+        let group = self.prefix_group(meta, root_i, &key[..MAX_INLINE_SIZE]);
+        let pos = group
+            .iter()
+            .position(|(_, _, k)| key <= k.as_slice())
+            .unwrap_or(group.len());
+        if let Some((stub, _, k)) = group.get(pos) {
+            if k == key {
+                return Ok(parse_rank(stub));
+            }
+        }
+        let lo = pos.checked_sub(1).map(|p| parse_rank(&group[p].0));
+        let hi = group.get(pos).map(|(stub, _, _)| parse_rank(stub));
+        match rank_between(lo, hi) {
+            Some(rank) => Ok(rank),
+            None => self.rerank(meta, root_i, group, pos),
+        }
+    }
+
+    // 先頭部分が同じキーの順位を等間隔に付け直し、pos に入るキーの順位を返す
+    // stub が変わるエントリは消して入れ直す (オーバーフローページはそのまま使う)
+    fn rerank(
+        &self,
+        meta: &Meta,
+        root_i: usize,
+        group: Vec<(Key, Value, Key)>,
+        pos: usize,
+    ) -> Result<u64, String> {
+        let mut ord = 0;
+        let mut ords = Vec::with_capacity(group.len());
+        let mut key_ord = None;
+        for (j, (_, _, k)) in group.iter().enumerate() {
+            if j == pos {
+                ord += 1;
+                key_ord = Some(ord);
+            }
+            if j == 0 || &group[j - 1].2 != k {
+                ord += 1;
+            }
+            ords.push(ord);
+        }
+        let key_ord = key_ord.unwrap_or(ord + 1);
+        let step = u64::MAX / (ord.max(key_ord) + 1);
+
+        let mut tree = self;
+        for ((stub, value, _), ord) in group.into_iter().zip(ords) {
+            let mut new_stub = stub.clone();
+            set_rank(&mut new_stub, ord * step);
+            if new_stub == stub {
+                continue;
+            }
+            // stub は最初のオーバーフローページを含むので、同じものは一つしかない
+            let (cursor, found) = tree.find(meta, root_i, &stub);
+            debug_assert!(found);
+            tree.cursor_delete(meta, cursor);
+            tree.insert(meta, root_i, &new_stub, &value)?;
+        }
+        Ok(key_ord * step)
+    }
+
+    // 以下はオーバーフローを透過的に扱う木の操作
+
+    pub fn insert_entry(
+        &self,
+        meta: &Meta,
+        root_i: usize,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), String> {
+        let mut stored_key = self.store_overflow(meta.key_size, key);
+        if is_stub(meta.key_size, &stored_key) {
+            let rank = self.rank_key(meta, root_i, key)?;
+            set_rank(&mut stored_key, rank);
+        }
+        let value = self.store_overflow(meta.value_size, value);
+        let mut tree = self;
+        tree.insert(meta, root_i, &stored_key, &value)
+    }
+
+    // entries はキーの順に並んでいること
+    // 空の木なら下から作り、そうでなければ一つずつ挿入する
+    pub fn bulk_load_entries(
+        &self,
+        meta: &Meta,
+        root_i: usize,
+        entries: Vec<(Key, Value)>,
+        fill_factor: f64,
+    ) -> Result<(), String> {
+        let is_empty = {
+            let root = self.get_ref(root_i);
+            root.is_leaf(meta) && root.size(meta) == 0
+        };
+        if !is_empty {
+            for (key, value) in entries {
+                self.insert_entry(meta, root_i, &key, &value)?;
+            }
+            return Ok(());
+        }
+        let entries = self.store_entries_overflow(meta, entries);
+        let mut tree = self;
+        tree.bulk_load(meta, root_i, entries, fill_factor);
+        Ok(())
+    }
+
+    // entries から新しい木を作り、ルートを返す
+    // entries はキーの順に並んでいること
+    pub fn build_tree(&self, meta: &Meta, entries: Vec<(Key, Value)>, fill_factor: f64) -> usize {
+        let entries = self.store_entries_overflow(meta, entries);
+        let mut tree = self;
+        tree.build(meta, entries, fill_factor)
+            .unwrap_or_else(|| tree.add_root_node())
+    }
+
+    // entries はキーの順に並んでいるので、先頭部分が同じ長いキーの並びごとに順位を付ける
+    fn store_entries_overflow(&self, meta: &Meta, entries: Vec<(Key, Value)>) -> Vec<(Key, Value)> {
+        let mut ranks = vec![0; entries.len()];
+        let mut i = 0;
+        while i < entries.len() {
+            if !is_stub(meta.key_size, &entries[i].0) {
+                i += 1;
+                continue;
+            }
+            let prefix = &entries[i].0[..MAX_INLINE_SIZE];
+            let end = i + entries[i..]
+                .iter()
+                .take_while(|(k, _)| is_stub(meta.key_size, k) && k.starts_with(prefix))
+                .count();
+            let distinct = 1 + entries[i..end]
+                .windows(2)
+                .filter(|w| w[0].0 != w[1].0)
+                .count() as u64;
+            let step = u64::MAX / (distinct + 1);
+            let mut ord = 1;
+            for j in i..end {
+                if i < j && entries[j - 1].0 != entries[j].0 {
+                    ord += 1;
+                }
+                ranks[j] = ord * step;
+            }
+            i = end;
+        }
+        entries
+            .into_iter()
+            .zip(ranks)
+            .map(|((key, value), rank)| {
+                let mut key = self.store_overflow(meta.key_size, &key);
+                if is_stub(meta.key_size, &key) {
+                    set_rank(&mut key, rank);
+                }
+                (key, self.store_overflow(meta.value_size, &value))
+            })
+            .collect()
+    }
+
+    pub fn find_entry(&self, meta: &Meta, root_i: usize, key: &[u8]) -> (BTreeCursor, bool) {
+        self.view(None).find_entry(meta, root_i, key)
+    }
+
+    pub fn get_entry(&self, meta: &Meta, cursor: &BTreeCursor) -> Option<(Key, Value)> {
+        self.view(None).get_entry(meta, cursor)
+    }
+
+    pub fn delete_entry(&self, meta: &Meta, cursor: BTreeCursor) -> BTreeCursor {
+        if let Some((key, value)) = self.cursor_get(meta, &cursor) {
+            self.free_overflow(meta.key_size, &key);
+            self.free_overflow(meta.value_size, &value);
+        }
+        let mut tree = self;
+        tree.cursor_delete(meta, cursor)
+    }
+}
+
+impl<'a> PagerView<'a> {
+    // stub ならオーバーフローページから読み戻す
+    fn load_overflow(&self, size: Option<usize>, bytes: Vec<u8>) -> Vec<u8> {
+        if !is_stub(size, &bytes) {
+            return bytes;
+        }
+        let (mut page_i, len) = parse_stub(&bytes);
+        let mut loaded = Vec::with_capacity(len);
+        while loaded.len() < len {
+            let page = self.node_ref(page_i);
+            let chunk_len = (len - loaded.len()).min(page.len() - 4);
+            loaded.extend(&page[4..4 + chunk_len]);
+            page_i = parse_u32(&page[0..4]) as usize;
+        }
+        loaded
+    }
+
+    pub fn find_entry(&self, meta: &Meta, root_i: usize, key: &[u8]) -> (BTreeCursor, bool) {
+        if !is_stub(meta.key_size, key) {
+            return self.find(meta, root_i, &key.to_vec());
+        }
+        // 先頭部分で探し、同じ先頭部分を持つ stub を順に比べる
+        // stub は元のキーの順に並んでいるので、key 以上の最初のものの位置を返す
+        let prefix = key[..MAX_INLINE_SIZE].to_vec();
+        let (mut cursor, _) = self.find(meta, root_i, &prefix);
+        while !self.cursor_is_end(meta, &cursor) {
+            let (k, _) = self.cursor_get(meta, &cursor).unwrap();
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            if is_stub(meta.key_size, &k) {
+                let k = self.load_overflow(meta.key_size, k);
+                if key <= k.as_slice() {
+                    return (cursor, k == key);
+                }
+            }
+            cursor = self.cursor_next(meta, cursor);
+        }
+        (cursor, false)
+    }
+
+    pub fn get_entry(&self, meta: &Meta, cursor: &BTreeCursor) -> Option<(Key, Value)> {
+        let (key, value) = self.cursor_get(meta, cursor)?;
+        Some((
+            self.load_overflow(meta.key_size, key),
+            self.load_overflow(meta.value_size, value),
+        ))
+    }
+}
+
+// (最初のオーバーフローページ, 全体の長さ)
+fn parse_stub(stub: &[u8]) -> (usize, usize) {
+    debug_assert_eq!(stub.len(), STUB_SIZE);
+    let pointer = &stub[MAX_INLINE_SIZE + RANK_SIZE..];
+    (
+        parse_u32(&pointer[..4]) as usize,
+        u64::from_le_bytes(pointer[4..].try_into().unwrap()) as usize,
+    )
+}
+
+fn parse_rank(stub: &[u8]) -> u64 {
+    u64::from_be_bytes(
+        stub[MAX_INLINE_SIZE..MAX_INLINE_SIZE + RANK_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn set_rank(stub: &mut [u8], rank: u64) {
+    stub[MAX_INLINE_SIZE..MAX_INLINE_SIZE + RANK_SIZE].copy_from_slice(&rank.to_be_bytes());
+}
+
+// lo と hi の間の順位 (None なら端)
+// 端に足すときは RANK_STEP ずつ空けるので、順に足しても付け直さずに済む
+fn rank_between(lo: Option<u64>, hi: Option<u64>) -> Option<u64> {
+    let rank = match (lo, hi) {
+        (None, None) => u64::MAX / 2,
+        (Some(lo), None) => lo + RANK_STEP.min((u64::MAX - lo) / 2),
+        (None, Some(hi)) => hi - RANK_STEP.min(hi / 2),
+        (Some(lo), Some(hi)) => lo + (hi - lo) / 2,
+    };
+    if lo.is_none_or(|lo| lo < rank) && hi.is_none_or(|hi| rank < hi) {
+        Some(rank)
+    } else {
+        None
     }
 }
 
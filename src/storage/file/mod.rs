@@ -1,27 +1,74 @@
+mod header;
 mod impl_btree;
 mod page;
 mod pager;
 mod simple_store;
 mod summary;
 
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
 use crate::{
     btree::{BTree, BTreeCursor},
-    data::{data_vec_from_bytes, data_vec_to_bytes, Data, Type},
-    schema::Schema,
+    data::{
+        data_vec_from_key, data_vec_from_nullable_key, data_vec_to_key, data_vec_to_nullable_key,
+        null_bitmap_size, row_from_bytes, row_to_bytes, Data, Type,
+    },
+    schema::{Column, Schema},
     storage::{
-        file::simple_store::{init_as_simple_store, read_object, write_object},
+        file::simple_store::{copy_objects, init_as_simple_store, read_object, write_object},
         Storage,
     },
 };
 
-use self::{impl_btree::Meta, pager::Pager};
+pub use self::pager::Backend;
+
+use self::{
+    header::{open_header, probe_file, Probe},
+    impl_btree::{Meta, PagerView},
+    pager::{Cipher, Pager, Snapshot},
+};
 
 pub struct File {
     pager: Pager<page::Page>,
-    schema: Schema,
-    sources: Vec<Source>,
+    // 書き換えは Arc::make_mut で行うので、snapshot は Arc を共有するだけ
+    schema: Arc<Schema>,
+    sources: Arc<Vec<Source>>,
+    // begin した時点の schema と sources
+    snapshot: Option<(Arc<Schema>, Arc<Vec<Source>>)>,
+    // add_rows で木を下から作るときに各ノードを埋める割合
+    fill_factor: f64,
+}
+
+const DEFAULT_FILL_FACTOR: f64 = 0.9;
+
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    pub backend: Backend,
+    // 新しく作るときのページの大きさ (4K から 64K の 2 のべき乗)
+    // 既にあるファイルはヘッダに書かれた大きさで開く
+    pub page_size: usize,
+    // 新しく作るときにページを圧縮するか
+    // 繰り返しの多い文字列が多いと小さくなるが、読み書きのたびに展開と圧縮をする
+    pub compression: bool,
+    // あれば新しく作るときに暗号化する
+    // 暗号化されたファイルを開くには同じパスフレーズが要る
+    pub passphrase: Option<String>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Read,
+            page_size: pager::DEFAULT_PAGE_SIZE,
+            compression: false,
+            passphrase: None,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Source {
     table_index: usize,
     page_index: usize,
@@ -32,17 +79,31 @@ pub struct Source {
     value_types: Vec<Type>,
     parent_source_index: Option<usize>,
     meta: Meta,
+    // スキーマから設定する (set_key_nullable)
+    #[serde(skip)]
+    key_nullable: Vec<bool>,
 }
 
 #[derive(Debug, Clone)]
 pub struct FileCursor {
     source_index: usize,
     btree_cursor: BTreeCursor,
+    // snapshot から作ったカーソルはその時点のページと sources を読む
+    snapshot: Option<(u64, Arc<Vec<Source>>)>,
+}
+
+// 取った時点のページと schema と sources
+// 木のルートは sources から引くので、その後に sources が変わっても取った時点の木を読む
+pub struct FileSnapshot {
+    pages: Snapshot<page::Page>,
+    schema: Arc<Schema>,
+    sources: Arc<Vec<Source>>,
 }
 
 impl Storage for File {
     type Cursor = FileCursor;
     type SourceIndex = usize;
+    type Snapshot = FileSnapshot;
 
     fn schema(&self) -> &Schema {
         &self.schema
@@ -51,7 +112,9 @@ impl Storage for File {
     fn add_table(&mut self, table: crate::schema::Table) {
         // TODO: name duplication check
         let source_index = self.sources.len();
-        let page_index = self.pager.add_root_node();
+        let page_index = self.add_root_node();
+        let table_index = self.schema.tables.len();
+        let page_size = self.pager.page_size();
         let value_column_indices: Vec<_> = (0..table.columns.len())
             .filter(|x| !table.primary_key.contains(x))
             .collect();
@@ -60,8 +123,8 @@ impl Storage for File {
             .iter()
             .map(|i| table.columns[*i].dtype.clone())
             .collect();
-        self.sources.push(Source {
-            table_index: self.schema.tables.len(),
+        let mut sources = vec![Source {
+            table_index,
             page_index,
             key_columns: table
                 .primary_key
@@ -80,14 +143,18 @@ impl Storage for File {
                 key_size: table
                     .primary_key
                     .iter()
-                    .map(|i| table.columns[*i].dtype.size())
-                    .sum(),
-                value_size: value_column_indices
-                    .iter()
-                    .map(|i| table.columns[*i].dtype.size())
+                    .map(|i| table.columns[*i].dtype.key_size())
                     .sum(),
+                value_size: row_size(
+                    &value_column_indices
+                        .iter()
+                        .map(|i| &table.columns[*i])
+                        .collect::<Vec<_>>(),
+                ),
+                page_size,
             },
-        });
+            key_nullable: vec![false; table.primary_key.len()],
+        }];
 
         // sources for indices
         for cols in table.indices.iter().map(|i| &i.column_indices) {
@@ -95,9 +162,9 @@ impl Storage for File {
                 .iter()
                 .map(|ci| &table.columns[*ci])
                 .collect::<Vec<_>>();
-            let page_index = self.pager.add_root_node();
-            self.sources.push(Source {
-                table_index: self.schema.tables.len(),
+            let page_index = self.add_root_node();
+            sources.push(Source {
+                table_index,
                 page_index,
                 key_column_indices: cols.clone(),
                 value_column_indices: vec![],
@@ -108,53 +175,89 @@ impl Storage for File {
                 key_columns: key_columns.iter().map(|c| c.name.clone()).collect(),
                 value_types: primary_key_types.clone(),
                 parent_source_index: Some(source_index),
+                key_nullable: key_columns.iter().map(|c| c.nullable).collect(),
                 meta: Meta {
-                    key_size: key_columns.iter().map(|c| c.dtype.size()).sum(),
-                    value_size: primary_key_types.iter().map(|t| t.size()).sum(),
+                    key_size: key_size(&key_columns),
+                    // 主キーをキーのエンコードで持つ
+                    value_size: primary_key_types.iter().map(|t| t.key_size()).sum(),
+                    page_size,
                 },
             });
         }
 
-        self.schema.tables.push(table);
+        Arc::make_mut(&mut self.sources).extend(sources);
+        Arc::make_mut(&mut self.schema).tables.push(table);
         self.write_schema();
+        self.write_sources();
     }
 
     fn source_index(&self, table_name: &str, key_columns: &[String]) -> Option<Self::SourceIndex> {
-        for (i, source) in self.sources.iter().enumerate() {
-            let table = &self.schema.tables[source.table_index];
-            if table.name == table_name && source.key_columns == key_columns {
-                return Some(i);
-            }
-        }
-        None
+        find_source(&self.schema, &self.sources, table_name, key_columns)
     }
 
     fn get_cursor_first(&self, source_index: Self::SourceIndex) -> Self::Cursor {
-        let source = &self.sources[source_index];
-        FileCursor {
-            source_index,
-            btree_cursor: self.pager.first_cursor(&source.meta, source.page_index),
-        }
+        self.cursor_first(source_index, None)
     }
 
     fn get_cursor_just(&self, source_index: Self::SourceIndex, key: &Vec<Data>) -> Self::Cursor {
-        let source = &self.sources[source_index];
-        let key = data_vec_to_bytes(key);
-        let (btree_cursor, _found) = self.pager.find(&source.meta, source.page_index, &key);
-        FileCursor {
-            source_index,
-            btree_cursor,
+        self.cursor_just(source_index, key, None)
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        FileSnapshot {
+            pages: self.pager.snapshot(),
+            schema: self.schema.clone(),
+            sources: self.sources.clone(),
         }
     }
 
+    fn schema_at<'a>(&'a self, snapshot: &'a Self::Snapshot) -> &'a Schema {
+        &snapshot.schema
+    }
+
+    fn source_index_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        table_name: &str,
+        key_columns: &[String],
+    ) -> Option<Self::SourceIndex> {
+        find_source(&snapshot.schema, &snapshot.sources, table_name, key_columns)
+    }
+
+    fn get_cursor_first_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        source_index: Self::SourceIndex,
+    ) -> Self::Cursor {
+        self.cursor_first(
+            source_index,
+            Some((snapshot.pages.id(), snapshot.sources.clone())),
+        )
+    }
+
+    fn get_cursor_just_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        source_index: Self::SourceIndex,
+        key: &[Data],
+    ) -> Self::Cursor {
+        self.cursor_just(
+            source_index,
+            key,
+            Some((snapshot.pages.id(), snapshot.sources.clone())),
+        )
+    }
+
     fn cursor_get_row(&self, cursor: &Self::Cursor) -> Option<Vec<Data>> {
-        let mut source = &self.sources[cursor.source_index];
-        let (key, value) = self.pager.cursor_get(&source.meta, &cursor.btree_cursor)?;
+        let pager = self.view_at(&cursor.snapshot);
+        let sources = self.sources_at(&cursor.snapshot);
+        let mut source = &sources[cursor.source_index];
+        let (key, value) = pager.get_entry(&source.meta, &cursor.btree_cursor)?;
         let (key, value) = if let Some(parent_surce_index) = source.parent_source_index {
-            source = &self.sources[parent_surce_index];
-            let (btree_cursor, found) = self.pager.find(&source.meta, source.page_index, &value);
+            source = &sources[parent_surce_index];
+            let (btree_cursor, found) = pager.find_entry(&source.meta, source.page_index, &value);
             assert!(found, "row is not found, index is broken?");
-            self.pager.cursor_get(&source.meta, &btree_cursor)?
+            pager.get_entry(&source.meta, &btree_cursor)?
         } else {
             (key, value)
         };
@@ -162,49 +265,52 @@ impl Storage for File {
     }
 
     fn cursor_advance(&self, cursor: &mut Self::Cursor) -> bool {
-        let source = &self.sources[cursor.source_index];
+        let source = &self.sources_at(&cursor.snapshot)[cursor.source_index];
         cursor.btree_cursor = self
-            .pager
+            .view_at(&cursor.snapshot)
             .cursor_next(&source.meta, cursor.btree_cursor.clone());
         true
     }
 
     fn cursor_is_end(&self, cursor: &Self::Cursor) -> bool {
-        let source = &self.sources[cursor.source_index];
-        self.pager.cursor_is_end(&source.meta, &cursor.btree_cursor)
+        let source = &self.sources_at(&cursor.snapshot)[cursor.source_index];
+        self.view_at(&cursor.snapshot)
+            .cursor_is_end(&source.meta, &cursor.btree_cursor)
     }
 
     fn cursor_next_occupied(&self, cursor: &mut Self::Cursor) {
-        let source = &self.sources[cursor.source_index];
+        let source = &self.sources_at(&cursor.snapshot)[cursor.source_index];
         cursor.btree_cursor = self
-            .pager
+            .view_at(&cursor.snapshot)
             .cursor_next_occupied(&source.meta, cursor.btree_cursor.clone());
     }
 
-    fn cursor_delete(&mut self, cursor: &mut Self::Cursor) -> bool {
+    fn cursor_delete(&self, cursor: &mut Self::Cursor) -> bool {
+        assert!(cursor.snapshot.is_none(), "snapshot is read only");
+        let _writer = self.pager.lock_writer();
         let source = &self.sources[cursor.source_index];
         if let Some(parent_source_index) = source.parent_source_index {
             let (_key, index) = self
                 .pager
-                .cursor_get(&source.meta, &cursor.btree_cursor)
+                .get_entry(&source.meta, &cursor.btree_cursor)
                 .unwrap();
             cursor.btree_cursor = self
                 .pager
-                .cursor_delete(&source.meta, cursor.btree_cursor.clone());
+                .delete_entry(&source.meta, cursor.btree_cursor.clone());
 
             let main_source = &self.sources[parent_source_index];
             let (btree_cursor, found) =
                 self.pager
-                    .find(&main_source.meta, main_source.page_index, &index);
+                    .find_entry(&main_source.meta, main_source.page_index, &index);
             assert!(found, "row is not found, index is broken?");
 
             let (key, value) = self
                 .pager
-                .cursor_get(&main_source.meta, &btree_cursor)
+                .get_entry(&main_source.meta, &btree_cursor)
                 .unwrap();
 
             // delete main
-            self.pager.cursor_delete(&main_source.meta, btree_cursor);
+            self.pager.delete_entry(&main_source.meta, btree_cursor);
 
             let value = main_source.build_value(&key, &value);
             let pk = key;
@@ -212,35 +318,32 @@ impl Storage for File {
             // delete indices
             for (source_index, source) in self.sources.iter().enumerate() {
                 if source.table_index != main_source.table_index
+                    || source.parent_source_index.is_none()
                     || source_index == cursor.source_index
                 {
                     continue;
                 }
 
-                let key: Vec<_> = source
-                    .key_column_indices
-                    .iter()
-                    .map(|i| value[*i].clone())
-                    .collect();
-                let key = data_vec_to_bytes(&key);
-                let (mut cursor, found) = self.pager.find(&source.meta, source.page_index, &key);
+                let key = source.build_key(&value);
+                let (mut cursor, found) =
+                    self.pager.find_entry(&source.meta, source.page_index, &key);
                 assert!(found, "index is broken?");
 
-                while self.pager.cursor_get(&source.meta, &cursor).unwrap().1 != pk {
-                    cursor = self.pager.cursor_next(&source.meta, cursor);
+                while self.pager.get_entry(&source.meta, &cursor).unwrap().1 != pk {
+                    cursor = self.pager.view(None).cursor_next(&source.meta, cursor);
                 }
-                self.pager.cursor_delete(&source.meta, cursor);
+                self.pager.delete_entry(&source.meta, cursor);
             }
         } else {
             let (key, value) = self
                 .pager
-                .cursor_get(&source.meta, &cursor.btree_cursor)
+                .get_entry(&source.meta, &cursor.btree_cursor)
                 .unwrap();
 
             // delete main
             cursor.btree_cursor = self
                 .pager
-                .cursor_delete(&source.meta, cursor.btree_cursor.clone());
+                .delete_entry(&source.meta, cursor.btree_cursor.clone());
 
             let value = source.build_value(&key, &value);
             let pk = key;
@@ -254,19 +357,15 @@ impl Storage for File {
                     continue;
                 }
 
-                let key: Vec<_> = source
-                    .key_column_indices
-                    .iter()
-                    .map(|i| value[*i].clone())
-                    .collect();
-                let key = data_vec_to_bytes(&key);
-                let (mut cursor, found) = self.pager.find(&source.meta, source.page_index, &key);
+                let key = source.build_key(&value);
+                let (mut cursor, found) =
+                    self.pager.find_entry(&source.meta, source.page_index, &key);
                 assert!(found, "index is broken?");
 
-                while self.pager.cursor_get(&source.meta, &cursor).unwrap().1 != pk {
-                    cursor = self.pager.cursor_next(&source.meta, cursor);
+                while self.pager.get_entry(&source.meta, &cursor).unwrap().1 != pk {
+                    cursor = self.pager.view(None).cursor_next(&source.meta, cursor);
                 }
-                self.pager.cursor_delete(&source.meta, cursor);
+                self.pager.delete_entry(&source.meta, cursor);
             }
         };
 
@@ -277,83 +376,593 @@ impl Storage for File {
         todo!()
     }
 
-    fn add_row(&mut self, table_name: &str, data: Vec<Data>) -> Result<(), String> {
+    fn add_row(&self, table_name: &str, data: Vec<Data>) -> Result<(), String> {
         let (table_index, table) = self.schema.get_table(table_name).unwrap();
 
-        let index_value: Vec<_> = table.primary_key.iter().map(|i| data[*i].clone()).collect();
-
+        let _writer = self.pager.lock_writer();
         for source in self.sources.iter() {
             if source.table_index != table_index {
                 continue;
             }
 
-            let key: Vec<_> = source
-                .key_column_indices
-                .iter()
-                .map(|i| data[*i].clone())
-                .collect();
-            let key = data_vec_to_bytes(&key);
+            let (key, value) = source.build_entry(&table.primary_key, &data);
+            self.pager
+                .insert_entry(&source.meta, source.page_index, &key, &value)?; // !!!!
+        }
+        Ok(())
+    }
 
-            let value: Vec<_> = source
-                .value_column_indices
+    // Source ごとに一度だけ並べ替えて、まとめて木に入れる
+    fn add_rows(&self, table_name: &str, rows: Vec<Vec<Data>>) -> Result<(), String> {
+        let (table_index, table) = self.schema.get_table(table_name).unwrap();
+
+        let _writer = self.pager.lock_writer();
+        for source in self.sources.iter() {
+            if source.table_index != table_index {
+                continue;
+            }
+
+            let mut entries: Vec<_> = rows
                 .iter()
-                .map(|i| data[*i].clone())
+                .map(|row| source.build_entry(&table.primary_key, row))
                 .collect();
-            let value = if source.parent_source_index.is_some() {
-                data_vec_to_bytes(&index_value)
-            } else {
-                data_vec_to_bytes(&value)
-            };
-
-            self.pager
-                .insert(&source.meta, source.page_index, &key, &value)?; // !!!!
+            entries.sort();
+            self.pager.bulk_load_entries(
+                &source.meta,
+                source.page_index,
+                entries,
+                self.fill_factor,
+            )?;
         }
         Ok(())
     }
 
     fn flush(&self) {
-        #[allow(mutable_transmutes)]
-        let pager = unsafe { std::mem::transmute::<_, &mut Pager<page::Page>>(&self.pager) };
-        pager.save()
+        if self.snapshot.is_some() {
+            // トランザクション中の変更は commit で書き込む
+            return;
+        }
+        let _writer = self.pager.lock_writer();
+        write_free_page_head(&self.pager);
+        self.pager.save()
+    }
+
+    fn vacuum(&mut self) -> Result<(), String> {
+        File::vacuum(self)
+    }
+
+    fn backup_to(&self, snapshot: &Self::Snapshot, path: &str) -> Result<(), String> {
+        File::backup_to(self, &snapshot.pages, path)
+    }
+
+    fn begin(&mut self) {
+        assert!(self.snapshot.is_none(), "transaction is already begun");
+        write_free_page_head(&self.pager);
+        self.pager.begin();
+        self.snapshot = Some((self.schema.clone(), self.sources.clone()));
+    }
+
+    fn commit(&mut self) {
+        self.snapshot.take().expect("transaction is not begun");
+        write_free_page_head(&self.pager);
+        self.pager.commit();
+    }
+
+    fn rollback(&mut self) {
+        let (schema, sources) = self.snapshot.take().expect("transaction is not begun");
+        self.pager.rollback();
+        self.schema = schema;
+        self.sources = sources;
     }
 }
 
 impl File {
-    pub fn open(filepath: &str) -> Self {
-        let mut pager = Pager::<page::Page>::open(filepath);
+    fn cursor_first(
+        &self,
+        source_index: usize,
+        snapshot: Option<(u64, Arc<Vec<Source>>)>,
+    ) -> FileCursor {
+        let source = &self.sources_at(&snapshot)[source_index];
+        let btree_cursor = self
+            .view_at(&snapshot)
+            .first_cursor(&source.meta, source.page_index);
+        FileCursor {
+            source_index,
+            btree_cursor,
+            snapshot,
+        }
+    }
+
+    fn cursor_just(
+        &self,
+        source_index: usize,
+        key: &[Data],
+        snapshot: Option<(u64, Arc<Vec<Source>>)>,
+    ) -> FileCursor {
+        let source = &self.sources_at(&snapshot)[source_index];
+        let key = data_vec_to_nullable_key(key, &source.key_nullable);
+        let (btree_cursor, _found) =
+            self.view_at(&snapshot)
+                .find_entry(&source.meta, source.page_index, &key);
+        FileCursor {
+            source_index,
+            btree_cursor,
+            snapshot,
+        }
+    }
+
+    // カーソルが読むページと sources
+    // snapshot から作ったカーソルなら、その時点のもの
+    fn view_at(&self, snapshot: &Option<(u64, Arc<Vec<Source>>)>) -> PagerView<'_> {
+        self.pager.view(snapshot.as_ref().map(|(id, _)| *id))
+    }
+
+    fn sources_at<'a>(&'a self, snapshot: &'a Option<(u64, Arc<Vec<Source>>)>) -> &'a [Source] {
+        match snapshot {
+            Some((_, sources)) => sources,
+            None => &self.sources,
+        }
+    }
+
+    pub fn open(filepath: &str) -> Result<Self, String> {
+        Self::open_with(filepath, OpenOptions::default())
+    }
+
+    // 読み込みの多い用途では Backend::Mmap を使う
+    pub fn open_with_backend(filepath: &str, backend: Backend) -> Result<Self, String> {
+        Self::open_with(
+            filepath,
+            OpenOptions {
+                backend,
+                ..OpenOptions::default()
+            },
+        )
+    }
+
+    pub fn open_encrypted(filepath: &str, passphrase: &str) -> Result<Self, String> {
+        Self::open_with(
+            filepath,
+            OpenOptions {
+                passphrase: Some(passphrase.to_owned()),
+                ..OpenOptions::default()
+            },
+        )
+    }
+
+    pub fn open_with(filepath: &str, options: OpenOptions) -> Result<Self, String> {
+        if !pager::is_valid_page_size(options.page_size) {
+            return Err(format!("page size {} is not supported", options.page_size));
+        }
+        let probe = probe_file(filepath);
+        // 違う鍵でページを読むと壊れた木に見えるので、ヘッダの key check で先に弾く
+        let cipher = match (probe.as_ref().map(|p| p.encryption), &options.passphrase) {
+            (None, passphrase) => passphrase
+                .as_ref()
+                .map(|passphrase| Cipher::new(passphrase, Cipher::random_salt())),
+            (Some(None), None) => None,
+            (Some(None), Some(_)) => {
+                return Err(format!("cannot open {:?}: not encrypted", filepath))
+            }
+            (Some(Some(_)), None) => {
+                return Err(format!(
+                    "cannot open {:?}: encrypted (passphrase is required)",
+                    filepath
+                ))
+            }
+            (Some(Some((salt, key_check))), Some(passphrase)) => {
+                let cipher = Cipher::new(passphrase, salt);
+                if cipher.key_check() != key_check {
+                    return Err(format!("cannot open {:?}: wrong passphrase", filepath));
+                }
+                Some(cipher)
+            }
+        };
+        let probe = probe.unwrap_or(Probe {
+            page_size: options.page_size,
+            checksum: true,
+            compression: options.compression,
+            encryption: None,
+        });
+        let pager = Pager::<page::Page>::open_with_backend(
+            filepath,
+            probe.page_size,
+            probe.checksum,
+            probe.compression,
+            cipher,
+            options.backend,
+        );
+        Self::from_pager(filepath, pager)
+    }
+
+    // 空のファイルなら初期化する
+    fn from_pager(filepath: &str, mut pager: Pager<page::Page>) -> Result<Self, String> {
         if pager.size() == 0 {
+            if std::fs::metadata(filepath)
+                .map_err(|e| e.to_string())?
+                .len()
+                != 0
+            {
+                return Err(format!("{:?} is not an rdb file (too short)", filepath));
+            }
             // initialize
             let schema = Schema::new_empty();
-            init_as_simple_store(&mut pager);
-            write_object(&mut pager, "schema", &schema);
-
-            // TODO write file header
-            // 0x1006 <storage format>
-            Self {
+            init_as_simple_store(&pager);
+            write_object(&pager, "free_page_head", &0u32);
+            write_object(&pager, "schema", &schema);
+            Ok(Self {
                 pager,
-                schema,
-                sources: vec![],
-            }
+                schema: Arc::new(schema),
+                sources: Arc::new(vec![]),
+                snapshot: None,
+                fill_factor: DEFAULT_FILL_FACTOR,
+            })
         } else {
-            let schema = read_object(&mut pager, "schema").unwrap();
-            dbg!(&schema);
-            Self {
+            open_header(&mut pager).map_err(|e| format!("cannot open {:?}: {}", filepath, e))?;
+            // マイグレーションした場合はここで書き込まれる
+            pager.save();
+            let schema = read_object(&pager, "schema").unwrap();
+            let mut sources = read_sources(&pager);
+            set_key_nullable(&mut sources, &schema);
+            let free_page_head: u32 = read_object(&pager, "free_page_head").unwrap_or(0);
+            pager.set_free_head(free_page_head as usize);
+            Ok(Self {
                 pager,
-                schema,
-                sources: vec![],
+                schema: Arc::new(schema),
+                sources: Arc::new(sources),
+                snapshot: None,
+                fill_factor: DEFAULT_FILL_FACTOR,
+            })
+        }
+    }
+
+    // ページのチェックサム、各 Source の木の構造、インデックスと主キーの対応を検査する
+    // 今のページを読むので、検査の間は書き込みを止める
+    pub fn check_integrity(&self) -> Result<(), Vec<String>> {
+        let _writer = self.pager.lock_writer();
+        let problems: Vec<_> = self
+            .pager
+            .corrupted_pages()
+            .into_iter()
+            .map(|i| format!("page {} is corrupted (checksum mismatch)", i))
+            .collect();
+        if !problems.is_empty() {
+            // 壊れたページは読めないので木はたどらない
+            return Err(problems);
+        }
+
+        let mut problems = vec![];
+        for (source_index, source) in self.sources.iter().enumerate() {
+            for problem in self.pager.view(None).check(&source.meta, source.page_index) {
+                problems.push(format!("source {}: {}", source_index, problem));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        for (source_index, source) in self.sources.iter().enumerate() {
+            let entries = self.source_entries(source);
+            if let Some(parent_source_index) = source.parent_source_index {
+                // インデックスの各エントリが主キーの行を指しているか
+                let parent = &self.sources[parent_source_index];
+                for (key, pk) in entries {
+                    let (cursor, found) =
+                        self.pager.find_entry(&parent.meta, parent.page_index, &pk);
+                    if !found {
+                        problems.push(format!(
+                            "source {}: index entry points to a missing row",
+                            source_index
+                        ));
+                        continue;
+                    }
+                    let (pk, value) = self.pager.get_entry(&parent.meta, &cursor).unwrap();
+                    let row = parent.build_value(&pk, &value);
+                    if key != source.build_key(&row) {
+                        problems.push(format!(
+                            "source {}: index entry does not match the row",
+                            source_index
+                        ));
+                    }
+                }
+            } else {
+                if entries.windows(2).any(|w| w[0].0 == w[1].0) {
+                    problems.push(format!(
+                        "source {}: primary key is duplicated",
+                        source_index
+                    ));
+                }
+                // 各行がすべてのインデックスに載っているか
+                for (pk, value) in entries {
+                    let row = source.build_value(&pk, &value);
+                    for (index_source_index, index_source) in self.sources.iter().enumerate() {
+                        if index_source.parent_source_index != Some(source_index) {
+                            continue;
+                        }
+                        let key = index_source.build_key(&row);
+                        let (mut cursor, _) = self.pager.find_entry(
+                            &index_source.meta,
+                            index_source.page_index,
+                            &key,
+                        );
+                        let mut found = false;
+                        while !self
+                            .pager
+                            .view(None)
+                            .cursor_is_end(&index_source.meta, &cursor)
+                        {
+                            let (k, v) = self.pager.get_entry(&index_source.meta, &cursor).unwrap();
+                            if k != key {
+                                break;
+                            }
+                            if v == pk {
+                                found = true;
+                                break;
+                            }
+                            cursor = self
+                                .pager
+                                .view(None)
+                                .cursor_next(&index_source.meta, cursor);
+                        }
+                        if !found {
+                            problems.push(format!(
+                                "source {}: row is missing in index source {}",
+                                source_index, index_source_index
+                            ));
+                        }
+                    }
+                }
             }
         }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // すべての Source の木とオブジェクトを新しいファイルに詰めて書き直し、置き換える
+    // 置き換えは rename なので、途中で落ちても元のファイルが残る
+    pub fn vacuum(&mut self) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err("cannot vacuum in a transaction".to_owned());
+        }
+        if self.pager.has_snapshot() {
+            return Err("cannot vacuum while snapshots are alive".to_owned());
+        }
+        self.flush();
+
+        let filepath = self.pager.filepath().to_owned();
+        replace_via_tmp(&filepath, |tmp_path| {
+            let pager = Pager::<page::Page>::open_with_backend(
+                tmp_path,
+                self.pager.page_size(),
+                true,
+                self.pager.compression(),
+                self.pager.cipher().cloned(),
+                Backend::Read,
+            );
+            let _writer = pager.lock_writer();
+            init_as_simple_store(&pager);
+            copy_objects(&self.pager, &pager);
+            let mut sources = Vec::clone(&self.sources);
+            for source in sources.iter_mut() {
+                // 木の順に読むので、キーの順に並んでいる
+                let entries = self.source_entries(source);
+                source.page_index = pager.build_tree(&source.meta, entries, self.fill_factor);
+            }
+            write_object(&pager, "sources", &sources);
+            write_free_page_head(&pager);
+            pager.save();
+            Ok(())
+        })?;
+
+        let fill_factor = self.fill_factor;
+        let capacity = self.pager.capacity();
+        let pager = Pager::open_with_backend(
+            &filepath,
+            self.pager.page_size(),
+            true,
+            self.pager.compression(),
+            self.pager.cipher().cloned(),
+            self.pager.backend(),
+        );
+        *self = File::from_pager(&filepath, pager)?;
+        self.fill_factor = fill_factor;
+        self.set_cache_capacity(capacity);
+        Ok(())
+    }
+
+    // snapshot の時点のページを path に書き出す
+    // 書き出している間も読み書きは止めない
+    // 途中で落ちても path は書き換わらない
+    pub fn backup_to(
+        &self,
+        snapshot: &pager::Snapshot<page::Page>,
+        path: &str,
+    ) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err("cannot back up in a transaction".to_owned());
+        }
+        replace_via_tmp(path, |tmp_path| {
+            self.pager
+                .write_snapshot(snapshot, tmp_path)
+                .map_err(|e| e.to_string())?;
+            // ページに書かれた先頭は最後に flush した時点のものなので、snapshot の時点のものに直す
+            let probe = probe_file(tmp_path).unwrap();
+            let pager = Pager::<page::Page>::open_with_backend(
+                tmp_path,
+                probe.page_size,
+                probe.checksum,
+                probe.compression,
+                self.pager.cipher().cloned(),
+                Backend::Read,
+            );
+            write_object(&pager, "free_page_head", &(snapshot.free_head() as u32));
+            pager.save();
+            Ok(())
+        })
+    }
+
+    fn add_root_node(&self) -> usize {
+        let _writer = self.pager.lock_writer();
+        (&self.pager).add_root_node()
+    }
+
+    fn source_entries(&self, source: &Source) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = vec![];
+        let cursor = self
+            .pager
+            .view(None)
+            .first_cursor(&source.meta, source.page_index);
+        let mut cursor = self
+            .pager
+            .view(None)
+            .cursor_next_occupied(&source.meta, cursor);
+        while !self.pager.view(None).cursor_is_end(&source.meta, &cursor) {
+            entries.push(self.pager.get_entry(&source.meta, &cursor).unwrap());
+            cursor = self.pager.view(None).cursor_next(&source.meta, cursor);
+        }
+        entries
+    }
+
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.pager.set_capacity(capacity);
+    }
+
+    // 0.0 より大きく 1.0 以下
+    pub fn set_fill_factor(&mut self, fill_factor: f64) {
+        assert!(
+            0.0 < fill_factor && fill_factor <= 1.0,
+            "invalid fill factor"
+        );
+        self.fill_factor = fill_factor;
     }
 
     pub fn write_schema(&mut self) {
-        write_object(&mut self.pager, "schema", &self.schema);
+        write_object(&self.pager, "schema", &*self.schema);
+    }
+
+    pub fn write_sources(&mut self) {
+        write_object(&self.pager, "sources", &*self.sources);
+    }
+}
+
+// page_size は保存していないので、ページャーから設定する
+fn read_sources(pager: &Pager<page::Page>) -> Vec<Source> {
+    let mut sources: Vec<Source> = read_object(pager, "sources").unwrap_or_default();
+    for source in sources.iter_mut() {
+        source.meta.page_size = pager.page_size();
+    }
+    sources
+}
+
+fn find_source(
+    schema: &Schema,
+    sources: &[Source],
+    table_name: &str,
+    key_columns: &[String],
+) -> Option<usize> {
+    for (i, source) in sources.iter().enumerate() {
+        let table = &schema.tables[source.table_index];
+        if table.name == table_name && source.key_columns == key_columns {
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn set_key_nullable(sources: &mut [Source], schema: &Schema) {
+    for source in sources.iter_mut() {
+        let table = &schema.tables[source.table_index];
+        source.key_nullable = source
+            .key_column_indices
+            .iter()
+            .map(|i| table.columns[*i].nullable)
+            .collect();
+    }
+}
+
+// null は場所を取らないので、nullable な列があれば可変長になる
+fn row_size(columns: &[&Column]) -> Option<usize> {
+    if columns.iter().any(|c| c.nullable) {
+        return None;
+    }
+    let size: Option<usize> = columns.iter().map(|c| c.dtype.size()).sum();
+    size.map(|size| null_bitmap_size(columns.len()) + size)
+}
+
+fn key_size(columns: &[&Column]) -> Option<usize> {
+    if columns.iter().any(|c| c.nullable) {
+        return None;
+    }
+    columns.iter().map(|c| c.dtype.key_size()).sum()
+}
+
+// 書き込み中にページが確保されると先頭が変わるので、変わらなくなるまで書き直す
+// path の隣の一時ファイルに write で書き出し、rename で path と置き換える
+// 前に落ちたときの一時ファイルが残っていれば消してから書く
+fn replace_via_tmp(
+    path: &str,
+    write: impl FnOnce(&str) -> Result<(), String>,
+) -> Result<(), String> {
+    let tmp_path = format!("{}-tmp", path);
+    for path in [&tmp_path, &pager::wal_path(&tmp_path)] {
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.to_string());
+            }
+        }
+    }
+    write(&tmp_path)?;
+    // save した後の WAL は空なので消してよい
+    std::fs::remove_file(pager::wal_path(&tmp_path)).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+}
+
+fn write_free_page_head(pager: &Pager<page::Page>) {
+    loop {
+        let head = pager.free_head();
+        write_object(pager, "free_page_head", &(head as u32));
+        if pager.free_head() == head {
+            break;
+        }
     }
 }
 
 impl Source {
+    pub fn build_key(&self, row: &[Data]) -> Vec<u8> {
+        let key: Vec<_> = self
+            .key_column_indices
+            .iter()
+            .map(|i| row[*i].clone())
+            .collect();
+        data_vec_to_nullable_key(&key, &self.key_nullable)
+    }
+
+    // 木に入れる (キー, 値)
+    // インデックスの値は主キー
+    pub fn build_entry(&self, primary_key: &[usize], row: &[Data]) -> (Vec<u8>, Vec<u8>) {
+        let value = if self.parent_source_index.is_some() {
+            let pk: Vec<_> = primary_key.iter().map(|i| row[*i].clone()).collect();
+            data_vec_to_key(&pk)
+        } else {
+            let value: Vec<_> = self
+                .value_column_indices
+                .iter()
+                .map(|i| row[*i].clone())
+                .collect();
+            row_to_bytes(&value)
+        };
+        (self.build_key(row), value)
+    }
+
     pub fn build_value(&self, key: &[u8], value: &[u8]) -> Vec<Data> {
-        let key = data_vec_from_bytes(&self.key_types, key).unwrap();
-        let value = data_vec_from_bytes(&self.value_types, value).unwrap();
+        let key = data_vec_from_nullable_key(&self.key_types, &self.key_nullable, key).unwrap();
+        let value = if self.parent_source_index.is_some() {
+            data_vec_from_key(&self.value_types, value).unwrap()
+        } else {
+            row_from_bytes(&self.value_types, value).unwrap()
+        };
         let mut ret = vec![Data::U64(0); key.len() + value.len()];
         for (i, j) in self.key_column_indices.iter().enumerate() {
             ret[*j] = key[i].clone();
@@ -365,12 +974,34 @@ impl Source {
     }
 }
 
+#[cfg(test)]
+use crate::test_util::{email_table, user_table, TestDir};
+
+// source_index の木を最初から最後まで読む
+#[cfg(test)]
+fn scan_all(f: &File, source_index: usize) -> Vec<Vec<Data>> {
+    let mut cursor = f.get_cursor_first(source_index);
+    let mut rows = vec![];
+    while !f.cursor_is_end(&cursor) {
+        rows.push(f.cursor_get_row(&cursor).unwrap());
+        f.cursor_advance(&mut cursor);
+    }
+    rows
+}
+
 #[test]
 fn test() {
-    let mut f = File::open("hello");
+    let dir = TestDir::new("test");
+    let filepath = &dir.path("test.rdb");
+    let mut f = File::open(filepath).unwrap();
     f.add_table(crate::schema::Table {
         name: "hey".to_owned(),
-        columns: vec![],
+        columns: vec![crate::schema::Column {
+            name: "id".to_owned(),
+            dtype: Type::U64,
+            nullable: false,
+            default: None,
+        }],
         primary_key: vec![0],
         constraints: vec![],
         indices: vec![],
@@ -378,3 +1009,693 @@ fn test() {
 
     f.pager.save();
 }
+
+#[test]
+fn test_reopen() {
+    let dir = TestDir::new("test_reopen");
+    let filepath = &dir.path("test_reopen.rdb");
+    let table = user_table();
+    {
+        let mut f = File::open(filepath).unwrap();
+        f.set_cache_capacity(4);
+        f.add_table(table);
+        for i in 0..100 {
+            f.add_row(
+                "user",
+                vec![Data::U64(i), Data::String(format!("user{}", i))],
+            )
+            .unwrap();
+        }
+        f.flush();
+    }
+    {
+        let f = File::open(filepath).unwrap();
+        assert_eq!(f.sources.len(), 2);
+
+        let source_index = f.source_index("user", &["id".to_owned()]).unwrap();
+        let ids: Vec<_> = scan_all(&f, source_index)
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect();
+        // 数値の順に並ぶ
+        assert_eq!(ids, (0..100).map(Data::U64).collect::<Vec<_>>());
+
+        let source_index = f.source_index("user", &["name".to_owned()]).unwrap();
+        let cursor = f.get_cursor_just(source_index, &vec![Data::String("user42".to_owned())]);
+        assert_eq!(
+            f.cursor_get_row(&cursor),
+            Some(vec![Data::U64(42), Data::String("user42".to_owned())])
+        );
+    }
+}
+
+#[test]
+fn test_check_integrity() {
+    let dir = TestDir::new("test_check_integrity");
+    let filepath = &dir.path("test_check_integrity.rdb");
+    let table = user_table();
+    let root_i;
+    {
+        let mut f = File::open(filepath).unwrap();
+        f.add_table(table);
+        root_i = f.sources[0].page_index;
+        for i in 0..300 {
+            f.add_row(
+                "user",
+                vec![Data::U64(i), Data::String(format!("user{}", i % 50))],
+            )
+            .unwrap();
+        }
+        let source_index = f.source_index("user", &["id".to_owned()]).unwrap();
+        let mut cursor = f.get_cursor_first(source_index);
+        for _ in 0..100 {
+            f.cursor_delete(&mut cursor);
+        }
+        assert_eq!(f.check_integrity(), Ok(()));
+
+        // インデックスのエントリだけを消す
+        let source = &f.sources[1];
+        let cursor = f
+            .pager
+            .view(None)
+            .first_cursor(&source.meta, source.page_index);
+        {
+            let _writer = f.pager.lock_writer();
+            (&f.pager).cursor_delete(&source.meta, cursor);
+        }
+        let problems = f.check_integrity().unwrap_err();
+        assert!(problems[0].contains("missing in index"), "{:?}", problems);
+        f.flush();
+    }
+    {
+        // ディスク上のページを壊す
+        use std::io::{Seek, SeekFrom, Write};
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(filepath)
+            .unwrap();
+        file.seek(SeekFrom::Start(
+            root_i as u64 * (pager::DEFAULT_PAGE_SIZE as u64 + 8) + 100,
+        ))
+        .unwrap();
+        file.write_all(&[0xff; 4]).unwrap();
+    }
+    let f = File::open(filepath).unwrap();
+    assert_eq!(
+        f.check_integrity(),
+        Err(vec![format!(
+            "page {} is corrupted (checksum mismatch)",
+            root_i
+        )])
+    );
+}
+
+#[test]
+fn test_overflow() {
+    let dir = TestDir::new("test_overflow");
+    let filepath = &dir.path("test_overflow.rdb");
+    let table = crate::front::yaml::schema::parse_table_from_yaml(
+        r"
+name: post
+columns:
+-   name: id
+    type: u64
+-   name: title
+    type: string
+-   name: body
+    type: string
+primary_key: [id]
+indices:
+-   name: title
+    columns: [title]
+",
+    )
+    .unwrap();
+    // 先頭部分が同じで、末尾だけが違う長いキー
+    let title = |i: u64| format!("{}{}", "t".repeat(1000), i);
+    let body = |i: u64| format!("{}", i).repeat(200_000);
+    let row = |i: u64| vec![Data::U64(i), Data::String(title(i)), Data::String(body(i))];
+    {
+        let mut f = File::open(filepath).unwrap();
+        f.add_table(table);
+        // 逆順に入れても、元のキーの順に並ぶ
+        for i in (0..10).rev() {
+            f.add_row("post", row(i)).unwrap();
+        }
+        f.flush();
+    }
+    let f = File::open(filepath).unwrap();
+    assert_eq!(f.check_integrity(), Ok(()));
+    let source_index = f.source_index("post", &["title".to_owned()]).unwrap();
+    assert_eq!(
+        scan_all(&f, source_index),
+        (0..10).map(row).collect::<Vec<_>>()
+    );
+    for i in 0..10 {
+        let cursor = f.get_cursor_just(source_index, &vec![Data::String(title(i))]);
+        assert_eq!(f.cursor_get_row(&cursor), Some(row(i)));
+    }
+    // ないキーからの範囲は、その次のキーから始まる
+    let mut cursor = f.get_cursor_just(source_index, &vec![Data::String(format!("{}0", title(3)))]);
+    let mut ids = vec![];
+    while !f.cursor_is_end(&cursor) {
+        ids.push(f.cursor_get_row(&cursor).unwrap()[0].clone());
+        f.cursor_advance(&mut cursor);
+    }
+    assert_eq!(ids, (4..10).map(Data::U64).collect::<Vec<_>>());
+
+    let size = f.pager.size();
+    let mut cursor = f.get_cursor_first(source_index);
+    for _ in 0..10 {
+        f.cursor_delete(&mut cursor);
+    }
+    assert_eq!(f.check_integrity(), Ok(()));
+    assert!(size / 2 < f.pager.free_pages().len());
+
+    // 同じ二つのキーの間に入れ続けると、間がなくなって順位を付け直す
+    let title = |n: usize| format!("{}{}", "t".repeat(1000), "1".repeat(n));
+    f.add_row(
+        "post",
+        vec![
+            Data::U64(100),
+            Data::String(format!("{}2", "t".repeat(1000))),
+            Data::String(String::new()),
+        ],
+    )
+    .unwrap();
+    for n in 1..100 {
+        f.add_row(
+            "post",
+            vec![
+                Data::U64(n as u64),
+                Data::String(title(n)),
+                Data::String(String::new()),
+            ],
+        )
+        .unwrap();
+    }
+    assert_eq!(f.check_integrity(), Ok(()));
+    let ids: Vec<_> = scan_all(&f, source_index)
+        .into_iter()
+        .map(|row| row[0].clone())
+        .collect();
+    let expected: Vec<_> = (1..100).chain([100]).map(Data::U64).collect();
+    assert_eq!(ids, expected);
+    for n in 1..100 {
+        let cursor = f.get_cursor_just(source_index, &vec![Data::String(title(n))]);
+        assert_eq!(f.cursor_get_row(&cursor).unwrap()[0], Data::U64(n as u64));
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let dir = TestDir::new("test_snapshot_file");
+    let filepath = &dir.path("test_snapshot_file.rdb");
+    let table = user_table();
+    let row = |i: u64, name: &str| vec![Data::U64(i), Data::String(format!("{}{:04}", name, i))];
+    let mut f = File::open(filepath).unwrap();
+    f.add_table(table);
+    for i in 0..500 {
+        f.add_row("user", row(i, "user")).unwrap();
+    }
+    f.flush();
+
+    let source_index = f.source_index("user", &["name".to_owned()]).unwrap();
+    let snapshot = f.snapshot();
+    let mut cursor = f.get_cursor_first_at(&snapshot, source_index);
+    f.cursor_next_occupied(&mut cursor);
+    let mut rows = vec![];
+    for _ in 0..250 {
+        rows.push(f.cursor_get_row(&cursor).unwrap());
+        f.cursor_advance(&mut cursor);
+        f.cursor_next_occupied(&mut cursor);
+    }
+
+    // 読んでいる途中で全ての行を消して、名前を変えて入れ直す
+    let mut write_cursor = f.get_cursor_first(f.source_index("user", &["id".to_owned()]).unwrap());
+    while !f.cursor_is_end(&write_cursor) {
+        f.cursor_delete(&mut write_cursor);
+        f.cursor_next_occupied(&mut write_cursor);
+    }
+    for i in 0..1000 {
+        f.add_row("user", row(i, "renamed")).unwrap();
+    }
+    f.flush();
+
+    while !f.cursor_is_end(&cursor) {
+        rows.push(f.cursor_get_row(&cursor).unwrap());
+        f.cursor_advance(&mut cursor);
+        f.cursor_next_occupied(&mut cursor);
+    }
+    assert_eq!(rows, (0..500).map(|i| row(i, "user")).collect::<Vec<_>>());
+    let cursor = f.get_cursor_just_at(&snapshot, 0, &[Data::U64(42)]);
+    assert_eq!(f.cursor_get_row(&cursor), Some(row(42, "user")));
+
+    // snapshot の後に足したテーブルは、snapshot のスキーマと sources には無い
+    f.begin();
+    f.add_table(
+        crate::front::yaml::schema::parse_table_from_yaml(
+            r"
+name: item
+columns:
+-   name: id
+    type: u64
+primary_key: [id]
+",
+        )
+        .unwrap(),
+    );
+    f.add_row("item", vec![Data::U64(1)]).unwrap();
+    f.commit();
+    assert!(f.source_index("item", &["id".to_owned()]).is_some());
+    assert!(f
+        .source_index_at(&snapshot, "item", &["id".to_owned()])
+        .is_none());
+    assert!(f.schema_at(&snapshot).get_table("item").is_none());
+    let source_index = f
+        .source_index_at(&snapshot, "user", &["id".to_owned()])
+        .unwrap();
+    let mut cursor = f.get_cursor_first_at(&snapshot, source_index);
+    f.cursor_next_occupied(&mut cursor);
+    assert_eq!(f.cursor_get_row(&cursor), Some(row(0, "user")));
+
+    // snapshot を解放すると古い版は捨てられ、今の内容が見える
+    drop(snapshot);
+    assert_eq!(f.pager.versions_size(), 0);
+    let cursor = f.get_cursor_just(0, &vec![Data::U64(42)]);
+    assert_eq!(f.cursor_get_row(&cursor), Some(row(42, "renamed")));
+    assert_eq!(f.check_integrity(), Ok(()));
+}
+
+#[test]
+fn test_bulk_load() {
+    let dir = TestDir::new("test_bulk_load");
+    let filepath = &dir.path("test_bulk_load.rdb");
+    let table = user_table();
+    // 同じ名前が多く、たまに長い名前がある
+    // common は一つの葉に収まらない
+    let row = |i: u64| {
+        let name = if i.is_multiple_of(100) {
+            format!("{}{}", "n".repeat(1000), i)
+        } else if i % 10 == 1 {
+            "common".to_owned()
+        } else {
+            format!("user{}", i % 300)
+        };
+        vec![Data::U64(i), Data::String(name)]
+    };
+    let mut f = File::open(filepath).unwrap();
+    f.set_cache_capacity(16);
+    f.set_fill_factor(0.7);
+    f.add_table(table);
+    // 順番はばらばらでよい
+    let ids: Vec<u64> = (0..5000).map(|i| i * 7919 % 5000).collect();
+    f.add_rows("user", ids.iter().map(|i| row(*i)).collect())
+        .unwrap();
+    assert_eq!(f.check_integrity(), Ok(()));
+
+    // 空でない木には一つずつ入る
+    f.add_rows("user", (5000..5500).rev().map(row).collect())
+        .unwrap();
+    for i in 5500..5600 {
+        f.add_row("user", row(i)).unwrap();
+    }
+    f.flush();
+    drop(f);
+
+    let f = File::open(filepath).unwrap();
+    assert_eq!(f.check_integrity(), Ok(()));
+    let source_index = f.source_index("user", &["id".to_owned()]).unwrap();
+    assert_eq!(
+        scan_all(&f, source_index),
+        (0..5600).map(row).collect::<Vec<_>>()
+    );
+
+    let source_index = f.source_index("user", &["name".to_owned()]).unwrap();
+    let mut cursor = f.get_cursor_just(source_index, &vec![Data::String("common".to_owned())]);
+    let mut count = 0;
+    while !f.cursor_is_end(&cursor) {
+        if f.cursor_get_row(&cursor).unwrap()[1] != Data::String("common".to_owned()) {
+            break;
+        }
+        count += 1;
+        f.cursor_advance(&mut cursor);
+    }
+    assert_eq!(count, 560);
+}
+
+#[test]
+fn test_vacuum() {
+    use crate::btree::BTreeNode;
+
+    let dir = TestDir::new("test_vacuum");
+    let filepath = &dir.path("test_vacuum.rdb");
+    let table = user_table();
+    let row = |i: u64| {
+        let name = if i.is_multiple_of(50) {
+            format!("{}{}", "n".repeat(1000), i)
+        } else {
+            format!("user{}", i)
+        };
+        vec![Data::U64(i), Data::String(name)]
+    };
+    let mut f = File::open(filepath).unwrap();
+    f.add_table(table);
+    for i in 0..3000 {
+        f.add_row("user", row(i)).unwrap();
+    }
+    // 3 つに 2 つを消す
+    let mut cursor = f.get_cursor_first(0);
+    while !f.cursor_is_end(&cursor) {
+        let id = f.cursor_get_row(&cursor).unwrap()[0].clone();
+        if id == Data::U64(0) || !matches!(id, Data::U64(i) if i % 3 == 0) {
+            f.cursor_delete(&mut cursor);
+            f.cursor_next_occupied(&mut cursor);
+        } else {
+            f.cursor_advance(&mut cursor);
+        }
+    }
+    f.flush();
+    let expected: Vec<_> = (1..1000).map(|i| row(i * 3)).collect();
+    assert_eq!(scan_all(&f, 0), expected);
+    let len = std::fs::metadata(filepath).unwrap().len();
+
+    // トランザクションの中や snapshot が生きている間は書き直さない
+    f.begin();
+    assert!(f.vacuum().is_err());
+    f.rollback();
+    let snapshot = f.snapshot();
+    assert!(f.vacuum().is_err());
+    drop(snapshot);
+    assert_eq!(std::fs::metadata(filepath).unwrap().len(), len);
+
+    f.vacuum().unwrap();
+    assert!(std::fs::metadata(filepath).unwrap().len() < len / 2);
+    assert_eq!(f.check_integrity(), Ok(()));
+    assert_eq!(scan_all(&f, 0), expected);
+    assert!(f.pager.free_pages().is_empty());
+
+    // 葉はファイルの前から順に並ぶ
+    let source = &f.sources[0];
+    let mut node_i = source.page_index;
+    while !f.pager.get_ref(node_i).is_leaf(&source.meta) {
+        node_i = f.pager.get_ref(node_i).get_first_child(&source.meta);
+    }
+    while let Some(next_i) = f.pager.get_ref(node_i).get_next(&source.meta) {
+        assert!(node_i < next_i);
+        node_i = next_i;
+    }
+
+    // 書き直した後も普通に使える
+    f.add_row("user", row(1)).unwrap();
+    f.flush();
+    drop(f);
+    let f = File::open(filepath).unwrap();
+    assert_eq!(f.check_integrity(), Ok(()));
+    let rows = scan_all(&f, 0);
+    assert_eq!(rows.len(), 1000);
+    assert_eq!(rows[0], row(1));
+    let source_index = f.source_index("user", &["name".to_owned()]).unwrap();
+    let cursor = f.get_cursor_just(source_index, &vec![row(150)[1].clone()]);
+    assert_eq!(f.cursor_get_row(&cursor), Some(row(150)));
+}
+
+#[test]
+fn test_mmap_backend() {
+    let dir = TestDir::new("test_mmap_backend");
+    let filepath = &dir.path("test_mmap_backend.rdb");
+    let table = user_table();
+    let read_ids = |f: &File| {
+        let source_index = f.source_index("user", &["id".to_owned()]).unwrap();
+        scan_all(f, source_index)
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect::<Vec<_>>()
+    };
+    let root_i;
+    {
+        let mut f = File::open_with_backend(filepath, Backend::Mmap).unwrap();
+        assert_eq!(f.pager.backend(), Backend::Mmap);
+        // 追い出されたページをマップから読み直すように小さくする
+        f.set_cache_capacity(4);
+        f.add_table(table);
+        root_i = f.sources[0].page_index;
+        for i in 0..500 {
+            f.add_row(
+                "user",
+                vec![Data::U64(i), Data::String(format!("user{}", i).repeat(10))],
+            )
+            .unwrap();
+            // ファイルが伸びるたびにマップし直す
+            if i.is_multiple_of(100) {
+                f.flush();
+            }
+        }
+        f.flush();
+        assert_eq!(read_ids(&f), (0..500).map(Data::U64).collect::<Vec<_>>());
+        assert_eq!(f.check_integrity(), Ok(()));
+    }
+    {
+        // どちらの方法で開いても同じ内容が読める
+        let f = File::open(filepath).unwrap();
+        assert_eq!(read_ids(&f), (0..500).map(Data::U64).collect::<Vec<_>>());
+        let f = File::open_with_backend(filepath, Backend::Mmap).unwrap();
+        assert_eq!(read_ids(&f), (0..500).map(Data::U64).collect::<Vec<_>>());
+        let source_index = f.source_index("user", &["name".to_owned()]).unwrap();
+        let cursor = f.get_cursor_just(source_index, &vec![Data::String("user42".repeat(10))]);
+        assert_eq!(
+            f.cursor_get_row(&cursor),
+            Some(vec![Data::U64(42), Data::String("user42".repeat(10))])
+        );
+    }
+    {
+        // マップから読んでもチェックサムを検査する
+        use std::io::{Seek, SeekFrom, Write};
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(filepath)
+            .unwrap();
+        file.seek(SeekFrom::Start(
+            root_i as u64 * (pager::DEFAULT_PAGE_SIZE as u64 + 8) + 100,
+        ))
+        .unwrap();
+        file.write_all(&[0xff; 4]).unwrap();
+    }
+    let f = File::open_with_backend(filepath, Backend::Mmap).unwrap();
+    assert_eq!(
+        f.check_integrity(),
+        Err(vec![format!(
+            "page {} is corrupted (checksum mismatch)",
+            root_i
+        )])
+    );
+}
+
+#[test]
+fn test_page_size() {
+    let dir = TestDir::new("test_page_size");
+    let table = user_table();
+    assert!(File::open_with(
+        &dir.path("test_page_size_invalid.rdb"),
+        OpenOptions {
+            page_size: 5000,
+            ..OpenOptions::default()
+        }
+    )
+    .is_err());
+    // 64K ではオフセットが 4 バイトになる
+    for &page_size in &[8 * 1024, 64 * 1024] {
+        let filepath = dir.path(&format!("test_page_size_{}.rdb", page_size));
+        {
+            let mut f = File::open_with(
+                &filepath,
+                OpenOptions {
+                    page_size,
+                    ..OpenOptions::default()
+                },
+            )
+            .unwrap();
+            f.add_table(table.clone());
+            for i in 0..2000 {
+                f.add_row(
+                    "user",
+                    vec![Data::U64(i), Data::String(format!("user{}", i).repeat(20))],
+                )
+                .unwrap();
+            }
+            f.flush();
+            assert_eq!(f.check_integrity(), Ok(()));
+        }
+        {
+            // 既にあるファイルはヘッダの大きさで開く
+            let f = File::open(&filepath).unwrap();
+            assert_eq!(f.pager.page_size(), page_size);
+            assert_eq!(f.check_integrity(), Ok(()));
+            let source_index = f.source_index("user", &["id".to_owned()]).unwrap();
+            let ids: Vec<_> = scan_all(&f, source_index)
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect();
+            assert_eq!(ids, (0..2000).map(Data::U64).collect::<Vec<_>>());
+            let source_index = f.source_index("user", &["name".to_owned()]).unwrap();
+            let cursor = f.get_cursor_just(source_index, &vec![Data::String("user42".repeat(20))]);
+            assert_eq!(f.cursor_get_row(&cursor).unwrap()[0], Data::U64(42));
+        }
+    }
+}
+
+#[test]
+fn test_compression() {
+    let table = email_table();
+    let row = |i: u64| {
+        vec![
+            Data::U64(i),
+            Data::String(format!("user{:05}@mail.example.com", i)),
+        ]
+    };
+    let dir = TestDir::new("test_compression_file");
+    let filepath = &dir.path("test_compression_file.rdb");
+    let plain_path = &dir.path("test_compression_plain.rdb");
+    let backup_path = &dir.path("test_compression_backup.rdb");
+    let mut lens = vec![];
+    for (path, compression) in [(plain_path, false), (filepath, true)] {
+        let mut f = File::open_with(
+            path,
+            OpenOptions {
+                compression,
+                ..OpenOptions::default()
+            },
+        )
+        .unwrap();
+        f.set_cache_capacity(8);
+        f.add_table(table.clone());
+        for i in 0..3000 {
+            f.add_row("user", row(i)).unwrap();
+            if i % 500 == 0 {
+                f.flush();
+            }
+        }
+        f.flush();
+        assert_eq!(f.pager.compression(), compression);
+        lens.push(std::fs::metadata(path).unwrap().len());
+    }
+    assert!(lens[1] < lens[0] / 2);
+
+    let expected: Vec<_> = (0..3000).map(row).collect();
+    {
+        let f = File::open_with_backend(filepath, Backend::Mmap).unwrap();
+        assert!(f.pager.compression());
+        assert_eq!(f.check_integrity(), Ok(()));
+        assert_eq!(scan_all(&f, 0), expected);
+        let snapshot = f.pager.snapshot();
+        f.backup_to(&snapshot, backup_path).unwrap();
+    }
+    {
+        let f = File::open(backup_path).unwrap();
+        assert!(f.pager.compression());
+        assert_eq!(f.check_integrity(), Ok(()));
+        assert_eq!(scan_all(&f, 0), expected);
+    }
+    {
+        // 消して詰め直しても圧縮したまま
+        let mut f = File::open(filepath).unwrap();
+        let mut cursor = f.get_cursor_first(0);
+        while !f.cursor_is_end(&cursor) {
+            if matches!(f.cursor_get_row(&cursor).unwrap()[0], Data::U64(i) if i % 2 == 0) {
+                f.cursor_delete(&mut cursor);
+                f.cursor_next_occupied(&mut cursor);
+            } else {
+                f.cursor_advance(&mut cursor);
+            }
+        }
+        f.flush();
+        f.vacuum().unwrap();
+        assert!(f.pager.compression());
+        assert!(std::fs::metadata(filepath).unwrap().len() < lens[1]);
+    }
+    let f = File::open(filepath).unwrap();
+    assert_eq!(f.check_integrity(), Ok(()));
+    let expected: Vec<_> = (0..1500).map(|i| row(i * 2 + 1)).collect();
+    assert_eq!(scan_all(&f, 0), expected);
+    let source_index = f.source_index("user", &["email".to_owned()]).unwrap();
+    let cursor = f.get_cursor_just(source_index, &vec![row(101)[1].clone()]);
+    assert_eq!(f.cursor_get_row(&cursor), Some(row(101)));
+}
+
+#[test]
+fn test_encryption() {
+    let dir = TestDir::new("test_encryption");
+    let table = email_table();
+    let row = |i: u64| {
+        vec![
+            Data::U64(i),
+            Data::String(format!("secret{:05}@mail.example.com", i)),
+        ]
+    };
+    let contains_secret = |path: &str| {
+        let bytes = std::fs::read(path).unwrap();
+        bytes.windows(6).any(|w| w == b"secret")
+    };
+    let options = |compression| OpenOptions {
+        compression,
+        passphrase: Some("correct horse".to_owned()),
+        ..OpenOptions::default()
+    };
+    let expected: Vec<_> = (0..2000).map(row).collect();
+    for compression in [false, true] {
+        let filepath = dir.path(&format!("test_encryption_{}.rdb", compression));
+        let backup_path = dir.path(&format!("test_encryption_{}_backup.rdb", compression));
+        {
+            let mut f = File::open_with(&filepath, options(compression)).unwrap();
+            f.set_cache_capacity(4);
+            f.add_table(table.clone());
+            for i in 0..2000 {
+                f.add_row("user", row(i)).unwrap();
+            }
+            // 追い出されたページは wal にある
+            assert!(0 < std::fs::metadata(pager::wal_path(&filepath)).unwrap().len());
+            assert!(!contains_secret(&pager::wal_path(&filepath)));
+            f.flush();
+            assert!(!contains_secret(&filepath));
+        }
+
+        // 鍵が違えば木を読む前に失敗する
+        let err = File::open(&filepath).err().unwrap();
+        assert!(err.contains("passphrase is required"), "{}", err);
+        let err = File::open_encrypted(&filepath, "wrong horse")
+            .err()
+            .unwrap();
+        assert!(err.contains("wrong passphrase"), "{}", err);
+
+        {
+            let mut f = File::open_encrypted(&filepath, "correct horse").unwrap();
+            assert_eq!(f.pager.compression(), compression);
+            assert_eq!(f.check_integrity(), Ok(()));
+            assert_eq!(scan_all(&f, 0), expected);
+            let snapshot = f.pager.snapshot();
+            f.backup_to(&snapshot, &backup_path).unwrap();
+            drop(snapshot);
+            f.vacuum().unwrap();
+            assert_eq!(scan_all(&f, 0), expected);
+        }
+        assert!(!contains_secret(&filepath));
+        assert!(!contains_secret(&backup_path));
+        for path in [&filepath, &backup_path] {
+            let f = File::open_encrypted(path, "correct horse").unwrap();
+            assert_eq!(f.check_integrity(), Ok(()));
+            assert_eq!(scan_all(&f, 0), expected);
+            let source_index = f.source_index("user", &["email".to_owned()]).unwrap();
+            let cursor = f.get_cursor_just(source_index, &vec![row(42)[1].clone()]);
+            assert_eq!(f.cursor_get_row(&cursor), Some(row(42)));
+        }
+    }
+
+    // 暗号化されていないファイルにパスフレーズを渡すのは間違い
+    let dir = TestDir::new("test_encryption_plain");
+    let filepath = &dir.path("test_encryption_plain.rdb");
+    File::open(filepath).unwrap().flush();
+    let err = File::open_encrypted(filepath, "correct horse")
+        .err()
+        .unwrap();
+    assert!(err.contains("not encrypted"), "{}", err);
+}
@@ -1,4 +1,4 @@
-use super::pager::{PageRaw, PAGE_SIZE};
+use super::pager::PageRaw;
 
 pub struct Page {
     raw: PageRaw,
@@ -11,7 +11,7 @@ impl From<PageRaw> for Page {
 }
 
 impl std::ops::Deref for Page {
-    type Target = PageRaw;
+    type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
         &self.raw
@@ -26,7 +26,7 @@ impl std::ops::DerefMut for Page {
 
 impl std::fmt::Debug for Page {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for x in &self.raw {
+        for x in self.raw.iter() {
             write!(f, "{} ", x)?;
         }
         write!(f, "\n")
@@ -34,8 +34,12 @@ impl std::fmt::Debug for Page {
 }
 
 impl Page {
-    pub fn new_leaf() -> Self {
-        let mut page = Page::from([0; PAGE_SIZE as usize]);
+    pub fn new(page_size: usize) -> Self {
+        Page::from(vec![0; page_size].into_boxed_slice())
+    }
+
+    pub fn new_leaf(page_size: usize) -> Self {
+        let mut page = Page::new(page_size);
         page[0] = 1;
         page
     }
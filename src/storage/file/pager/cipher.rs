@@ -0,0 +1,129 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// # sealed page
+// [NONCE_SIZE] nonce (書くたびにランダム)
+// [n] ciphertext
+// [TAG_SIZE] tag
+//
+// ページ番号を関連データにするので、別の位置に置かれたページは復号できない
+// 書かれていないページも 0 のページを暗号化して置くので、ファイルの中のページはすべて検証される
+// ページごとの版は持たないので、同じ位置の古いページに戻されたことは検出できない
+
+pub const SALT_SIZE: usize = 16;
+pub const KEY_CHECK_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+// 暗号化で増える大きさ
+pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+// PBKDF2-HMAC-SHA256
+const KDF_ITERATIONS: u32 = 100_000;
+
+// パスフレーズとソルトから作った鍵
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    key_check: [u8; KEY_CHECK_SIZE],
+}
+
+impl Cipher {
+    pub fn new(passphrase: &str, salt: [u8; SALT_SIZE]) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, KDF_ITERATIONS, &mut key);
+        // 鍵そのものではなく、鍵のハッシュをヘッダに置いて照合する
+        let mut key_check = [0; KEY_CHECK_SIZE];
+        key_check.copy_from_slice(
+            &Sha256::new()
+                .chain_update(b"rdb key check")
+                .chain_update(key)
+                .finalize()[..KEY_CHECK_SIZE],
+        );
+        Self {
+            aead: ChaCha20Poly1305::new(&key.into()),
+            salt,
+            key_check,
+        }
+    }
+
+    pub fn random_salt() -> [u8; SALT_SIZE] {
+        let mut salt = [0; SALT_SIZE];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    pub fn salt(&self) -> [u8; SALT_SIZE] {
+        self.salt
+    }
+
+    pub fn key_check(&self) -> [u8; KEY_CHECK_SIZE] {
+        self.key_check
+    }
+
+    pub fn seal(&self, i: usize, plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = self
+            .aead
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plain,
+                    aad: &(i as u32).to_le_bytes(),
+                },
+            )
+            .unwrap();
+        let mut bytes = Vec::with_capacity(plain.len() + OVERHEAD);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend(sealed);
+        bytes
+    }
+
+    pub fn open(&self, i: usize, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < OVERHEAD {
+            return Err(format!("page {} is too short to decrypt", i));
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_SIZE);
+        self.aead
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &(i as u32).to_le_bytes(),
+                },
+            )
+            .map_err(|_| format!("page {} cannot be decrypted (authentication failed)", i))
+    }
+}
+
+#[test]
+fn test() {
+    let salt = Cipher::random_salt();
+    let cipher = Cipher::new("correct horse", salt);
+    let sealed = cipher.seal(3, b"alice@example.com");
+    assert_eq!(sealed.len(), 17 + OVERHEAD);
+    assert_eq!(cipher.open(3, &sealed).unwrap(), b"alice@example.com");
+    // 同じ内容でも毎回違う
+    assert_ne!(cipher.seal(3, b"alice@example.com"), sealed);
+
+    // 別の位置、改ざん、違う鍵
+    assert!(cipher.open(4, &sealed).is_err());
+    let mut tampered = sealed.clone();
+    tampered[NONCE_SIZE] ^= 1;
+    assert!(cipher.open(3, &tampered).is_err());
+    let other = Cipher::new("wrong horse", salt);
+    assert_ne!(other.key_check(), cipher.key_check());
+    assert!(other.open(3, &sealed).is_err());
+    assert_eq!(
+        Cipher::new("correct horse", salt).key_check(),
+        cipher.key_check()
+    );
+    assert_ne!(
+        Cipher::new("correct horse", Cipher::random_salt()).key_check(),
+        cipher.key_check()
+    );
+}
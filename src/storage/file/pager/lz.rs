@@ -0,0 +1,167 @@
+use std::convert::TryInto;
+
+// LZ4 のブロック形式に近い LZ77 の符号化
+//
+// # sequence
+// [1] token (上位 4 ビットがリテラルの長さ、下位 4 ビットが一致の長さ - MIN_MATCH)
+// [n] リテラルの長さの続き (15 のとき、255 未満のバイトが来るまで足す)
+// [literal length] リテラル
+// [2] 一致の距離 (little endian)
+// [n] 一致の長さの続き
+//
+// 最後の sequence はリテラルだけで、距離を持たない
+
+const MIN_MATCH: usize = 4;
+const MAX_DISTANCE: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    // 4 バイトのハッシュ -> 最後に現れた位置
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input.len() {
+        let seq = read_u32(input, i);
+        let h = hash(seq);
+        let candidate = table[h];
+        table[h] = i;
+        if candidate != usize::MAX
+            && i - candidate <= MAX_DISTANCE
+            && read_u32(input, candidate) == seq
+        {
+            let mut len = MIN_MATCH;
+            while i + len < input.len() && input[candidate + len] == input[i + len] {
+                len += 1;
+            }
+            write_sequence(&mut out, &input[anchor..i], Some((i - candidate, len)));
+            i += len;
+            anchor = i;
+        } else {
+            i += 1;
+        }
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+// 壊れた入力でも panic せずにエラーを返す
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    loop {
+        let token = *input.get(i).ok_or("unexpected end of compressed data")?;
+        i += 1;
+        let literal_len = read_len(input, &mut i, (token >> 4) as usize)?;
+        let literals = input
+            .get(i..i + literal_len)
+            .ok_or("literals overrun compressed data")?;
+        out.extend_from_slice(literals);
+        i += literal_len;
+        if i == input.len() {
+            break;
+        }
+        let distance = input
+            .get(i..i + 2)
+            .map(|b| u16::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or("unexpected end of compressed data")?;
+        i += 2;
+        let match_len = read_len(input, &mut i, (token & 0xf) as usize)? + MIN_MATCH;
+        if distance == 0 || out.len() < distance {
+            return Err(format!("invalid match distance {}", distance));
+        }
+        if len < out.len() + match_len {
+            return Err("decompressed data is too long".to_owned());
+        }
+        // 重なっていてもよいので 1 バイトずつコピーする
+        let start = out.len() - distance;
+        for k in 0..match_len {
+            out.push(out[start + k]);
+        }
+    }
+    if out.len() != len {
+        return Err(format!(
+            "decompressed {} bytes, expected {}",
+            out.len(),
+            len
+        ));
+    }
+    Ok(out)
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize, usize)>) {
+    let match_len = m.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    write_len(out, literals.len());
+    out.extend_from_slice(literals);
+    if let Some((distance, _)) = m {
+        out.extend((distance as u16).to_le_bytes());
+        write_len(out, match_len);
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while 255 <= rest {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn read_len(input: &[u8], i: &mut usize, nibble: usize) -> Result<usize, String> {
+    let mut len = nibble;
+    if nibble == 15 {
+        loop {
+            let b = *input.get(*i).ok_or("unexpected end of compressed data")?;
+            *i += 1;
+            len += b as usize;
+            if b != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+fn read_u32(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap())
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+#[test]
+fn test() {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+    let mut inputs: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![1, 2, 3],
+        vec![0; 4096],
+        b"alice@example.com,bob@example.com,carol@example.com"
+            .repeat(100)
+            .to_vec(),
+        (0..70000).map(|i| (i % 251) as u8).collect(),
+    ];
+    inputs.push((0..4096).map(|_| rng.gen()).collect());
+    inputs.push((0..4096).map(|_| rng.gen_range(0..4)).collect());
+    for input in inputs {
+        let compressed = compress(&input);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+    }
+    assert!(compress(&[0; 4096]).len() < 64);
+
+    // 壊れた入力
+    let compressed = compress(&b"hello hello hello hello".repeat(10));
+    for n in 0..compressed.len() {
+        assert!(decompress(&compressed[..n], 230).is_err());
+    }
+    assert!(decompress(&compressed, 229).is_err());
+    assert!(decompress(&[0x0f, 0, 0], 100).is_err());
+}
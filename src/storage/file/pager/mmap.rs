@@ -0,0 +1,83 @@
+use std::{fs::File, os::unix::io::AsRawFd};
+
+// ファイル全体を読み取り専用で共有マップする
+// write で書き込んだ内容もページキャッシュを通してそのまま見える
+// ファイルが伸びたら remap し直す
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    pub fn new() -> Self {
+        Mmap {
+            ptr: std::ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    pub fn remap(&mut self, file: &File, len: u64) -> std::io::Result<()> {
+        if self.len as u64 == len {
+            return Ok(());
+        }
+        self.unmap();
+        // 長さ 0 ではマップできない
+        if len == 0 {
+            return Ok(());
+        }
+        // SAFETY: file は開いている。マップは unmap するまで self が持つ
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.ptr = ptr;
+        self.len = len as usize;
+        Ok(())
+    }
+
+    // マップの外は読めない
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        let offset = offset as usize;
+        if self.len < offset + buf.len() {
+            return Err(format!(
+                "cannot read {} bytes at {} (mapped {} bytes)",
+                buf.len(),
+                offset,
+                self.len
+            ));
+        }
+        // マップしていないとき ptr は null なので、長さ 0 でも from_raw_parts に渡さない
+        if buf.is_empty() {
+            return Ok(());
+        }
+        // SAFETY: offset + buf.len() はマップの範囲に収まっていて、マップは読み取り専用
+        let src =
+            unsafe { std::slice::from_raw_parts(self.ptr.cast::<u8>().add(offset), buf.len()) };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn unmap(&mut self) {
+        if 0 < self.len {
+            // SAFETY: ptr と len は remap でマップしたもので、まだ unmap していない
+            unsafe { libc::munmap(self.ptr, self.len) };
+            self.ptr = std::ptr::null_mut();
+            self.len = 0;
+        }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
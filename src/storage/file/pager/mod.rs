@@ -0,0 +1,1276 @@
+mod cipher;
+mod lz;
+mod mmap;
+mod page_map;
+mod wal;
+
+use std::{
+    borrow::Cow,
+    cell::UnsafeCell,
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    fs::File,
+    io::{Seek, SeekFrom},
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread::{self, ThreadId},
+};
+
+pub use self::cipher::{Cipher, KEY_CHECK_SIZE, SALT_SIZE};
+use self::mmap::Mmap;
+use self::page_map::PageMap;
+pub use self::wal::wal_path;
+use self::wal::{checksum, Wal, CHECKSUM_INIT};
+
+// ページの大きさはデータベースを作るときに決め、ヘッダに書く
+pub const DEFAULT_PAGE_SIZE: usize = 4 * 1024;
+pub const MIN_PAGE_SIZE: usize = 4 * 1024;
+pub const MAX_PAGE_SIZE: usize = 64 * 1024;
+// checksum が有効なファイルでは、各ページの後ろにチェックサムを置く
+const CHECKSUM_SIZE: u64 = 8;
+
+// 長さはページの大きさ
+pub type PageRaw = Box<[u8]>;
+
+pub trait Page:
+    From<PageRaw> + std::ops::Deref<Target = [u8]> + std::ops::DerefMut<Target = [u8]>
+{
+}
+
+impl<P> Page for P where
+    P: From<PageRaw> + std::ops::Deref<Target = [u8]> + std::ops::DerefMut<Target = [u8]>
+{
+}
+
+pub fn is_valid_page_size(page_size: usize) -> bool {
+    (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) && page_size.is_power_of_two()
+}
+
+fn zero_page(page_size: usize) -> PageRaw {
+    vec![0; page_size].into_boxed_slice()
+}
+
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+// ファイルからページを読む方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    // ページごとに seek して read する
+    Read,
+    // ファイルをマップしてコピーする
+    // 読み込みが多いときにシステムコールを減らせる
+    Mmap,
+}
+
+#[derive(Debug)]
+struct PageContainer<P: Page> {
+    page: UnsafeCell<P>,
+    // ページの中身を守るラッチ
+    // 読むときは共有で、書き換えるときは排他で取る
+    latch: RwLock<()>,
+    modified: bool,
+    pins: usize,
+    last_used: u64,
+}
+
+// 複数のスレッドが snapshot から読みながら、一つのスレッドが書き込める
+// 書き込むスレッドは同時に一つだけで、木を書き換える間は lock_writer のガードを持つ
+pub struct Pager<P: Page> {
+    filepath: String,
+    page_size: usize,
+    // ページごとのチェックサムを持つ形式か
+    checksum: bool,
+    // 暗号化するファイルの鍵
+    cipher: Option<Cipher>,
+    pool: Mutex<Pool<P>>,
+    versions: Arc<Mutex<Versions<P>>>,
+    writer: Mutex<()>,
+    // writer のロックを持っているスレッド
+    writer_thread: Mutex<Option<ThreadId>>,
+}
+
+// # MVCC
+// snapshot を取った後に初めて書き換えられるページは、書き換え前の内容をその時点の
+// 最新の snapshot の版として残す (copy-on-write)
+// snapshot s から読むときは、s 以降の版のうち最も古いものを使い、なければ現在のページを使う
+// 版は、それを読む snapshot がなくなると捨てられる
+// 残る版はページごとに生きている snapshot の数までなので、snapshot が生きている間の
+// 大きさは (snapshot の数) x (その時点のページ数) ページで抑えられる
+// 長く生きる snapshot があると、その間に書き換えたページの分だけメモリを使い続ける
+struct Versions<P: Page> {
+    next_id: u64,
+    // snapshot id -> その時点のページ数
+    live: BTreeMap<u64, usize>,
+    // ページごとの (snapshot id, 書き換え前の内容)、id の昇順
+    pages: HashMap<usize, Vec<(u64, Box<P>)>>,
+}
+
+// 生きている間は取った時点のページが残る
+pub struct Snapshot<P: Page> {
+    id: u64,
+    pages_num: usize,
+    // 取った時点の空きページの連結リストの先頭
+    free_head: usize,
+    versions: Arc<Mutex<Versions<P>>>,
+}
+
+// バッファプール
+// ピンされていないページのみ LRU で追い出す
+// 変更されたページはコミットされていないフレームとして wal に退避する
+// ページの中身以外はすべてこのロックで守る
+struct Pool<P: Page> {
+    file: File,
+    page_size: usize,
+    file_len: u64,
+    // 圧縮するファイルでのページの置き場所
+    // None なら各ページは固定長の領域に並ぶ
+    map: Option<PageMap>,
+    cipher: Option<Cipher>,
+    // Backend::Mmap のときのマップ
+    // 常にファイル全体 (file_len) をマップしている
+    mmap: Option<Mmap>,
+    pages_num: usize,
+    // 空きページの連結リストの先頭 (0 なら空)
+    // 空きページの先頭4バイトに次の空きページを持つ
+    free_head: usize,
+    capacity: usize,
+    // begin した時点の (pages_num, free_head)
+    snapshot: Option<(usize, usize)>,
+    wal: Wal,
+    frames: HashMap<usize, *mut PageContainer<P>>,
+    spilled: HashMap<usize, u64>,
+    tick: u64,
+}
+
+// フレームは生ポインタで持つが、次を守るのでスレッド間で共有できる
+// - フレームは Box で確保し、frames から外すときにだけ解放する
+//   外すのはピンされていないとき (evict) か、&mut self で参照がないとき (rollback, drop) だけ
+// - frames とフレームの pins, modified, last_used は pool のロックを持って読み書きする
+// - ページの中身はラッチで守る。PageRef は共有ラッチを、PageMut は排他ラッチを持つ
+//   ただし save は writer のロックを持つスレッドが PageMut を持たずに呼ぶので、ラッチなしで読める
+// - 古い版は Versions の Box にあり、それを読む snapshot が生きている間は解放されない
+// ラッチは一つのページしか守らないので、木の書き換え (分割や削除) のように複数のページに
+// またがる書き込みは lock_writer で一つのスレッドに限らなければならない
+// 同じ理由で、書き込みの途中の木を今のページから読むと壊れて見える
+// 書き込みと同時に読むスレッドは snapshot から読む (get_ref_at)
+// 版は書き換える前に排他ラッチの中で残すので、snapshot からはどのページも取った時点の内容に見える
+unsafe impl<P: Page + Send> Send for Pager<P> {}
+unsafe impl<P: Page + Send + Sync> Sync for Pager<P> {}
+
+// ピンされたページへの参照
+// 生きている間はページが追い出されず、共有ラッチを持つ
+pub struct PageRef<'a, P: Page> {
+    pager: &'a Pager<P>,
+    i: usize,
+    page: *const P,
+    // 古い版を指すときはピンもラッチもしない
+    latch: Option<RwLockReadGuard<'a, ()>>,
+}
+
+// 書き換えるページへの参照
+// 生きている間は排他ラッチを持つ
+pub struct PageMut<'a, P: Page> {
+    pager: &'a Pager<P>,
+    i: usize,
+    page: *mut P,
+    latch: Option<RwLockWriteGuard<'a, ()>>,
+}
+
+// 書き込むスレッドであることを示す
+// 生きている間は他のスレッドが lock_writer で待つ
+pub struct WriterGuard<'a, P: Page> {
+    pager: &'a Pager<P>,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl<P: Page> Pager<P> {
+    pub fn open(filepath: &str, page_size: usize, checksum: bool) -> Self {
+        Self::open_with_backend(filepath, page_size, checksum, false, None, Backend::Read)
+    }
+
+    // compression なら page 0 以外のページを圧縮して可変長の領域に置く
+    // cipher があれば page 0 (ヘッダ) 以外のページと wal のフレームを暗号化する
+    // 圧縮や暗号化をするファイルは常にチェックサムを持つ
+    pub fn open_with_backend(
+        filepath: &str,
+        page_size: usize,
+        checksum: bool,
+        compression: bool,
+        cipher: Option<Cipher>,
+        backend: Backend,
+    ) -> Self {
+        assert!(
+            is_valid_page_size(page_size),
+            "invalid page size {}",
+            page_size
+        );
+        assert!(checksum || !(compression || cipher.is_some()));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(filepath)
+            .unwrap();
+        let map = if compression {
+            Some(PageMap::open(&mut file, page_size).unwrap_or_else(|e| panic!("{}", e)))
+        } else {
+            None
+        };
+        let mut pager = Self {
+            filepath: filepath.to_owned(),
+            page_size,
+            checksum,
+            cipher: cipher.clone(),
+            pool: Mutex::new(Pool {
+                file,
+                page_size,
+                file_len: 0,
+                map,
+                cipher: cipher.clone(),
+                mmap: match backend {
+                    Backend::Read => None,
+                    Backend::Mmap => Some(Mmap::new()),
+                },
+                pages_num: 0,
+                free_head: 0,
+                capacity: DEFAULT_CAPACITY,
+                snapshot: None,
+                wal: Wal::open(&wal_path(filepath), page_size, cipher),
+                frames: HashMap::new(),
+                spilled: HashMap::new(),
+                tick: 0,
+            }),
+            versions: Arc::new(Mutex::new(Versions {
+                next_id: 0,
+                live: BTreeMap::new(),
+                pages: HashMap::new(),
+            })),
+            writer: Mutex::new(()),
+            writer_thread: Mutex::new(None),
+        };
+        pager.recover();
+
+        let pool = pager.pool.get_mut().unwrap();
+        pool.update_file_len();
+        pool.pages_num = match &pool.map {
+            Some(map) => map.len(),
+            None => {
+                (pool.file_len / slot_size(page_size, checksum, pool.cipher.is_some())) as usize
+            }
+        };
+        pager
+    }
+
+    // wal に残っているコミット済みのページをファイルに書き戻す
+    fn recover(&mut self) {
+        let pool = self.pool.get_mut().unwrap();
+        let pages = pool.wal.committed_pages();
+        if !pages.is_empty() {
+            let mut pages_num = pool.map.as_ref().map_or(0, |map| map.len());
+            for (i, page) in pages {
+                pool.write_page(self.checksum, i, &page);
+                pages_num = pages_num.max(i + 1);
+            }
+            if let Some(map) = &mut pool.map {
+                map.commit(&mut pool.file, pool.cipher.as_ref(), pages_num);
+            }
+            pool.file.sync_data().unwrap();
+        }
+        pool.wal.reset();
+    }
+
+    // 同じスレッドで二重に取ると止まる
+    pub fn lock_writer(&self) -> WriterGuard<'_, P> {
+        let lock = self.writer.lock().unwrap();
+        *self.writer_thread.lock().unwrap() = Some(thread::current().id());
+        WriterGuard {
+            pager: self,
+            _lock: lock,
+        }
+    }
+
+    // このスレッドが lock_writer のガードを持っているか
+    pub fn holds_writer(&self) -> bool {
+        *self.writer_thread.lock().unwrap() == Some(thread::current().id())
+    }
+
+    fn pool(&self) -> MutexGuard<'_, Pool<P>> {
+        self.pool.lock().unwrap()
+    }
+
+    pub fn filepath(&self) -> &str {
+        &self.filepath
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn compression(&self) -> bool {
+        self.pool().map.is_some()
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+
+    pub fn backend(&self) -> Backend {
+        if self.pool().mmap.is_some() {
+            Backend::Mmap
+        } else {
+            Backend::Read
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.pool().pages_num
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.pool().capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(0 < capacity);
+        let pool = self.pool.get_mut().unwrap();
+        pool.capacity = capacity;
+        pool.evict(capacity);
+    }
+
+    // プールに載っているページの数
+    pub fn resident_size(&self) -> usize {
+        self.pool().frames.len()
+    }
+
+    pub fn ensure_page(&self, i: usize) {
+        let container = self.pin(i, true);
+        // SAFETY: 直前にピンしたフレームで、ラッチは取っていない
+        unsafe { self.unpin(container) };
+    }
+
+    // ページをプールに載せてピンする
+    // append なら末尾の次のページを追加できる
+    fn pin(&self, i: usize, append: bool) -> *mut PageContainer<P> {
+        let mut pool = self.pool();
+        if append && pool.pages_num == i {
+            pool.pages_num += 1;
+            pool.insert(i, P::from(zero_page(self.page_size)), false);
+        }
+        assert!(i < pool.pages_num, "page {} is out of range", i);
+        let container = pool.load(self.checksum, i);
+        // SAFETY: load が返したフレームは frames にあり、pool のロックを持っている
+        unsafe { (*container).pins += 1 };
+        container
+    }
+
+    // ラッチを外してから呼ぶ
+    // ピンが外れたフレームは追い出されうる
+    // # Safety
+    // container は pin で取り、まだ unpin していないフレームでなければならない
+    unsafe fn unpin(&self, container: *mut PageContainer<P>) {
+        let _pool = self.pool();
+        // SAFETY: ピンされているので解放されておらず、pool のロックを持っている
+        (*container).pins -= 1;
+    }
+
+    pub fn get_ref(&self, i: usize) -> PageRef<'_, P> {
+        // SAFETY: ピンしたフレームは PageRef が unpin するまで解放されない
+        let container = unsafe { &*self.pin(i, false) };
+        let latch = container.latch.read().unwrap();
+        PageRef {
+            pager: self,
+            i,
+            page: container.page.get(),
+            latch: Some(latch),
+        }
+    }
+
+    // snapshot を取った時点のページを読む
+    pub fn get_ref_at(&self, i: usize, snapshot: Option<u64>) -> PageRef<'_, P> {
+        let page_ref = self.get_ref(i);
+        if let Some(snapshot) = snapshot {
+            // ラッチを持ったまま版を探すので、書き換えの途中は見えない
+            let versions = self.versions.lock().unwrap();
+            if let Some((_, page)) = versions
+                .pages
+                .get(&i)
+                .and_then(|pages| pages.iter().find(|(id, _)| snapshot <= *id))
+            {
+                // 版は Box の中にあり、snapshot が解放されるまで動かない
+                // 呼ぶ側は PageRef より長く snapshot を持つ
+                let page: *const P = &**page;
+                drop(versions);
+                drop(page_ref);
+                return PageRef {
+                    pager: self,
+                    i,
+                    page,
+                    latch: None,
+                };
+            }
+        }
+        page_ref
+    }
+
+    // 生きている Snapshot があるか
+    pub fn has_snapshot(&self) -> bool {
+        !self.versions.lock().unwrap().live.is_empty()
+    }
+
+    pub fn snapshot(&self) -> Snapshot<P> {
+        let (pages_num, free_head) = {
+            let pool = self.pool();
+            (pool.pages_num, pool.free_head)
+        };
+        let mut versions = self.versions.lock().unwrap();
+        let id = versions.next_id;
+        versions.next_id += 1;
+        versions.live.insert(id, pages_num);
+        Snapshot {
+            id,
+            pages_num,
+            free_head,
+            versions: self.versions.clone(),
+        }
+    }
+
+    // snapshot の時点のページをすべて別のファイルに書き出す
+    // 書き出している間に変更されたページは snapshot が残した版を読む
+    pub fn write_snapshot(&self, snapshot: &Snapshot<P>, path: &str) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // 圧縮するファイルは同じ形式で書き出す
+        let mut map = if self.compression() {
+            Some(PageMap::new(self.page_size))
+        } else {
+            None
+        };
+        for i in 0..snapshot.pages_num {
+            let page: PageRaw = (**self.get_ref_at(i, Some(snapshot.id))).into();
+            match &mut map {
+                Some(map) => map.write_page(&mut file, self.cipher.as_ref(), i, &page),
+                None => write_page(&mut file, self.checksum, self.cipher.as_ref(), i, &page),
+            }
+        }
+        if let Some(map) = &mut map {
+            map.commit(&mut file, self.cipher.as_ref(), snapshot.pages_num);
+        }
+        file.sync_data()
+    }
+
+    // 古い版として残っているページの数
+    #[cfg(test)]
+    pub fn versions_size(&self) -> usize {
+        self.versions
+            .lock()
+            .unwrap()
+            .pages
+            .values()
+            .map(|v| v.len())
+            .sum()
+    }
+
+    // 書き換える前に、生きている snapshot のために今の内容を残す
+    // 排他ラッチを持って呼ぶ
+    fn preserve(&self, i: usize, page: &P) {
+        let mut versions = self.versions.lock().unwrap();
+        let (latest, pages_num) =
+            if let Some((latest, pages_num)) = versions.live.iter().next_back() {
+                (*latest, *pages_num)
+            } else {
+                return;
+            };
+        // snapshot の後に追加されたページは読まれない
+        if pages_num <= i {
+            return;
+        }
+        let preserved = versions
+            .pages
+            .get(&i)
+            .and_then(|pages| pages.last())
+            .is_some_and(|(id, _)| latest <= *id);
+        if !preserved {
+            let page: PageRaw = (**page).into();
+            versions
+                .pages
+                .entry(i)
+                .or_default()
+                .push((latest, Box::new(P::from(page))));
+        }
+    }
+
+    pub fn get_mut(&self, i: usize) -> PageMut<'_, P> {
+        let container = self.pin(i, true);
+        {
+            // modified は save や evict が pool のロックを持って読む
+            let _pool = self.pool();
+            // SAFETY: ピンしたフレームで、pool のロックを持っている
+            unsafe { (*container).modified = true };
+        }
+        // SAFETY: ピンしたフレームは PageMut が unpin するまで解放されない
+        let container = unsafe { &*container };
+        let latch = container.latch.write().unwrap();
+        let page = container.page.get();
+        // SAFETY: 排他ラッチを持っているので、他に参照はない
+        self.preserve(i, unsafe { &*page });
+        PageMut {
+            pager: self,
+            i,
+            page,
+            latch: Some(latch),
+        }
+    }
+
+    pub fn push(&self, page: P) -> usize {
+        let i = self.allocate();
+        self.swap(i, page);
+        i
+    }
+
+    // 空きページがあれば再利用し、なければ末尾にページを追加する
+    pub fn allocate(&self) -> usize {
+        let mut pool = self.pool();
+        if pool.free_head == 0 {
+            let i = pool.pages_num;
+            pool.pages_num += 1;
+            pool.insert(i, P::from(zero_page(self.page_size)), true);
+            i
+        } else {
+            let i = pool.free_head;
+            drop(pool);
+            let mut page = self.get_mut(i);
+            let next = u32::from_le_bytes(page[0..4].try_into().unwrap()) as usize;
+            page.fill(0);
+            drop(page);
+            self.pool().free_head = next;
+            i
+        }
+    }
+
+    pub fn free(&self, i: usize) {
+        debug_assert!(0 < i && i < self.size());
+        let next = self.free_head();
+        let mut page = self.get_mut(i);
+        page.fill(0);
+        page[0..4].copy_from_slice(&(next as u32).to_le_bytes());
+        drop(page);
+        self.pool().free_head = i;
+    }
+
+    pub fn free_head(&self) -> usize {
+        self.pool().free_head
+    }
+
+    pub fn set_free_head(&self, i: usize) {
+        self.pool().free_head = i;
+    }
+
+    pub fn free_pages(&self) -> Vec<usize> {
+        let mut pages = vec![];
+        let mut i = self.free_head();
+        while i != 0 {
+            pages.push(i);
+            i = u32::from_le_bytes(self.get_ref(i)[0..4].try_into().unwrap()) as usize;
+        }
+        pages
+    }
+
+    pub fn swap(&self, i: usize, page: P) -> P {
+        debug_assert_eq!(page.len(), self.page_size);
+        std::mem::replace(&mut *self.get_mut(i), page)
+    }
+
+    // writer のロックを持ち、PageMut を持たずに呼ぶ (&mut self からでもよい)
+    // 他のスレッドはページを読んでいるだけなので、ラッチなしで中身を読める
+    pub fn save(&self) {
+        let mut pool = self.pool();
+        let pool = &mut *pool;
+        // SAFETY: pool のロックを持っている間はフレームは解放されず、
+        // 中身を書き換える PageMut もない
+        let modified_pages: Vec<_> = pool
+            .frames
+            .iter()
+            .map(|(i, c)| unsafe { (*i, &**c) })
+            .filter(|(_, c)| c.modified)
+            .map(|(i, c)| unsafe { (i, &**c.page.get()) })
+            .collect();
+        if modified_pages.is_empty() && pool.spilled.is_empty() {
+            return;
+        }
+        pool.wal.commit(modified_pages.into_iter());
+
+        // checkpoint
+        let spilled: Vec<_> = pool.spilled.drain().collect();
+        for (i, offset) in spilled {
+            let page = pool.wal.read_frame(i, offset);
+            pool.write_page(self.checksum, i, &page);
+        }
+        // SAFETY: 上と同じ
+        let modified: Vec<_> = pool
+            .frames
+            .iter()
+            .filter(|(_, c)| unsafe { (***c).modified })
+            .map(|(i, c)| (*i, *c))
+            .collect();
+        for (i, container) in modified {
+            // SAFETY: 上と同じ。modified は pool のロックを持っているので書き換えてよい
+            let container = unsafe { &mut *container };
+            pool.write_page(self.checksum, i, unsafe { &*container.page.get() });
+            container.modified = false;
+        }
+        if let Some(map) = &mut pool.map {
+            map.commit(&mut pool.file, pool.cipher.as_ref(), pool.pages_num);
+        }
+        pool.file.sync_data().unwrap();
+        pool.update_file_len();
+        pool.wal.reset();
+    }
+
+    // 以降の変更は commit までファイルに書かれない
+    // 退避されたページもコミットされていないフレームとして wal に置かれるだけ
+    pub fn begin(&mut self) {
+        self.save();
+        let pool = self.pool.get_mut().unwrap();
+        assert!(pool.snapshot.is_none(), "transaction is already begun");
+        pool.snapshot = Some((pool.pages_num, pool.free_head));
+    }
+
+    pub fn commit(&mut self) {
+        let pool = self.pool.get_mut().unwrap();
+        pool.snapshot.take().expect("transaction is not begun");
+        self.save();
+    }
+
+    // begin 以降に変更されたページを捨てる
+    pub fn rollback(&mut self) {
+        let pool = self.pool.get_mut().unwrap();
+        let (pages_num, free_head) = pool.snapshot.take().expect("transaction is not begun");
+        pool.frames.retain(|i, container| {
+            // SAFETY: &mut self なので PageRef や PageMut はなく、フレームを解放してよい
+            let discard = unsafe { (**container).modified } || pages_num <= *i;
+            if discard {
+                unsafe { drop(Box::from_raw(*container)) };
+            }
+            !discard
+        });
+        pool.spilled.clear();
+        pool.wal.reset();
+        pool.pages_num = pages_num;
+        pool.free_head = free_head;
+    }
+
+    // ディスク上のチェックサムが一致しないページを返す
+    // プールに載っているページは読み込み時に検査済み
+    pub fn corrupted_pages(&self) -> Vec<usize> {
+        let pool = self.pool();
+        (0..pool.pages_num)
+            .filter(|i| !pool.frames.contains_key(i) && !pool.spilled.contains_key(i))
+            .filter(|i| pool.read_page(self.checksum, *i).is_err())
+            .collect()
+    }
+
+    // チェックサム付きの形式でファイルを書き直し、置き換える
+    // 置き換えは rename なので、途中で落ちても元のファイルが残る
+    pub fn enable_checksum(&mut self) {
+        assert!(!self.checksum && !self.compression());
+        let tmp_path = format!("{}-tmp", self.filepath);
+        let mut tmp = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .unwrap();
+        for i in 0..self.size() {
+            let page: PageRaw = (**self.get_ref(i)).into();
+            write_page(&mut tmp, true, None, i, &page);
+        }
+        tmp.sync_data().unwrap();
+        std::fs::rename(&tmp_path, &self.filepath).unwrap();
+
+        self.checksum = true;
+        let pool = self.pool.get_mut().unwrap();
+        pool.file = tmp;
+        if let Some(mmap) = &mut pool.mmap {
+            // 古いファイルのマップを捨てる
+            *mmap = Mmap::new();
+        }
+        pool.update_file_len();
+        pool.spilled.clear();
+        for container in pool.frames.values() {
+            // SAFETY: &mut self なので他に参照はない
+            unsafe { (**container).modified = false };
+        }
+        pool.wal.reset();
+    }
+}
+
+// 固定長の形式での一つ分の大きさ
+fn slot_size(page_size: usize, checksum: bool, encrypted: bool) -> u64 {
+    let mut size = page_size as u64;
+    if encrypted {
+        size += cipher::OVERHEAD as u64;
+    }
+    if checksum {
+        size += CHECKSUM_SIZE;
+    }
+    size
+}
+
+// ページ番号も混ぜて、別の位置に書かれたページも検出する
+fn page_checksum(i: usize, page: &[u8]) -> u64 {
+    checksum(checksum(CHECKSUM_INIT, &(i as u32).to_le_bytes()), page)
+}
+
+// 暗号化するファイルでは、page 0 は暗号化せずに後ろを 0 で埋める
+// チェックサムは暗号化した後の内容にかける
+fn write_page(file: &mut File, checksum: bool, cipher: Option<&Cipher>, i: usize, page: &[u8]) {
+    let stored = match cipher {
+        Some(cipher) if i != 0 => Cow::Owned(cipher.seal(i, page)),
+        Some(_) => {
+            let mut stored = page.to_vec();
+            stored.resize(page.len() + cipher::OVERHEAD, 0);
+            Cow::Owned(stored)
+        }
+        None => Cow::Borrowed(page),
+    };
+    file.seek(SeekFrom::Start(
+        i as u64 * slot_size(page.len(), checksum, cipher.is_some()),
+    ))
+    .unwrap();
+    std::io::Write::write_all(file, &stored).unwrap();
+    if checksum {
+        std::io::Write::write_all(file, &page_checksum(i, &stored).to_le_bytes()).unwrap();
+    }
+}
+
+// 暗号化するファイルでは、飛ばしたページも 0 のページを暗号化して置く
+// ファイルの中の暗号化されていない領域は、すべて改ざんとして扱える
+fn seal_gap(file: &mut File, checksum: bool, cipher: &Cipher, i: usize, page_size: usize) {
+    let written = file.metadata().unwrap().len() / slot_size(page_size, checksum, true);
+    let zero = zero_page(page_size);
+    for j in written as usize..i {
+        write_page(file, checksum, Some(cipher), j, &zero);
+    }
+}
+
+// ページとチェックサムを合わせた一つ分
+// 暗号化しないファイルでは、飛ばしたページは全て 0 のまま残る
+fn verify_page(i: usize, slot: &[u8], encrypted: bool) -> Result<(), String> {
+    let (page, sum) = slot.split_at(slot.len() - CHECKSUM_SIZE as usize);
+    let sum = u64::from_le_bytes(sum.try_into().unwrap());
+    let unwritten = !encrypted && sum == 0 && page.iter().all(|b| *b == 0);
+    if sum != page_checksum(i, page) && !unwritten {
+        return Err(format!("page {} is corrupted (checksum mismatch)", i));
+    }
+    Ok(())
+}
+
+impl<P: Page> Drop for Pager<P> {
+    fn drop(&mut self) {
+        for (_, container) in self.pool.get_mut().unwrap().frames.drain() {
+            // SAFETY: Pager より長く生きる PageRef や PageMut はない
+            unsafe { drop(Box::from_raw(container)) };
+        }
+    }
+}
+
+impl<P: Page> Pool<P> {
+    fn load(&mut self, checksum: bool, i: usize) -> *mut PageContainer<P> {
+        self.tick += 1;
+        if let Some(container) = self.frames.get(&i) {
+            // SAFETY: frames にあるフレームで、pool のロックを持っている (&mut self)
+            unsafe { (**container).last_used = self.tick };
+            return *container;
+        }
+
+        let (page, modified) = if let Some(offset) = self.spilled.remove(&i) {
+            (self.wal.read_frame(i, offset), true)
+        } else {
+            let page = self
+                .read_page(checksum, i)
+                .unwrap_or_else(|e| panic!("{}", e));
+            (page, false)
+        };
+        self.insert(i, P::from(page), modified)
+    }
+
+    // 一度も書かれていない (全て 0 の) ページは正しいものとして扱う
+    // ただし暗号化するファイルでは、ファイルの中のページはすべて暗号化されていなければならない
+    // 圧縮したページや暗号化したページはここで元に戻す
+    fn read_page(&self, checksum: bool, i: usize) -> Result<PageRaw, String> {
+        if let Some(map) = &self.map {
+            return match map.locate(i) {
+                Some((offset, len)) => {
+                    let mut extent = vec![0; len + CHECKSUM_SIZE as usize];
+                    self.read_at(offset, &mut extent)?;
+                    Ok(map
+                        .decode(self.cipher.as_ref(), i, &extent)?
+                        .into_boxed_slice())
+                }
+                // 暗号化するファイルでは map にあるページはすべて書かれている
+                None if self.cipher.is_some() && i != 0 && i < map.len() => {
+                    Err(format!("page {} is corrupted (not encrypted)", i))
+                }
+                None => Ok(zero_page(self.page_size)),
+            };
+        }
+        let slot_size = slot_size(self.page_size, checksum, self.cipher.is_some());
+        let offset = i as u64 * slot_size;
+        if self.file_len <= offset {
+            return Ok(zero_page(self.page_size));
+        }
+        let mut slot = vec![0; slot_size as usize];
+        self.read_at(offset, &mut slot)?;
+        if checksum {
+            verify_page(i, &slot, self.cipher.is_some())?;
+            slot.truncate(slot.len() - CHECKSUM_SIZE as usize);
+        }
+        match &self.cipher {
+            Some(cipher) if i != 0 => Ok(cipher.open(i, &slot)?.into_boxed_slice()),
+            _ => {
+                slot.truncate(self.page_size);
+                Ok(slot.into_boxed_slice())
+            }
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        if let Some(mmap) = &self.mmap {
+            mmap.read(offset, buf)
+        } else {
+            let mut file = &self.file;
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            std::io::Read::read_exact(&mut file, buf).map_err(|e| e.to_string())
+        }
+    }
+
+    fn write_page(&mut self, checksum: bool, i: usize, page: &[u8]) {
+        match &mut self.map {
+            Some(map) => map.write_page(&mut self.file, self.cipher.as_ref(), i, page),
+            None => {
+                if let Some(cipher) = &self.cipher {
+                    seal_gap(&mut self.file, checksum, cipher, i, page.len());
+                }
+                write_page(&mut self.file, checksum, self.cipher.as_ref(), i, page)
+            }
+        }
+    }
+
+    // ファイルの長さが変わったらマップし直す
+    fn update_file_len(&mut self) {
+        self.file_len = self.file.metadata().unwrap().len();
+        if let Some(mmap) = &mut self.mmap {
+            mmap.remap(&self.file, self.file_len).unwrap();
+        }
+    }
+
+    fn insert(&mut self, i: usize, page: P, modified: bool) -> *mut PageContainer<P> {
+        self.evict(self.capacity - 1);
+        self.tick += 1;
+        let container = Box::into_raw(Box::new(PageContainer {
+            page: UnsafeCell::new(page),
+            latch: RwLock::new(()),
+            modified,
+            pins: 0,
+            last_used: self.tick,
+        }));
+        if let Some(old) = self.frames.insert(i, container) {
+            // SAFETY: insert はフレームのない i か、ピンされていない i にしか呼ばない
+            unsafe { drop(Box::from_raw(old)) };
+        }
+        container
+    }
+
+    // ピンされていないページを size 以下になるまで追い出す
+    fn evict(&mut self, size: usize) {
+        while size < self.frames.len() {
+            // SAFETY: frames にあるフレームで、pool のロックを持っている (&mut self)
+            let victim = self
+                .frames
+                .iter()
+                .map(|(i, c)| unsafe { (*i, &**c) })
+                .filter(|(_, c)| c.pins == 0)
+                .min_by_key(|(_, c)| c.last_used)
+                .map(|(i, _)| i);
+            let i = if let Some(i) = victim {
+                i
+            } else {
+                // 全てピンされている
+                break;
+            };
+            // SAFETY: ピンされていないので、PageRef や PageMut から参照されていない
+            let container = unsafe { Box::from_raw(self.frames.remove(&i).unwrap()) };
+            if container.modified {
+                let offset = self.wal.append_frame(i, &container.page.into_inner());
+                self.spilled.insert(i, offset);
+            }
+        }
+    }
+}
+
+impl<P: Page> Snapshot<P> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn free_head(&self) -> usize {
+        self.free_head
+    }
+}
+
+impl<P: Page> Drop for Snapshot<P> {
+    fn drop(&mut self) {
+        let mut versions = self.versions.lock().unwrap();
+        versions.live.remove(&self.id);
+        if versions.live.is_empty() {
+            versions.pages.clear();
+            return;
+        }
+        // 版 v を読むのは、一つ前の版より後で v 以前の snapshot だけ
+        // そういう snapshot が生きていない版はもう読まれない
+        let Versions { live, pages, .. } = &mut *versions;
+        pages.retain(|_, pages| {
+            let mut prev = 0;
+            pages.retain(|(id, _)| {
+                let read = live.range(prev..=*id).next().is_some();
+                prev = *id + 1;
+                read
+            });
+            !pages.is_empty()
+        });
+    }
+}
+
+impl<'a, P: Page> std::ops::Deref for PageRef<'a, P> {
+    type Target = P;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: ピンと共有ラッチを持っているか、古い版を読む snapshot が生きている
+        unsafe { &*self.page }
+    }
+}
+
+impl<'a, P: Page> Drop for PageRef<'a, P> {
+    fn drop(&mut self) {
+        if let Some(latch) = self.latch.take() {
+            drop(latch);
+            let container = self.pager.pool().frames[&self.i];
+            // SAFETY: get_ref でピンしたフレームで、ラッチは外した
+            unsafe { self.pager.unpin(container) };
+        }
+    }
+}
+
+impl<'a, P: Page> std::ops::Deref for PageMut<'a, P> {
+    type Target = P;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: ピンと排他ラッチを持っている
+        unsafe { &*self.page }
+    }
+}
+
+impl<'a, P: Page> std::ops::DerefMut for PageMut<'a, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: ピンと排他ラッチを持っているので、他に参照はない
+        unsafe { &mut *self.page }
+    }
+}
+
+impl<'a, P: Page> Drop for WriterGuard<'a, P> {
+    fn drop(&mut self) {
+        // ロックを外す前に消す
+        *self.pager.writer_thread.lock().unwrap() = None;
+    }
+}
+
+impl<'a, P: Page> Drop for PageMut<'a, P> {
+    fn drop(&mut self) {
+        drop(self.latch.take());
+        let container = self.pager.pool().frames[&self.i];
+        // SAFETY: get_mut でピンしたフレームで、ラッチは外した
+        unsafe { self.pager.unpin(container) };
+    }
+}
+
+#[cfg(test)]
+use crate::test_util::TestDir;
+
+// #[test]
+// fn test() {
+//     let mut pager = Pager::<PageRaw>::open("hello");
+//     let page = pager.get_mut(0);
+//     dbg!(&page.as_ref()[0..10]);
+//     page.as_mut()[0] = 1;
+//     page.as_mut()[1] = 2;
+//     page.as_mut()[2] = 3;
+//     pager.save();
+// }
+
+#[test]
+fn test_eviction() {
+    use super::page::Page;
+
+    let dir = TestDir::new("test_eviction");
+    let filepath = &dir.path("test_eviction.rdb");
+    {
+        let mut pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        pager.set_capacity(8);
+        for i in 0..100 {
+            pager.get_mut(i)[0] = i as u8;
+            assert!(pager.resident_size() <= 8);
+        }
+        // ピンしたページは追い出されない
+        let page_ref = pager.get_ref(3);
+        for i in 0..100 {
+            assert_eq!(pager.get_ref(i)[0], i as u8);
+        }
+        assert_eq!(page_ref[0], 3);
+        drop(page_ref);
+        pager.save();
+        assert!(pager.resident_size() <= 8);
+    }
+    {
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        assert_eq!(pager.size(), 100);
+        for i in 0..100 {
+            assert_eq!(pager.get_ref(i)[0], i as u8);
+        }
+    }
+}
+
+#[test]
+fn test_writer() {
+    use super::page::Page;
+
+    let dir = TestDir::new("test_writer");
+    let filepath = &dir.path("test_writer.rdb");
+    let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+    assert!(!pager.holds_writer());
+    let writer = pager.lock_writer();
+    assert!(pager.holds_writer());
+    // ガードを持っていない他のスレッドは書き込むスレッドではなく、lock_writer で待つ
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            assert!(!pager.holds_writer());
+            assert!(pager.writer.try_lock().is_err());
+        })
+        .join()
+        .unwrap();
+    });
+    drop(writer);
+    assert!(!pager.holds_writer());
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let _writer = pager.lock_writer();
+            assert!(pager.holds_writer());
+        });
+    });
+    assert!(!pager.holds_writer());
+}
+
+#[test]
+fn test_free_list() {
+    use super::page::Page;
+
+    let dir = TestDir::new("test_free_list");
+    let filepath = &dir.path("test_free_list.rdb");
+    let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+    pager.get_mut(0);
+    let is: Vec<_> = (0..4).map(|_| pager.allocate()).collect();
+    assert_eq!(is, vec![1, 2, 3, 4]);
+    pager.get_mut(2)[10] = 1;
+    pager.free(2);
+    pager.free(4);
+    assert_eq!(pager.free_pages(), vec![4, 2]);
+    assert_eq!(pager.allocate(), 4);
+    assert_eq!(pager.allocate(), 2);
+    assert_eq!(pager.get_ref(2)[10], 0);
+    assert_eq!(pager.allocate(), 5);
+    assert_eq!(pager.size(), 6);
+}
+
+#[test]
+fn test_snapshot() {
+    use super::page::Page;
+
+    let dir = TestDir::new("test_snapshot");
+    let filepath = &dir.path("test_snapshot.rdb");
+    let mut pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+    pager.set_capacity(4);
+    for i in 0..10 {
+        pager.get_mut(i)[0] = i as u8;
+    }
+
+    let s1 = pager.snapshot();
+    for i in 0..10 {
+        pager.get_mut(i)[0] = 10 + i as u8;
+    }
+    pager.save();
+    let s2 = pager.snapshot();
+    pager.get_mut(3)[0] = 100;
+    pager.free(5);
+    assert_eq!(pager.allocate(), 5);
+
+    for i in 0..10 {
+        assert_eq!(pager.get_ref_at(i, Some(s1.id()))[0], i as u8);
+        assert_eq!(pager.get_ref_at(i, Some(s2.id()))[0], 10 + i as u8);
+    }
+    assert_eq!(pager.get_ref_at(3, None)[0], 100);
+    assert_eq!(pager.get_ref_at(5, None)[0], 0);
+
+    drop(s1);
+    assert_eq!(pager.versions_size(), 2);
+    assert_eq!(pager.get_ref_at(3, Some(s2.id()))[0], 13);
+    drop(s2);
+    assert_eq!(pager.versions_size(), 0);
+
+    // 古い snapshot が生きていても、途中の snapshot だけが読む版は捨てる
+    let s1 = pager.snapshot();
+    for n in 0..10 {
+        let s2 = pager.snapshot();
+        pager.get_mut(0)[0] = n;
+        drop(s2);
+    }
+    assert_eq!(pager.versions_size(), 1);
+    assert_eq!(pager.get_ref_at(0, Some(s1.id()))[0], 10);
+    assert_eq!(pager.get_ref_at(0, None)[0], 9);
+}
+
+#[test]
+fn test_compression() {
+    use super::page::Page;
+
+    let dir = TestDir::new("test_compression");
+    let filepath = &dir.path("test_compression.rdb");
+    let fill = |page: &mut [u8], i: usize, n: usize| {
+        for (k, b) in page.iter_mut().enumerate() {
+            *b = ((k / n) % 7 + i) as u8;
+        }
+    };
+    {
+        let mut pager = Pager::<Page>::open_with_backend(
+            filepath,
+            DEFAULT_PAGE_SIZE,
+            true,
+            true,
+            None,
+            Backend::Read,
+        );
+        pager.set_capacity(4);
+        for i in 0..20 {
+            fill(&mut pager.get_mut(i), i, 8);
+        }
+        pager.save();
+        assert!(std::fs::metadata(filepath).unwrap().len() < (20 * DEFAULT_PAGE_SIZE / 4) as u64);
+
+        // 大きくなって場所が変わるページと、小さくなるページ
+        for i in 0..10 {
+            fill(&mut pager.get_mut(i), i, 1 + i % 2 * 100);
+        }
+        pager.save();
+        pager.get_mut(3)[0] = 200;
+        pager.get_mut(20)[0] = 1;
+
+        // wal にコミットされたが、チェックポイントの前に落ちた
+        let pool = pager.pool.get_mut().unwrap();
+        // SAFETY: &mut で取った pool なので、他に参照はない
+        let modified_pages: Vec<_> = pool
+            .frames
+            .iter()
+            .map(|(i, c)| unsafe { (*i, &**c) })
+            .filter(|(_, c)| c.modified)
+            .map(|(i, c)| unsafe { (i, &**c.page.get()) })
+            .collect();
+        pool.wal.commit(modified_pages.into_iter());
+    }
+    let offset = {
+        let pager = Pager::<Page>::open_with_backend(
+            filepath,
+            DEFAULT_PAGE_SIZE,
+            true,
+            true,
+            None,
+            Backend::Mmap,
+        );
+        assert_eq!(pager.size(), 21);
+        for i in 0..20 {
+            let mut expected = vec![0; DEFAULT_PAGE_SIZE];
+            fill(&mut expected, i, if i < 10 { 1 + i % 2 * 100 } else { 8 });
+            if i == 3 {
+                expected[0] = 200;
+            }
+            assert_eq!(&**pager.get_ref(i), expected.as_slice());
+        }
+        assert_eq!(pager.get_ref(20)[0], 1);
+        assert!(pager.corrupted_pages().is_empty());
+        let offset = pager.pool().map.as_ref().unwrap().locate(5).unwrap().0;
+        offset
+    };
+
+    // 圧縮されたページが壊れていれば検出する
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(filepath)
+        .unwrap();
+    file.seek(SeekFrom::Start(offset + 1)).unwrap();
+    std::io::Write::write_all(&mut file, &[0xff]).unwrap();
+    let pager = Pager::<Page>::open_with_backend(
+        filepath,
+        DEFAULT_PAGE_SIZE,
+        true,
+        true,
+        None,
+        Backend::Read,
+    );
+    assert_eq!(pager.corrupted_pages(), vec![5]);
+}
+
+#[test]
+fn test_encrypted_gap() {
+    use super::page::Page;
+
+    let dir = TestDir::new("test_encrypted_gap");
+    let cipher = Cipher::new("correct horse", Cipher::random_salt());
+    for compression in [false, true] {
+        let filepath = &dir.path(&format!("test_encrypted_gap_{}.rdb", compression));
+        let open = || {
+            Pager::<Page>::open_with_backend(
+                filepath,
+                DEFAULT_PAGE_SIZE,
+                true,
+                compression,
+                Some(cipher.clone()),
+                Backend::Read,
+            )
+        };
+        {
+            let pager = open();
+            pager.get_mut(0)[0] = 1;
+            // page 1 と 2 は書かれないまま、後ろのページを書く
+            pager.ensure_page(1);
+            pager.ensure_page(2);
+            pager.get_mut(3)[0] = 3;
+            pager.save();
+        }
+        {
+            let pager = open();
+            assert_eq!(pager.size(), 4);
+            assert!(pager.corrupted_pages().is_empty());
+            assert_eq!(pager.get_ref(1)[0], 0);
+            assert_eq!(pager.get_ref(3)[0], 3);
+        }
+        if !compression {
+            // 全て 0 に書き換えられたページは、書かれていないページとしては読まない
+            let slot_size = slot_size(DEFAULT_PAGE_SIZE, true, true) as usize;
+            let mut bytes = std::fs::read(filepath).unwrap();
+            bytes[slot_size..slot_size * 2].fill(0);
+            std::fs::write(filepath, bytes).unwrap();
+            assert_eq!(open().corrupted_pages(), vec![1]);
+        }
+    }
+}
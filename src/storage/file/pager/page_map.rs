@@ -0,0 +1,344 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    convert::TryInto,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use super::{
+    cipher::Cipher,
+    lz, page_checksum, slot_size,
+    wal::{checksum, CHECKSUM_INIT},
+    CHECKSUM_SIZE,
+};
+
+// # compressed file layout
+// [slot] page 0 (圧縮せず、チェックサム付きで置く)
+// [SUPERBLOCK_SIZE] superblock A
+// [SUPERBLOCK_SIZE] superblock B
+// extent...
+//
+// ## superblock
+// [8] generation
+// [8] map offset
+// [4] number of pages
+// [8] checksum of the map
+// [8] checksum of the superblock
+//
+// ## map
+// ページごとに [8] offset [4] stored length
+// 長さ 0 のページは一度も書かれていない (全て 0)
+// 暗号化するファイルでは、書かれていないページも 0 のページを暗号化して置くので、長さ 0 はない
+// 長さがページの大きさなら圧縮していない
+//
+// ## extent
+// [stored length] 圧縮したページ (暗号化するファイルでは、その後で暗号化する)
+// [8] checksum
+//
+// superblock は世代の大きい正しい方を使い、書くときは古い方を上書きする
+// 新しい map を書いて同期してから superblock を書くので、途中で落ちても前の map が残る
+// checkpoint で書かれるページは全て wal にあるので、前の map が指す領域のうち
+// そのページのものは上書きしてよい (recover で書き直される)
+
+const SUPERBLOCK_SIZE: u64 = 64;
+const SUPERBLOCK_FIELDS_SIZE: usize = 8 + 8 + 4 + 8;
+const ENTRY_SIZE: usize = 8 + 4;
+// extent はこの単位で確保し、少し大きくなっても同じ場所に書けるようにする
+const EXTENT_UNIT: u64 = 64;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Entry {
+    offset: u64,
+    len: u32,
+}
+
+// ページ番号からファイル上の位置への変換表
+pub struct PageMap {
+    page_size: usize,
+    entries: Vec<Entry>,
+    // 使われていない領域 offset -> 長さ
+    free: BTreeMap<u64, u64>,
+    // 使われている領域の末尾
+    end: u64,
+    generation: u64,
+    // 今の map が置かれている領域
+    map_extent: (u64, u64),
+}
+
+impl PageMap {
+    pub fn new(page_size: usize) -> Self {
+        let mut map = Self {
+            page_size,
+            entries: vec![],
+            free: BTreeMap::new(),
+            end: 0,
+            generation: 0,
+            map_extent: (0, 0),
+        };
+        map.end = map.data_start();
+        map
+    }
+
+    // 正しい superblock がなければ空の map になる
+    // (最初の checkpoint の途中で落ちたときは wal から書き直される)
+    pub fn open(file: &mut File, page_size: usize) -> Result<Self, String> {
+        let mut map = Self::new(page_size);
+        let superblock = (0..2)
+            .filter_map(|k| map.read_superblock(file, k))
+            .max_by_key(|s| s.0);
+        let (generation, map_offset, pages_num, map_checksum) = match superblock {
+            Some(superblock) => superblock,
+            None => return Ok(map),
+        };
+        let mut bytes = vec![0; pages_num * ENTRY_SIZE];
+        file.seek(SeekFrom::Start(map_offset))
+            .and_then(|_| file.read_exact(&mut bytes))
+            .map_err(|e| format!("cannot read page map: {}", e))?;
+        if checksum(CHECKSUM_INIT, &bytes) != map_checksum {
+            return Err("page map is corrupted (checksum mismatch)".to_owned());
+        }
+        map.entries = bytes
+            .chunks(ENTRY_SIZE)
+            .map(|b| Entry {
+                offset: u64::from_le_bytes(b[0..8].try_into().unwrap()),
+                len: u32::from_le_bytes(b[8..12].try_into().unwrap()),
+            })
+            .collect();
+        map.generation = generation;
+        map.map_extent = (map_offset, extent_size(bytes.len()));
+
+        // 使われている領域の隙間を空き領域にする
+        let mut used: Vec<(u64, u64)> = map
+            .entries
+            .iter()
+            .skip(1)
+            .filter(|e| e.len != 0)
+            .map(|e| {
+                (
+                    e.offset,
+                    extent_size(e.len as usize + CHECKSUM_SIZE as usize),
+                )
+            })
+            .collect();
+        used.push(map.map_extent);
+        used.sort_unstable();
+        let mut end = map.data_start();
+        for (offset, size) in used {
+            if end < offset {
+                map.free.insert(end, offset - end);
+            }
+            end = end.max(offset + size);
+        }
+        map.end = end;
+        Ok(map)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // ページの位置と長さ、書かれていなければ None
+    pub fn locate(&self, i: usize) -> Option<(u64, usize)> {
+        self.entries
+            .get(i)
+            .filter(|e| e.len != 0)
+            .map(|e| (e.offset, e.len as usize))
+    }
+
+    // extent (チェックサムを含む) からページを取り出す
+    pub fn decode(
+        &self,
+        cipher: Option<&Cipher>,
+        i: usize,
+        extent: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let (stored, sum) = extent.split_at(extent.len() - CHECKSUM_SIZE as usize);
+        if u64::from_le_bytes(sum.try_into().unwrap()) != page_checksum(i, stored) {
+            return Err(format!("page {} is corrupted (checksum mismatch)", i));
+        }
+        let stored = match cipher {
+            Some(cipher) if i != 0 => Cow::Owned(cipher.open(i, stored)?),
+            _ => Cow::Borrowed(stored),
+        };
+        if stored.len() == self.page_size {
+            Ok(stored.to_vec())
+        } else {
+            lz::decompress(&stored, self.page_size)
+                .map_err(|e| format!("page {} is corrupted ({})", i, e))
+        }
+    }
+
+    // 縮まなければ圧縮せずに置く
+    pub fn write_page(&mut self, file: &mut File, cipher: Option<&Cipher>, i: usize, page: &[u8]) {
+        debug_assert_eq!(page.len(), self.page_size);
+        if let Some(cipher) = cipher {
+            self.seal_gap(file, cipher, i);
+        }
+        if self.entries.len() <= i {
+            self.entries.resize(i + 1, Entry::default());
+        }
+        let mut stored = if i == 0 {
+            page.to_vec()
+        } else {
+            let compressed = lz::compress(page);
+            if compressed.len() < page.len() {
+                compressed
+            } else {
+                page.to_vec()
+            }
+        };
+        if let Some(cipher) = cipher.filter(|_| i != 0) {
+            stored = cipher.seal(i, &stored);
+        }
+        let sum = page_checksum(i, &stored);
+        stored.extend(sum.to_le_bytes());
+
+        let offset = if i == 0 {
+            0
+        } else {
+            let old = self.entries[i];
+            let old_size = if old.len == 0 {
+                0
+            } else {
+                extent_size(old.len as usize + CHECKSUM_SIZE as usize)
+            };
+            let size = extent_size(stored.len());
+            if size <= old_size {
+                self.release(old.offset + size, old_size - size);
+                old.offset
+            } else {
+                if old_size != 0 {
+                    self.release(old.offset, old_size);
+                }
+                self.allocate(size)
+            }
+        };
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&stored).unwrap();
+        self.entries[i] = Entry {
+            offset,
+            len: (stored.len() - CHECKSUM_SIZE as usize) as u32,
+        };
+    }
+
+    // 暗号化するファイルでは、i より前の書かれていないページを 0 のページで埋める
+    fn seal_gap(&mut self, file: &mut File, cipher: &Cipher, i: usize) {
+        let zero = vec![0; self.page_size];
+        for j in self.entries.len().max(1)..i {
+            self.write_page(file, Some(cipher), j, &zero);
+        }
+    }
+
+    // 書いたページを同期してから新しい map と superblock を書く
+    pub fn commit(&mut self, file: &mut File, cipher: Option<&Cipher>, pages_num: usize) {
+        if let Some(cipher) = cipher {
+            self.seal_gap(file, cipher, pages_num);
+        }
+        self.entries.resize(pages_num.max(1), Entry::default());
+        let mut bytes = Vec::with_capacity(self.entries.len() * ENTRY_SIZE);
+        for entry in &self.entries {
+            bytes.extend(entry.offset.to_le_bytes());
+            bytes.extend(entry.len.to_le_bytes());
+        }
+        let size = extent_size(bytes.len());
+        let map_offset = self.allocate(size);
+        file.seek(SeekFrom::Start(map_offset)).unwrap();
+        file.write_all(&bytes).unwrap();
+        file.sync_data().unwrap();
+
+        self.generation += 1;
+        let mut superblock = Vec::with_capacity(SUPERBLOCK_FIELDS_SIZE + 8);
+        superblock.extend(self.generation.to_le_bytes());
+        superblock.extend(map_offset.to_le_bytes());
+        superblock.extend((self.entries.len() as u32).to_le_bytes());
+        superblock.extend(checksum(CHECKSUM_INIT, &bytes).to_le_bytes());
+        superblock.extend(checksum(CHECKSUM_INIT, &superblock).to_le_bytes());
+        file.seek(SeekFrom::Start(
+            self.superblock_offset((self.generation % 2) as usize),
+        ))
+        .unwrap();
+        file.write_all(&superblock).unwrap();
+        file.sync_data().unwrap();
+
+        // 前の map は superblock を書いた後でなければ再利用できない
+        // 末尾の空き領域は切り詰める
+        let (old_offset, old_size) = std::mem::replace(&mut self.map_extent, (map_offset, size));
+        if old_size != 0 {
+            self.release(old_offset, old_size);
+        }
+        if let Some((&offset, &size)) = self.free.iter().next_back() {
+            if offset + size == self.end {
+                self.free.remove(&offset);
+                self.end = offset;
+            }
+        }
+        file.set_len(self.end).unwrap();
+    }
+
+    fn read_superblock(&self, file: &mut File, k: usize) -> Option<(u64, u64, usize, u64)> {
+        let mut bytes = [0; SUPERBLOCK_FIELDS_SIZE + 8];
+        file.seek(SeekFrom::Start(self.superblock_offset(k))).ok()?;
+        file.read_exact(&mut bytes).ok()?;
+        let (fields, sum) = bytes.split_at(SUPERBLOCK_FIELDS_SIZE);
+        if u64::from_le_bytes(sum.try_into().unwrap()) != checksum(CHECKSUM_INIT, fields) {
+            return None;
+        }
+        Some((
+            u64::from_le_bytes(fields[0..8].try_into().unwrap()),
+            u64::from_le_bytes(fields[8..16].try_into().unwrap()),
+            u32::from_le_bytes(fields[16..20].try_into().unwrap()) as usize,
+            u64::from_le_bytes(fields[20..28].try_into().unwrap()),
+        ))
+    }
+
+    fn superblock_offset(&self, k: usize) -> u64 {
+        slot_size(self.page_size, true, false) + SUPERBLOCK_SIZE * k as u64
+    }
+
+    fn data_start(&self) -> u64 {
+        self.superblock_offset(2)
+    }
+
+    // 最初に収まる空き領域を使い、なければ末尾に足す
+    fn allocate(&mut self, size: u64) -> u64 {
+        let found = self
+            .free
+            .iter()
+            .find(|(_, free_size)| size <= **free_size)
+            .map(|(offset, free_size)| (*offset, *free_size));
+        if let Some((offset, free_size)) = found {
+            self.free.remove(&offset);
+            if size < free_size {
+                self.free.insert(offset + size, free_size - size);
+            }
+            offset
+        } else {
+            let offset = self.end;
+            self.end += size;
+            offset
+        }
+    }
+
+    // 隣の空き領域とつなげる
+    fn release(&mut self, mut offset: u64, mut size: u64) {
+        if size == 0 {
+            return;
+        }
+        if let Some((&prev, &prev_size)) = self.free.range(..offset).next_back() {
+            if prev + prev_size == offset {
+                self.free.remove(&prev);
+                offset = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(offset + size)) {
+            size += next_size;
+        }
+        self.free.insert(offset, size);
+    }
+}
+
+fn extent_size(len: usize) -> u64 {
+    (len as u64).div_ceil(EXTENT_UNIT) * EXTENT_UNIT
+}
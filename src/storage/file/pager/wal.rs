@@ -0,0 +1,213 @@
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use super::{cipher::Cipher, PageRaw};
+
+// # wal layout
+// frame...
+//
+// ## frame
+// [4] page index
+// [page size] page image (暗号化するファイルでは暗号化したもの)
+//
+// ## commit record
+// [4] COMMIT_MARK
+// [4] number of frames in this commit
+// [8] checksum of the frames
+//
+// 後ろに正しい commit record がないフレームは、回復するときに捨てる
+
+const COMMIT_MARK: u32 = u32::MAX;
+const COMMIT_RECORD_SIZE: usize = 4 + 4 + 8;
+
+pub struct Wal {
+    file: File,
+    page_size: usize,
+    cipher: Option<Cipher>,
+    // 最後のコミット以降に追記されたフレームの数とチェックサム
+    pending_frames: u32,
+    pending_checksum: u64,
+}
+
+impl Wal {
+    pub fn open(filepath: &str, page_size: usize, cipher: Option<Cipher>) -> Self {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(filepath)
+            .unwrap();
+        Self {
+            file,
+            page_size,
+            cipher,
+            pending_frames: 0,
+            pending_checksum: CHECKSUM_INIT,
+        }
+    }
+
+    // コミットされていないフレームを追記し、ページイメージのオフセットを返す
+    pub fn append_frame(&mut self, i: usize, page: &[u8]) -> u64 {
+        debug_assert_eq!(page.len(), self.page_size);
+        let mut bytes = Vec::with_capacity(self.frame_size());
+        bytes.extend((i as u32).to_le_bytes());
+        match &self.cipher {
+            Some(cipher) => bytes.extend(cipher.seal(i, page)),
+            None => bytes.extend(page.iter()),
+        }
+        self.pending_frames += 1;
+        self.pending_checksum = checksum(self.pending_checksum, &bytes);
+
+        let offset = self.file.seek(SeekFrom::End(0)).unwrap();
+        self.file.write_all(&bytes).unwrap();
+        offset + 4
+    }
+
+    pub fn read_frame(&mut self, i: usize, offset: u64) -> PageRaw {
+        let mut image = vec![0; self.frame_size() - 4];
+        self.file.seek(SeekFrom::Start(offset)).unwrap();
+        self.file.read_exact(&mut image).unwrap();
+        self.open_image(i, image)
+    }
+
+    // 追記済みのフレームとページ群を1つのコミットとしてディスクに同期する
+    pub fn commit<'a>(&mut self, pages: impl Iterator<Item = (usize, &'a [u8])>) {
+        for (i, page) in pages {
+            self.append_frame(i, page);
+        }
+        let mut bytes = Vec::with_capacity(COMMIT_RECORD_SIZE);
+        bytes.extend(COMMIT_MARK.to_le_bytes());
+        bytes.extend(self.pending_frames.to_le_bytes());
+        bytes.extend(self.pending_checksum.to_le_bytes());
+
+        self.file.seek(SeekFrom::End(0)).unwrap();
+        self.file.write_all(&bytes).unwrap();
+        self.file.sync_data().unwrap();
+        self.pending_frames = 0;
+        self.pending_checksum = CHECKSUM_INIT;
+    }
+
+    // コミット済みのページを書き込まれた順に返す
+    pub fn committed_pages(&mut self) -> Vec<(usize, PageRaw)> {
+        let mut bytes = Vec::new();
+        self.file.seek(SeekFrom::Start(0)).unwrap();
+        self.file.read_to_end(&mut bytes).unwrap();
+
+        let mut pages = Vec::new();
+        let mut pending = Vec::new();
+        let mut commit_start = 0;
+        let mut i = 0;
+        while i + 4 <= bytes.len() {
+            let mark = parse_u32(&bytes[i..i + 4]);
+            if mark == COMMIT_MARK {
+                if bytes.len() < i + COMMIT_RECORD_SIZE {
+                    break;
+                }
+                let frames_num = parse_u32(&bytes[i + 4..i + 8]) as usize;
+                let sum = u64::from_le_bytes(bytes[i + 8..i + 16].try_into().unwrap());
+                if frames_num != pending.len()
+                    || sum != checksum(CHECKSUM_INIT, &bytes[commit_start..i])
+                {
+                    break;
+                }
+                pages.append(&mut pending);
+                i += COMMIT_RECORD_SIZE;
+                commit_start = i;
+            } else {
+                if bytes.len() < i + self.frame_size() {
+                    break;
+                }
+                let image = bytes[i + 4..i + self.frame_size()].to_vec();
+                pending.push((mark as usize, self.open_image(mark as usize, image)));
+                i += self.frame_size();
+            }
+        }
+        pages
+    }
+
+    fn frame_size(&self) -> usize {
+        match self.cipher {
+            Some(_) => 4 + self.page_size + super::cipher::OVERHEAD,
+            None => 4 + self.page_size,
+        }
+    }
+
+    // 鍵はヘッダで照合済みなので、復号できなければ壊れている
+    fn open_image(&self, i: usize, image: Vec<u8>) -> PageRaw {
+        match &self.cipher {
+            Some(cipher) => cipher
+                .open(i, &image)
+                .unwrap_or_else(|e| panic!("wal frame is corrupted: {}", e))
+                .into_boxed_slice(),
+            None => image.into_boxed_slice(),
+        }
+    }
+
+    // チェックポイント完了後にログを空にする
+    pub fn reset(&mut self) {
+        self.file.set_len(0).unwrap();
+        self.file.sync_data().unwrap();
+        self.pending_frames = 0;
+        self.pending_checksum = CHECKSUM_INIT;
+    }
+}
+
+pub fn wal_path(filepath: &str) -> String {
+    format!("{}-wal", filepath)
+}
+
+pub const CHECKSUM_INIT: u64 = 0xcbf29ce484222325;
+
+// FNV-1a
+pub fn checksum(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn parse_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+#[test]
+fn test() {
+    use super::{super::page::Page, Pager, DEFAULT_PAGE_SIZE};
+    use crate::test_util::TestDir;
+
+    let dir = TestDir::new("test_wal");
+    let filepath = &dir.path("test_wal.rdb");
+    {
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        pager.get_mut(0)[0] = 1;
+        pager.get_mut(1)[0] = 2;
+        pager.save();
+    }
+    {
+        // コミットが wal に届いた後、チェックポイントの前に落ちた
+        let mut wal = Wal::open(&wal_path(filepath), DEFAULT_PAGE_SIZE, None);
+        let mut page = vec![0; DEFAULT_PAGE_SIZE];
+        page[0] = 3;
+        wal.commit(vec![(1, page.as_slice())].into_iter());
+
+        // 二つ目のコミットは書き込みの途中で切れた
+        page[0] = 4;
+        let mut bytes = 0u32.to_le_bytes().to_vec();
+        bytes.extend(page.iter());
+        wal.file.seek(SeekFrom::End(0)).unwrap();
+        wal.file.write_all(&bytes).unwrap();
+    }
+    {
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        pager.ensure_page(0);
+        pager.ensure_page(1);
+        assert_eq!(pager.get_ref(0)[0], 1);
+        assert_eq!(pager.get_ref(1)[0], 3);
+    }
+    assert_eq!(std::fs::metadata(wal_path(filepath)).unwrap().len(), 0);
+}
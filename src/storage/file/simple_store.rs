@@ -1,8 +1,10 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 
 use super::{
+    header::{read_header, write_header, Header},
     page::Page,
-    pager::{Pager, PAGE_SIZE},
+    pager::Pager,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -10,20 +12,41 @@ struct ObjectTable {
     objects: Vec<(String, u32)>,
 }
 
-pub fn init_as_simple_store(pager: &mut Pager<Page>) {
+// page 0 にヘッダを書き、オブジェクトテーブルを置く
+pub fn init_as_simple_store(pager: &Pager<Page>) {
+    pager.ensure_page(0);
+    let ot_i = pager.allocate();
+    write_header(pager, &Header::new(ot_i, pager));
     let ot = ObjectTable { objects: vec![] };
     let size_to_write = bincode::serialized_size(&ot).unwrap();
-    let pages_num_required = (size_to_write / (PAGE_SIZE - 4)) as usize;
-    ensure_pages_to_write(pager, pages_num_required, 0);
-    bincode::serialize_into(PagerWriter::new(pager, 0), &ot).unwrap();
+    ensure_pages_to_write(pager, additional_pages_num(pager, size_to_write), ot_i);
+    bincode::serialize_into(PagerWriter::new(pager, ot_i), &ot).unwrap();
+}
+
+fn object_table_i(pager: &Pager<Page>) -> usize {
+    read_header(pager)
+        .expect("header is not found")
+        .object_table_page as usize
+}
+
+// page_i から始まるページがオブジェクトテーブルとして読めるか
+pub fn is_object_table(pager: &Pager<Page>, page_i: usize) -> bool {
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit((pager.size() * pager.page_size()) as u64);
+    options
+        .deserialize_from::<_, ObjectTable>(PagerReader::new(pager, page_i))
+        .is_ok_and(|ot| ot.objects.iter().all(|o| (o.1 as usize) < pager.size()))
 }
 
 pub fn read_object<T: Serialize + serde::de::DeserializeOwned>(
-    pager: &mut Pager<Page>,
+    pager: &Pager<Page>,
     name: &str,
 ) -> Option<T> {
-    ensure_pages_to_read(pager, 0);
-    let reader = PagerReader::new(pager, 0);
+    let ot_i = object_table_i(pager);
+    ensure_pages_to_read(pager, ot_i);
+    let reader = PagerReader::new(pager, ot_i);
     let ot: ObjectTable = bincode::deserialize_from(reader).unwrap();
     if let Some(o) = ot.objects.iter().find(|o| o.0.as_str() == name) {
         ensure_pages_to_read(pager, o.1 as usize);
@@ -34,66 +57,105 @@ pub fn read_object<T: Serialize + serde::de::DeserializeOwned>(
 }
 
 pub fn write_object<T: Serialize + serde::de::DeserializeOwned>(
-    pager: &mut Pager<Page>,
+    pager: &Pager<Page>,
     name: &str,
     object: &T,
 ) {
-    ensure_pages_to_read(pager, 0);
-    let reader = PagerReader::new(pager, 0);
+    let size_to_write = bincode::serialized_size(object).unwrap();
+    let page_i = prepare_object(pager, name, size_to_write);
+    bincode::serialize_into(PagerWriter::new(pager, page_i), object).unwrap();
+}
+
+// すべてのオブジェクトを別のストアに写す
+// 型はわからないので、ページの連結リストの中身をそのまま写す
+pub fn copy_objects(from: &Pager<Page>, to: &Pager<Page>) {
+    let ot_i = object_table_i(from);
+    ensure_pages_to_read(from, ot_i);
+    let ot: ObjectTable = bincode::deserialize_from(PagerReader::new(from, ot_i)).unwrap();
+    for (name, page_i) in ot.objects {
+        ensure_pages_to_read(from, page_i as usize);
+        let mut bytes = vec![];
+        std::io::Read::read_to_end(&mut PagerReader::new(from, page_i as usize), &mut bytes)
+            .unwrap();
+        let page_i = prepare_object(to, &name, bytes.len() as u64);
+        std::io::Write::write_all(&mut PagerWriter::new(to, page_i), &bytes).unwrap();
+    }
+}
+
+// size_to_write バイトを書けるだけのページを name に割り当て、最初のページを返す
+fn prepare_object(pager: &Pager<Page>, name: &str, size_to_write: u64) -> usize {
+    let ot_i = object_table_i(pager);
+    ensure_pages_to_read(pager, ot_i);
+    let reader = PagerReader::new(pager, ot_i);
     let mut ot: ObjectTable = bincode::deserialize_from(reader).unwrap();
 
     let page_i = if let Some(o) = ot.objects.iter().find(|o| o.0.as_str() == name) {
         o.1 as usize
     } else {
-        let i = pager.size();
+        let i = pager.allocate();
         ot.objects.push((name.to_string(), i as u32));
         i
     };
 
-    let size_to_write = bincode::serialized_size(object).unwrap();
-    let pages_num_required = (size_to_write / (PAGE_SIZE - 4)) as usize;
-    ensure_pages_to_write(pager, pages_num_required, page_i);
-    bincode::serialize_into(PagerWriter::new(pager, page_i), object).unwrap();
+    ensure_pages_to_write(pager, additional_pages_num(pager, size_to_write), page_i);
 
     let size_to_write = bincode::serialized_size(&ot).unwrap();
-    let pages_num_required = (size_to_write / (PAGE_SIZE - 4)) as usize;
-    ensure_pages_to_write(pager, pages_num_required, page_i);
-    bincode::serialize_into(PagerWriter::new(pager, 0), &ot).unwrap();
+    ensure_pages_to_write(pager, additional_pages_num(pager, size_to_write), ot_i);
+    bincode::serialize_into(PagerWriter::new(pager, ot_i), &ot).unwrap();
+    page_i
+}
+
+// 最初のページに続けて必要なページの数
+fn additional_pages_num(pager: &Pager<Page>, size_to_write: u64) -> usize {
+    (size_to_write.saturating_sub(1) / (pager.page_size() as u64 - 4)) as usize
 }
 
-fn ensure_pages_to_read(pager: &mut Pager<Page>, page_i: usize) {
+fn ensure_pages_to_read(pager: &Pager<Page>, page_i: usize) {
     pager.ensure_page(page_i);
-    let next_page_i = read_next_page_i(pager.get_ref(page_i));
+    let next_page_i = read_next_page_i(&pager.get_ref(page_i));
     if next_page_i != 0 {
         ensure_pages_to_read(pager, next_page_i);
     };
 }
 
-fn ensure_pages_to_write(pager: &mut Pager<Page>, pages_num: usize, page_i: usize) {
+fn ensure_pages_to_write(pager: &Pager<Page>, pages_num: usize, page_i: usize) {
     pager.ensure_page(page_i);
+    let next_page_i = read_next_page_i(&pager.get_ref(page_i));
     if pages_num == 0 {
+        // 要らなくなった後ろのページを解放する
+        if next_page_i != 0 {
+            write_next_page_i(&mut pager.get_mut(page_i), 0);
+            free_pages(pager, next_page_i);
+        }
         return;
     }
-    let next_page_i = read_next_page_i(pager.get_mut(page_i));
     let next_page_i = if next_page_i == 0 {
-        let next_page_i = pager.size();
-        write_next_page_i(pager.get_mut(page_i), next_page_i);
+        let next_page_i = pager.allocate();
+        write_next_page_i(&mut pager.get_mut(page_i), next_page_i);
         next_page_i
     } else {
-        next_page_i as usize
+        next_page_i
     };
     ensure_pages_to_write(pager, pages_num - 1, next_page_i);
 }
 
+fn free_pages(pager: &Pager<Page>, page_i: usize) {
+    let next_page_i = read_next_page_i(&pager.get_ref(page_i));
+    pager.free(page_i);
+    if next_page_i != 0 {
+        free_pages(pager, next_page_i);
+    }
+}
+
 pub struct PagerWriter<'a> {
-    pager: &'a mut Pager<Page>,
+    pager: &'a Pager<Page>,
     page_i: Option<usize>,
     i: usize,
     next_page_i: Option<usize>,
 }
 
 impl<'a> PagerWriter<'a> {
-    pub fn new(pager: &'a mut Pager<Page>, page_i: usize) -> Self {
+    pub fn new(pager: &'a Pager<Page>, page_i: usize) -> Self {
         Self {
             pager,
             page_i: Some(page_i),
@@ -107,22 +169,22 @@ impl<'a> std::io::Write for PagerWriter<'a> {
     fn write(&mut self, mut buf: &[u8]) -> std::io::Result<usize> {
         let mut total_write_len = 0;
         while !buf.is_empty() && self.page_i.is_some() {
-            let page = self.pager.get_mut(self.page_i.unwrap());
+            let mut page = self.pager.get_mut(self.page_i.unwrap());
             if self.i == 0 {
-                self.next_page_i = Some(read_next_page_i(page));
+                self.next_page_i = Some(read_next_page_i(&page));
                 if self.next_page_i == Some(0) {
                     self.next_page_i = None;
                 }
                 self.i += 4;
             }
-            let write_len = buf.len().min(PAGE_SIZE as usize - self.i);
+            let write_len = buf.len().min(page.len() - self.i);
             page.slice_mut(self.i, write_len)
                 .copy_from_slice(&buf[0..write_len]);
             buf = &buf[write_len..];
             self.i += write_len;
             total_write_len += write_len;
 
-            if self.i >= PAGE_SIZE as usize {
+            if self.i >= page.len() {
                 self.page_i = self.next_page_i;
                 self.i = 0;
             }
@@ -157,21 +219,27 @@ impl<'a> std::io::Read for PagerReader<'a> {
     fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
         let mut total_read_len = 0;
         while !buf.is_empty() && self.page_i.is_some() {
+            if self.pager.size() <= self.page_i.unwrap() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "page is out of range",
+                ));
+            }
             let page = self.pager.get_ref(self.page_i.unwrap());
             if self.i == 0 {
-                self.next_page_i = Some(read_next_page_i(page));
+                self.next_page_i = Some(read_next_page_i(&page));
                 if self.next_page_i == Some(0) {
                     self.next_page_i = None;
                 }
                 self.i += 4;
             }
-            let read_len = buf.len().min(PAGE_SIZE as usize - self.i);
+            let read_len = buf.len().min(page.len() - self.i);
             buf[0..read_len].copy_from_slice(page.slice(self.i, read_len));
             buf = &mut buf[read_len..];
             self.i += read_len;
             total_read_len += read_len;
 
-            if self.i >= PAGE_SIZE as usize {
+            if self.i >= page.len() {
                 self.page_i = self.next_page_i;
                 self.i = 0;
             }
@@ -190,30 +258,44 @@ fn write_next_page_i(page: &mut Page, next_page_i: usize) {
         .copy_from_slice(&(next_page_i as u32).to_le_bytes());
 }
 
+#[cfg(test)]
+use crate::test_util::TestDir;
+
 #[test]
 fn test() {
-    let filepath = "hello";
-    if let Ok(_) = std::fs::remove_file(filepath) {
-        println!("{:?} removed", filepath);
-    };
+    use super::pager::DEFAULT_PAGE_SIZE;
+
+    let dir = TestDir::new("hello");
+    let filepath = &dir.path("hello");
     {
-        let mut pager = Pager::<Page>::open(filepath);
-        init_as_simple_store(&mut pager);
-        dbg!(read_object::<String>(&mut pager, "hello"));
-        write_object::<String>(&mut pager, "hello", &"hello!!!".to_owned());
-        write_object::<String>(&mut pager, "too learge", &"too learge...".repeat(100));
-        write_object::<String>(&mut pager, "bye", &"bye!!!".to_owned());
-        dbg!(read_object::<String>(&mut pager, "hello"));
-        dbg!(read_object::<String>(&mut pager, "too learge").map(|x| x.len()));
-        dbg!(read_object::<String>(&mut pager, "bye"));
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        init_as_simple_store(&pager);
+        dbg!(read_object::<String>(&pager, "hello"));
+        write_object::<String>(&pager, "hello", &"hello!!!".to_owned());
+        write_object::<String>(&pager, "too learge", &"too learge...".repeat(100));
+        write_object::<String>(&pager, "bye", &"bye!!!".to_owned());
+        dbg!(read_object::<String>(&pager, "hello"));
+        dbg!(read_object::<String>(&pager, "too learge").map(|x| x.len()));
+        dbg!(read_object::<String>(&pager, "bye"));
         dbg!(pager.size());
         pager.save();
+
+        // 小さくしたオブジェクトのページは解放され、再利用される
+        write_object::<String>(&pager, "too learge", &"too learge...".repeat(1000));
+        let size = pager.size();
+        write_object::<String>(&pager, "too learge", &"small".to_owned());
+        assert!(!pager.free_pages().is_empty());
+        write_object::<String>(&pager, "too learge2", &"too learge...".repeat(900));
+        assert!(pager.free_pages().is_empty());
+        assert_eq!(pager.size(), size);
+        write_object::<String>(&pager, "too learge", &"too learge...".repeat(1000));
+        pager.save();
     }
     {
-        let mut pager = Pager::<Page>::open(filepath);
-        dbg!(read_object::<String>(&mut pager, "hello"));
-        dbg!(read_object::<String>(&mut pager, "too learge").map(|x| x.len()));
-        dbg!(read_object::<String>(&mut pager, "bye"));
+        let pager = Pager::<Page>::open(filepath, DEFAULT_PAGE_SIZE, true);
+        dbg!(read_object::<String>(&pager, "hello"));
+        dbg!(read_object::<String>(&pager, "too learge").map(|x| x.len()));
+        dbg!(read_object::<String>(&pager, "bye"));
         dbg!(pager.size());
     }
 }
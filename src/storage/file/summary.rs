@@ -1,10 +1,15 @@
-use crate::btree::{BTree, BTreeNode};
+use crate::btree::BTreeNode;
 
 use super::{impl_btree::Meta, File};
 
 impl File {
     pub fn print_summary(&self) {
-        println!("{} pages", self.pager.size());
+        println!(
+            "{} pages ({} resident, {} free)",
+            self.pager.size(),
+            self.pager.resident_size(),
+            self.pager.free_pages().len()
+        );
         println!("{} tables", self.schema.tables.len());
         for table in &self.schema.tables {
             println!("- {}", table.name,);
@@ -13,7 +18,7 @@ impl File {
             }
         }
         println!("{} sources", self.sources.len());
-        for source in &self.sources {
+        for source in self.sources.iter() {
             println!("table: {}", &self.schema.tables[source.table_index].name);
             println!("key: {}", &source.key_columns.join(", "));
             println!(
@@ -31,18 +36,26 @@ impl File {
 
     fn print_page(&self, meta: &Meta, node_i: usize, indent: usize) {
         let ind = "  ".repeat(indent);
-        let node = self.pager.node_ref(node_i);
+        let node = self.pager.get_ref(node_i);
         let parent = node.get_parent(meta);
         if node.is_leaf(meta) {
             let next = node.get_next(meta);
-            println!("{}- leaf(size: {}, page: {}, next: {:?}, parent: {:?})", ind, node.size(meta), node_i, next, parent);
+            println!(
+                "{}- leaf(size: {}, page: {}, next: {:?}, parent: {:?})",
+                ind,
+                node.size(meta),
+                node_i,
+                next,
+                parent
+            );
             // dbg!(node.cursor_get(meta, 0).unwrap().0[0]);
         } else {
             println!(
                 "{}- internal(size: {}, page: {}, parent: {:?})",
                 ind,
                 node.size(meta),
-                node_i, parent
+                node_i,
+                parent
             );
             for node_i in node.get_children(meta) {
                 self.print_page(meta, node_i, indent + 1);
@@ -1,3 +1,5 @@
+use std::sync::{Arc, RwLock};
+
 use crate::{data::Data, schema::Schema};
 
 use super::Storage;
@@ -5,10 +7,13 @@ use super::Storage;
 #[derive(Debug)]
 pub struct InMemory {
     schema: Schema,
-    tables: Vec<Source>,
+    // 書き込みは copy-on-write なので、snapshot は Arc を共有するだけ
+    tables: RwLock<Arc<Vec<Source>>>,
+    // begin した時点の状態
+    snapshot: Option<(Schema, Arc<Vec<Source>>)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Source {
     table_name: String,
     key_columns: Vec<String>,
@@ -16,7 +21,7 @@ pub struct Source {
     rows: SourceRows,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SourceRows {
     Data {
         columns_num: usize,
@@ -32,18 +37,21 @@ pub enum SourceRows {
 pub struct InMemoryCursor {
     source_index: usize,
     index: usize,
+    // snapshot から作ったカーソルはその時点の表を読む
+    snapshot: Option<Arc<Vec<Source>>>,
 }
 
 impl Storage for InMemory {
     type Cursor = InMemoryCursor;
     type SourceIndex = usize;
+    type Snapshot = Arc<Vec<Source>>;
 
     fn schema(&self) -> &crate::schema::Schema {
         &self.schema
     }
 
     fn add_table(&mut self, table: crate::schema::Table) {
-        self.tables.push(Source {
+        Arc::make_mut(self.tables.get_mut().unwrap()).push(Source {
             table_name: table.name.clone(),
             key_columns: table
                 .primary_key
@@ -61,6 +69,8 @@ impl Storage for InMemory {
 
     fn source_index(&self, table_name: &str, key_columns: &[String]) -> Option<Self::SourceIndex> {
         self.tables
+            .read()
+            .unwrap()
             .iter()
             .position(|x| x.table_name == table_name && x.key_columns == key_columns)
     }
@@ -69,31 +79,53 @@ impl Storage for InMemory {
         InMemoryCursor {
             source_index,
             index: 0,
+            snapshot: None,
         }
     }
 
     fn get_cursor_just(&self, source_index: Self::SourceIndex, key: &Vec<Data>) -> Self::Cursor {
-        let table = &self.tables[source_index];
-        let columns_num = key.len();
-        let rows_num = table.keys.len() / columns_num;
-        let mut index = 0;
-        while index < rows_num {
-            if &table.keys[index * columns_num..(index + 1) * columns_num] >= key {
-                break;
-            }
-            index += 1;
-        }
-        if index == rows_num {
-            index = usize::MAX;
-        }
+        cursor_just(&self.tables.read().unwrap(), source_index, key, None)
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.tables.read().unwrap().clone()
+    }
+
+    fn source_index_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        table_name: &str,
+        key_columns: &[String],
+    ) -> Option<Self::SourceIndex> {
+        snapshot
+            .iter()
+            .position(|x| x.table_name == table_name && x.key_columns == key_columns)
+    }
+
+    fn get_cursor_first_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        source_index: Self::SourceIndex,
+    ) -> Self::Cursor {
         InMemoryCursor {
             source_index,
-            index,
+            index: 0,
+            snapshot: Some(snapshot.clone()),
         }
     }
 
+    fn get_cursor_just_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        source_index: Self::SourceIndex,
+        key: &[Data],
+    ) -> Self::Cursor {
+        cursor_just(snapshot, source_index, key, Some(snapshot.clone()))
+    }
+
     fn cursor_get_row(&self, cursor: &Self::Cursor) -> Option<Vec<Data>> {
-        let table = &self.tables[cursor.source_index];
+        let tables = self.tables(cursor);
+        let table = &tables[cursor.source_index];
         if cursor.index * table.key_columns.len() >= table.keys.len() {
             None
         } else {
@@ -121,7 +153,8 @@ impl Storage for InMemory {
         if cursor.index == usize::MAX {
             return true;
         }
-        let table = &self.tables[cursor.source_index];
+        let tables = self.tables(cursor);
+        let table = &tables[cursor.source_index];
         cursor.index * table.key_columns.len() >= table.keys.len()
     }
 
@@ -129,7 +162,7 @@ impl Storage for InMemory {
         todo!()
     }
 
-    fn cursor_delete(&mut self, cursor: &mut Self::Cursor) -> bool {
+    fn cursor_delete(&self, cursor: &mut Self::Cursor) -> bool {
         todo!()
     }
 
@@ -137,9 +170,10 @@ impl Storage for InMemory {
         todo!()
     }
 
-    fn add_row(&mut self, table_name: &str, data: Vec<Data>) -> Result<(), String> {
+    fn add_row(&self, table_name: &str, data: Vec<Data>) -> Result<(), String> {
         let st = self.schema.get_table(table_name).unwrap().1;
-        for table in self.tables.iter_mut() {
+        let mut tables = self.tables.write().unwrap();
+        for table in Arc::make_mut(&mut tables).iter_mut() {
             if table.table_name != table_name {
                 continue;
             }
@@ -174,13 +208,71 @@ impl Storage for InMemory {
     fn flush(&self) {
         println!("InMemory flushed!");
     }
+
+    fn vacuum(&mut self) -> Result<(), String> {
+        // 行は詰めて持っているので何もしない
+        Ok(())
+    }
+
+    fn backup_to(&self, _snapshot: &Self::Snapshot, _path: &str) -> Result<(), String> {
+        Err("in-memory storage cannot be backed up".to_owned())
+    }
+
+    fn begin(&mut self) {
+        assert!(self.snapshot.is_none(), "transaction is already begun");
+        self.snapshot = Some((self.schema.clone(), self.tables.get_mut().unwrap().clone()));
+    }
+
+    fn commit(&mut self) {
+        self.snapshot.take().expect("transaction is not begun");
+    }
+
+    fn rollback(&mut self) {
+        let (schema, tables) = self.snapshot.take().expect("transaction is not begun");
+        self.schema = schema;
+        *self.tables.get_mut().unwrap() = tables;
+    }
 }
 
 impl InMemory {
     pub fn new() -> Self {
         InMemory {
             schema: Schema::new_empty(),
-            tables: vec![],
+            tables: RwLock::new(Arc::new(vec![])),
+            snapshot: None,
         }
     }
+
+    fn tables(&self, cursor: &InMemoryCursor) -> Arc<Vec<Source>> {
+        cursor
+            .snapshot
+            .clone()
+            .unwrap_or_else(|| self.tables.read().unwrap().clone())
+    }
+}
+
+fn cursor_just(
+    tables: &[Source],
+    source_index: usize,
+    key: &[Data],
+    snapshot: Option<Arc<Vec<Source>>>,
+) -> InMemoryCursor {
+    let table = &tables[source_index];
+    let columns_num = key.len();
+    let rows_num = table.keys.len() / columns_num;
+    let mut index = 0;
+    while index < rows_num {
+        if &table.keys[index * columns_num..(index + 1) * columns_num] >= key {
+            break;
+        }
+        index += 1;
+    }
+    if index == rows_num {
+        index = usize::MAX;
+    }
+    InMemoryCursor {
+        source_index,
+        index,
+        snapshot,
+    }
 }
@@ -6,9 +6,15 @@ use crate::{
     schema::{self, Schema},
 };
 
-pub trait Storage: 'static {
+// 読み取りと行の書き込みは &self で行い、複数のスレッドから呼べる
+// 行の書き込み (add_row, cursor_delete) は同時に一つのスレッドだけが行う
+// 書き込みと同時に読むときは snapshot から作ったカーソル (*_at) を使う
+// snapshot なしのカーソルは今の内容を読むので、書き込むスレッドか、書き込みがないときだけ使う
+// テーブルの追加とトランザクションは &mut self で排他的に行う
+pub trait Storage: Send + Sync + 'static {
     type Cursor: std::fmt::Debug;
     type SourceIndex: Clone + Copy;
+    type Snapshot;
 
     fn schema(&self) -> &Schema;
     fn add_table(&mut self, table: schema::Table);
@@ -17,16 +23,61 @@ pub trait Storage: 'static {
     fn get_cursor_first(&self, source_index: Self::SourceIndex) -> Self::Cursor;
     fn get_cursor_just(&self, source_index: Self::SourceIndex, key: &Vec<Data>) -> Self::Cursor;
 
+    // MVCC
+    // snapshot を取った時点の内容を読むカーソルを作る
+    // Snapshot が生きている間は、その後の書き込みはこのカーソルから見えない
+    fn snapshot(&self) -> Self::Snapshot;
+    // snapshot を取った時点のスキーマとソース
+    fn schema_at<'a>(&'a self, _snapshot: &'a Self::Snapshot) -> &'a Schema {
+        self.schema()
+    }
+    fn source_index_at(
+        &self,
+        _snapshot: &Self::Snapshot,
+        table_name: &str,
+        key_columns: &[String],
+    ) -> Option<Self::SourceIndex> {
+        self.source_index(table_name, key_columns)
+    }
+    fn get_cursor_first_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        source_index: Self::SourceIndex,
+    ) -> Self::Cursor;
+    fn get_cursor_just_at(
+        &self,
+        snapshot: &Self::Snapshot,
+        source_index: Self::SourceIndex,
+        key: &[Data],
+    ) -> Self::Cursor;
+
     fn cursor_get_row(&self, cursor: &Self::Cursor) -> Option<Vec<Data>>;
     fn cursor_advance(&self, cursor: &mut Self::Cursor) -> bool;
     fn cursor_is_end(&self, cursor: &Self::Cursor) -> bool;
     fn cursor_next_occupied(&self, cursor: &mut Self::Cursor);
-    fn cursor_delete(&mut self, cursor: &mut Self::Cursor) -> bool;
+    fn cursor_delete(&self, cursor: &mut Self::Cursor) -> bool;
     fn cursor_update(&self, cursor: &mut Self::Cursor, data: Vec<Data>) -> bool;
 
-    fn add_row(&mut self, table_name: &str, data: Vec<Data>) -> Result<(), String>;
+    fn add_row(&self, table_name: &str, data: Vec<Data>) -> Result<(), String>;
+    // 実装によってはまとめて並べ替えて、木を下から作る
+    fn add_rows(&self, table_name: &str, rows: Vec<Vec<Data>>) -> Result<(), String> {
+        for row in rows {
+            self.add_row(table_name, row)?;
+        }
+        Ok(())
+    }
 
     fn flush(&self);
+    // 削除などで空いた領域を詰めて書き直す
+    fn vacuum(&mut self) -> Result<(), String>;
+    // snapshot の時点の内容を別のファイルに書き出す
+    fn backup_to(&self, snapshot: &Self::Snapshot, path: &str) -> Result<(), String>;
+
+    // transaction
+    // rollback すると begin 以降の変更はすべて捨てられる
+    fn begin(&mut self);
+    fn commit(&mut self);
+    fn rollback(&mut self);
 }
 
 // full scan
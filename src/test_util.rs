@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use crate::{front::yaml::schema::parse_table_from_yaml, schema::Table};
+
+// テストごとの一時ディレクトリ
+// 作るときに前の実行で残ったものを消し、drop で WAL などのファイルごと消す
+pub struct TestDir {
+    dir: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rdb-{}", name));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        Self { dir }
+    }
+
+    // ディレクトリの中の name のパス
+    pub fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_str().unwrap().to_owned()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// user テーブル。name にインデックスがある
+pub fn user_table() -> Table {
+    parse_table_from_yaml(
+        r"
+name: user
+columns:
+-   name: id
+    type: u64
+-   name: name
+    type: string
+primary_key: [id]
+indices:
+-   name: name
+    columns: [name]
+",
+    )
+    .unwrap()
+}
+
+// email にインデックスがある user テーブル
+pub fn email_table() -> Table {
+    parse_table_from_yaml(
+        r"
+name: user
+columns:
+-   name: id
+    type: u64
+-   name: email
+    type: string
+primary_key: [id]
+indices:
+-   name: email
+    columns: [email]
+",
+    )
+    .unwrap()
+}